@@ -1,17 +1,81 @@
 pub mod prelude {
-    pub use rust_bert::pipelines::question_answering::QaInput;
+    pub use rust_bert::pipelines::ner::Entity;
+    pub use rust_bert::pipelines::question_answering::{Answer, QaInput};
 }
 
 
 pub mod ul {
-    use rust_bert::pipelines::question_answering::{Answer, QaInput, QuestionAnsweringModel};
+    use std::path::Path;
+
+    use rust_bert::RustBertError;
+    use rust_bert::pipelines::common::ModelType;
+    use rust_bert::pipelines::question_answering::{Answer, QaInput, QuestionAnsweringConfig, QuestionAnsweringModel};
+    use rust_bert::pipelines::sentence_embeddings::{SentenceEmbeddingsBuilder, SentenceEmbeddingsModel};
     use rust_bert::pipelines::sequence_classification::Label;
     use rust_bert::pipelines::zero_shot_classification::ZeroShotClassificationModel;
+    use rust_bert::resources::LocalResource;
+
+    /// Question answering model read from a local directory holding `rust_model.ot`, `config.json` and `vocab.txt`
+    pub struct QaModel {
+        model: QuestionAnsweringModel,
+    }
+
+    impl QaModel {
+        /// `model_type` is `bert` or `distilbert`
+        pub fn from_dir<P: AsRef<Path>>(dir: P, model_type: &str, lower_case: bool) -> Result<Self, RustBertError> {
+            let model_type = match model_type {
+                "bert" => ModelType::Bert,
+                "distilbert" => ModelType::DistilBert,
+                other => return Err(RustBertError::InvalidConfigurationError(format!("Unsupported QA model type: {other}"))),
+            };
+
+            let dir = dir.as_ref();
+            for file in ["rust_model.ot", "config.json", "vocab.txt"] {
+                if !dir.join(file).is_file() {
+                    return Err(RustBertError::FileDownloadError(format!("{} is missing", dir.join(file).display())));
+                }
+            }
+
+            let config = QuestionAnsweringConfig::new(
+                model_type,
+                LocalResource::from(dir.join("rust_model.ot")),
+                LocalResource::from(dir.join("config.json")),
+                LocalResource::from(dir.join("vocab.txt")),
+                None::<LocalResource>,
+                lower_case,
+                None,
+                None,
+            );
+            Ok(Self { model: QuestionAnsweringModel::new(config)? })
+        }
+
+        /// Up to `top_k` answers per input, best first
+        pub fn answer(&self, inputs: &[QaInput], top_k: i64, max_answer_length: usize) -> Vec<Vec<Answer>> {
+            self.model.predict(inputs, top_k, max_answer_length)
+        }
+    }
+
+    /// Sentence embeddings model read from a local directory. Loading takes a while, so keep the encoder around rather than making one per call.
+    pub struct SentenceEncoder {
+        model: SentenceEmbeddingsModel,
+    }
+
+    impl SentenceEncoder {
+        /// Encoder of a local directory laid out as the sentence-transformers model repositories are
+        pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self, RustBertError> {
+            let model = SentenceEmbeddingsBuilder::local(dir.as_ref()).create_model()?;
+            Ok(Self { model })
+        }
 
-    pub(crate) fn intent(sentences: &[&str], labels: &[&str]) -> Vec<Vec<Label>> {
-        let sequence_classification_model = ZeroShotClassificationModel::new(Default::default()).unwrap();
+        /// One vector per sentence
+        pub fn encode(&self, sentences: &[&str]) -> Result<Vec<Vec<f32>>, RustBertError> {
+            self.model.encode(sentences)
+        }
+    }
 
-        sequence_classification_model.predict_multilabel(
+    /// Scores of every label per sentence, with the model of a registry
+    pub(crate) fn intent(model: &ZeroShotClassificationModel, sentences: &[&str], labels: &[&str]) -> Vec<Vec<Label>> {
+        model.predict_multilabel(
             sentences,
             labels,
             Some(Box::new(|label: &str| {
@@ -20,38 +84,32 @@ pub mod ul {
             128,
         )
     }
-
-    pub fn qa(qas: &[QaInput]) -> Vec<Vec<Answer>> {
-
-        //    Set-up Question Answering model
-        let qa_model = QuestionAnsweringModel::new(
-            Default::default()
-        ).unwrap();
-
-        //    Define input
-        // let mut squad_path = std::path::PathBuf::from(".");
-        // squad_path.push("dev-v2.0.json");
-        // let mut qa_inputs = squad_processor(squad_path);
-        //
-        // // qa_inputs.insert(0, QaInput {
-        // //     question: "Who were the Normans?".into(),
-        // //     context: "The Normans (Norman: Nourmands; French: Normands; Latin: Normanni) were the people who in the 10th and 11th centuries gave their name to Normandy, a region in France. They were descended from Norse (\"Norman\" comes from \"Norseman\") raiders and pirates from Denmark, Iceland and Norway who, under their leader Rollo, agreed to swear fealty to King Charles III of West Francia. Through generations of assimilation and mixing with the native Frankish and Roman-Gaulish populations, their descendants would gradually merge with the Carolingian-based cultures of West Francia. The distinct cultural and ethnic identity of the Normans emerged initially in the first half of the 10th century, and it continued to evolve over the succeeding centuries.".into()
-        // // });
-        //    Get answer
-        let answers = qa_model.predict(qas, 3, 64);
-        println!("Sample answer: {:?}", answers.first().unwrap());
-        println!("{}", answers.len());
-        answers
-    }
 }
 
 #[cfg(test)]
 mod bert_tests {
     use rust_bert::pipelines::question_answering::QaInput;
-    // use crate::ml::bert::ul::*;
-    use super::ul::*;
+    use crate::registry::{ModelKind, ModelRegistry, ModelSpec};
+
+    fn registry() -> ModelRegistry {
+        let spec = |name: &str, kind, model_type: &str| ModelSpec {
+            name: name.into(),
+            kind,
+            dir: format!("models/{name}").into(),
+            model_type: model_type.into(),
+            lower_case: false,
+            warm_up: false,
+            generation: Default::default(),
+        };
+        ModelRegistry::new(vec![
+            spec("distilbert-qa", ModelKind::QuestionAnswering, "distilbert"),
+            spec("bart-large-mnli", ModelKind::ZeroShot, "bart"),
+            spec("all-MiniLM-L12-v2", ModelKind::SentenceEmbeddings, ""),
+        ])
+    }
 
     #[test]
+    #[ignore = "needs the weights in models/distilbert-qa"]
     fn qa_test() {
         let qas = &[
             QaInput {
@@ -59,17 +117,25 @@ mod bert_tests {
                 context: "Puerto Rico officially the Commonwealth of Puerto Rico, is a Caribbean island and unincorporated territory of the United States. It is located in the northeast Caribbean Sea, approximately 1,000 miles (1,600 km) southeast of Miami, Florida, between the Dominican Republic and the U.S. Virgin Islands, and includes the eponymous main island and several smaller islands, such as Mona, Culebra, and Vieques. It has roughly 3.2 million residents, and its capital and most populous city is San Juan.[10] Spanish and English are the official languages of the executive branch of government, though Spanish predominates. ".into()
             }
         ];
-        let answers = qa(qas);
+        let answers = registry().answer("distilbert-qa", qas, 3, 64).unwrap();
         println!("answers={answers:?}")
     }
 
     #[test]
+    #[ignore = "needs the weights in models/all-MiniLM-L12-v2"]
+    fn local_embed_test() {
+        let embeddings = registry().encode("all-MiniLM-L12-v2", &["How do I pay?", "I am locked out!"]).unwrap();
+        assert_eq!(embeddings.len(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs the weights in models/bart-large-mnli"]
     fn intent_test() {
 
         let input_sentence = &["How do I pay?", "I am locked out!"];
         let candidate_labels = &["login issue", "billing", "contact support"];
 
-        let result = intent(input_sentence, candidate_labels);
+        let result = registry().classify("bart-large-mnli", input_sentence, candidate_labels).unwrap();
         println!("{result:?}");
     }
 }
@@ -1,66 +1,399 @@
-mod unsupervised_learning {
-    use linfa::traits::Fit;
-    use linfa::traits::Predict;
-    use linfa::DatasetBase;
-    use linfa_clustering::KMeans;
-    use linfa_datasets::generate;
-    use ndarray::{array, Axis};
-    use ndarray_npy::write_npy;
-    use ndarray_rand::rand::{Error, RngCore, SeedableRng};
-    use rand_xoshiro::Xoshiro256Plus;
-
-    use linfa_nn::distance::LInfDist;
-    use rand::Rng;
-
-    pub enum Algorithm {
-        KMeans,
-    }
-
-    pub fn random(seed: Option<u64>) -> Xoshiro256Plus {
-        match seed {
-            None => {
-                let num = rand::thread_rng().gen_range(u64::MIN..u64::MAX);
-                Xoshiro256Plus::seed_from_u64(num)
-            }
-            Some(seed) => {
-                Xoshiro256Plus::seed_from_u64(seed)
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use linfa::DatasetBase;
+use linfa::ParamGuard;
+use linfa::traits::{Fit, Predict, Transformer};
+use linfa_clustering::{Dbscan, KMeans};
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+use rand_xoshiro::Xoshiro256Plus;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::intent::{terms, TfIdf};
+
+/// Most utterances the silhouette is measured on, it compares every pair of them
+const SILHOUETTE_SAMPLE: usize = 1_000;
+
+/// Words that never make a keyword on their own or at either end of a pair
+const STOPWORDS: [&str; 64] = [
+    "a", "about", "am", "an", "and", "any", "are", "as", "at", "be", "but", "by", "can", "could", "do", "does",
+    "for", "from", "get", "got", "has", "have", "hello", "hi", "how", "i", "if", "in", "is", "it", "me", "my",
+    "need", "no", "not", "of", "on", "or", "please", "so", "that", "the", "there", "this", "to", "up", "us",
+    "want", "was", "we", "what", "when", "where", "which", "who", "why", "will", "with", "would", "you", "your",
+    "im", "dont", "cant",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Tries every k in `min_k..=max_k` and keeps the one with the best silhouette
+    KMeans { min_k: usize, max_k: usize },
+    /// Groups utterances with at least `min_points` neighbours within `tolerance`, the rest is noise
+    Dbscan { min_points: usize, tolerance: f64 },
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::KMeans { min_k: 2, max_k: 12 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClusterParams {
+    pub algorithm: Algorithm,
+    /// Representative utterances per cluster, closest to its centre first
+    pub examples: usize,
+    pub keywords: usize,
+    pub seed: u64,
+}
+
+impl Default for ClusterParams {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::default(),
+            examples: 5,
+            keywords: 5,
+            seed: 42,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cluster {
+    /// Clusters are numbered from the largest down
+    pub id: usize,
+    pub size: usize,
+    /// The cluster's best keyword, a starting point for the name of a new intent
+    pub suggested_intent: String,
+    pub keywords: Vec<String>,
+    pub examples: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Clustering {
+    pub clusters: Vec<Cluster>,
+    /// Utterances DBSCAN left out of every cluster
+    pub noise: Vec<String>,
+    /// Silhouette of the chosen k, from -1 to 1 with higher meaning better separated clusters
+    pub silhouette: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterError {
+    Invalid(String),
+    Failed(String),
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterError::Invalid(message) => write!(f, "Cannot cluster: {message}"),
+            ClusterError::Failed(message) => write!(f, "Clustering failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ClusterError {}
+
+/// Trimmed utterances without blanks and without repeats, which differ only in case
+pub fn distinct<S: AsRef<str>>(utterances: &[S]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    utterances
+        .iter()
+        .map(|utterance| utterance.as_ref().trim())
+        .filter(|utterance| !utterance.is_empty() && seen.insert(utterance.to_lowercase()))
+        .map(String::from)
+        .collect()
+}
+
+fn is_stopword(word: &str) -> bool {
+    STOPWORDS.contains(&word) || word.chars().all(char::is_numeric)
+}
+
+/// TF-IDF features of the words that are not stopwords, for when no sentence encoder is available.
+/// Short utterances share too few words for word pairs or stopwords to help.
+pub fn tfidf(utterances: &[&str]) -> Array2<f64> {
+    let content = utterances
+        .iter()
+        .map(|utterance| terms(utterance, 1).into_iter().filter(|word| !is_stopword(word)).collect::<Vec<String>>().join(" "))
+        .collect::<Vec<String>>();
+    let content = content.iter().map(String::as_str).collect::<Vec<&str>>();
+    TfIdf::fit(&content, 1, 1).matrix(&content)
+}
+
+/// Features from sentence embeddings, one vector per utterance
+pub fn embeddings(vectors: &[Vec<f32>]) -> Result<Array2<f64>, ClusterError> {
+    let dimensions = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|vector| vector.len() != dimensions) {
+        return Err(ClusterError::Invalid("embeddings differ in length".into()));
+    }
+    Array2::from_shape_vec(
+        (vectors.len(), dimensions),
+        vectors.iter().flatten().map(|value| *value as f64).collect(),
+    )
+    .map_err(|err| ClusterError::Invalid(err.to_string()))
+}
+
+fn normalize(records: &mut Array2<f64>) {
+    for mut row in records.rows_mut() {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
+    }
+}
+
+fn distance(a: ArrayView1<f64>, b: ArrayView1<f64>) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f64>().sqrt()
+}
+
+/// Mean silhouette of the sampled rows, `None` when there is a single cluster
+fn silhouette(distances: &Array2<f64>, sample: &[usize], labels: &Array1<usize>) -> Option<f64> {
+    let k = labels.iter().max().map_or(0, |max| max + 1);
+    if k < 2 {
+        return None;
+    }
+
+    let mut total = 0.0;
+    for (i, &row) in sample.iter().enumerate() {
+        let mut sums = vec![0.0; k];
+        let mut counts = vec![0usize; k];
+        for (j, &other) in sample.iter().enumerate() {
+            if i != j {
+                sums[labels[other]] += distances[[i, j]];
+                counts[labels[other]] += 1;
             }
         }
+        let own = labels[row];
+        if counts[own] == 0 {
+            // a point alone in its cluster scores 0
+            continue;
+        }
+        let within = sums[own] / counts[own] as f64;
+        let nearest = (0..k)
+            .filter(|label| *label != own && counts[*label] > 0)
+            .map(|label| sums[label] / counts[label] as f64)
+            .fold(f64::INFINITY, f64::min);
+        if nearest.is_finite() {
+            total += (nearest - within) / within.max(nearest);
+        }
     }
+    Some(total / sample.len() as f64)
+}
 
-    pub fn kmeans() {
-        // Our random number generator, seeded for reproducibility
-        let mut rng = random(Some(42));
+fn kmeans(records: &Array2<f64>, min_k: usize, max_k: usize, seed: u64) -> Result<(Array1<usize>, Option<f64>), ClusterError> {
+    let max_k = max_k.min(records.nrows() - 1);
+    if max_k < 2 {
+        return Ok((Array1::zeros(records.nrows()), None));
+    }
+    let min_k = min_k.clamp(2, max_k);
 
-        // For each our expected centroids, generate `n` data points around it (a "blob")
-        let expected_centroids = array![[10., 10.], [1., 12.], [20., 30.], [-20., 30.],];
-        let n = 10000;
-        let dataset = DatasetBase::from(generate::blobs(
-            n,
-            &expected_centroids,
-            &mut rng
-        ));
+    let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+    let mut sample = (0..records.nrows()).collect::<Vec<usize>>();
+    for idx in (1..sample.len()).rev() {
+        sample.swap(idx, (rng.next_u64() % (idx as u64 + 1)) as usize);
+    }
+    sample.truncate(SILHOUETTE_SAMPLE);
+    let distances = Array2::from_shape_fn((sample.len(), sample.len()), |(i, j)| {
+        distance(records.row(sample[i]), records.row(sample[j]))
+    });
 
-        // Configure our training algorithm
-        let n_clusters = expected_centroids.len_of(Axis(0));
-        let model = KMeans::params_with(n_clusters, rng, LInfDist)
+    let dataset = DatasetBase::from(records.clone());
+    let mut best: Option<(Array1<usize>, f64)> = None;
+    for k in min_k..=max_k {
+        let model = KMeans::params_with_rng(k, Xoshiro256Plus::seed_from_u64(seed))
             .max_n_iterations(200)
             .tolerance(1e-5)
             .fit(&dataset)
-            .expect("KMeans fitted");
-
-        // Assign each point to a cluster using the set of centroids found using `fit`
-        let dataset = model.predict(dataset);
-        let DatasetBase {
-            records,
-            targets,
-            ..
-        } = dataset;
-
-        // Save to disk our dataset (and the cluster label assigned to each observation)
-        // We use the `npy` format for compatibility with NumPy
-        write_npy("clustered_dataset.npy", &records).expect("Failed to write .npy file");
-        write_npy("clustered_memberships.npy", &targets.map(|&x| x as u64))
-            .expect("Failed to write .npy file");
-    }
-}
\ No newline at end of file
+            .map_err(|err| ClusterError::Failed(err.to_string()))?;
+        let labels: Array1<usize> = model.predict(records);
+        let score = silhouette(&distances, &sample, &labels).unwrap_or(f64::NEG_INFINITY);
+        if best.as_ref().map_or(true, |(_, best)| score > *best) {
+            best = Some((labels, score));
+        }
+    }
+
+    let (labels, score) = best.ok_or_else(|| ClusterError::Failed("no k to try".into()))?;
+    Ok((labels, Some(score).filter(|score| score.is_finite())))
+}
+
+/// Distinctive words and word pairs of the cluster's utterances, by how much more often they come up in it than overall
+fn keywords(members: &[&str], document_frequency: &HashMap<String, usize>, documents: usize, max: usize) -> Vec<String> {
+    let mut cluster_frequency = BTreeMap::<String, usize>::new();
+    for utterance in members {
+        let mut seen = terms(utterance, 2);
+        seen.sort();
+        seen.dedup();
+        for term in seen {
+            let words = term.split(' ').collect::<Vec<&str>>();
+            if !words.first().map_or(true, |word| is_stopword(word)) && !words.last().map_or(true, |word| is_stopword(word)) {
+                *cluster_frequency.entry(term).or_default() += 1;
+            }
+        }
+    }
+
+    let min_frequency = if members.len() > 1 { 2 } else { 1 };
+    let mut scored = cluster_frequency
+        .into_iter()
+        .filter(|(_, frequency)| *frequency >= min_frequency)
+        .map(|(term, frequency)| {
+            let overall = document_frequency.get(&term).copied().unwrap_or(frequency) as f64;
+            let score = frequency as f64 / members.len() as f64 * (documents as f64 / overall).ln().max(0.0) + frequency as f64 * 1e-6;
+            (term, score)
+        })
+        .collect::<Vec<(String, f64)>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    scored.into_iter().take(max).map(|(term, _)| term).collect()
+}
+
+/// Groups `utterances` by their features, one row of `records` per utterance, with the same number of columns
+pub fn cluster(utterances: &[&str], mut records: Array2<f64>, params: &ClusterParams) -> Result<Clustering, ClusterError> {
+    if utterances.len() != records.nrows() {
+        return Err(ClusterError::Invalid(format!("{} utterances but {} feature rows", utterances.len(), records.nrows())));
+    }
+    if utterances.len() < 2 {
+        return Err(ClusterError::Invalid("at least 2 utterances are needed".into()));
+    }
+    normalize(&mut records);
+
+    let (labels, silhouette) = match params.algorithm {
+        Algorithm::KMeans { min_k, max_k } => {
+            let (labels, silhouette) = kmeans(&records, min_k, max_k, params.seed)?;
+            (labels.mapv(Some), silhouette)
+        }
+        Algorithm::Dbscan { min_points, tolerance } => {
+            let labels = Dbscan::params(min_points.max(2))
+                .tolerance(tolerance)
+                .check()
+                .map_err(|err| ClusterError::Invalid(err.to_string()))?
+                .transform(&records);
+            (labels, None)
+        }
+    };
+
+    let mut document_frequency = HashMap::<String, usize>::new();
+    for utterance in utterances {
+        let mut seen = terms(utterance, 2);
+        seen.sort();
+        seen.dedup();
+        for term in seen {
+            *document_frequency.entry(term).or_default() += 1;
+        }
+    }
+
+    let mut groups = BTreeMap::<usize, Vec<usize>>::new();
+    let mut noise = vec![];
+    for (idx, label) in labels.iter().enumerate() {
+        match label {
+            Some(label) => groups.entry(*label).or_default().push(idx),
+            None => noise.push(utterances[idx].to_string()),
+        }
+    }
+
+    let mut clusters = groups
+        .into_values()
+        .map(|members| {
+            let centre = records.select(Axis(0), &members).mean_axis(Axis(0)).unwrap_or_else(|| Array1::zeros(records.ncols()));
+            let mut closest = members.clone();
+            closest.sort_by(|a, b| distance(records.row(*a), centre.view()).total_cmp(&distance(records.row(*b), centre.view())));
+
+            let texts = members.iter().map(|idx| utterances[*idx]).collect::<Vec<&str>>();
+            let keywords = keywords(&texts, &document_frequency, utterances.len(), params.keywords);
+            Cluster {
+                id: 0,
+                size: members.len(),
+                suggested_intent: keywords.first().cloned().unwrap_or_default(),
+                keywords,
+                examples: closest.iter().take(params.examples).map(|idx| utterances[*idx].to_string()).collect(),
+            }
+        })
+        .collect::<Vec<Cluster>>();
+    clusters.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.examples.cmp(&b.examples)));
+    for (id, cluster) in clusters.iter_mut().enumerate() {
+        cluster.id = id;
+        if cluster.suggested_intent.is_empty() {
+            cluster.suggested_intent = format!("cluster {id}");
+        }
+    }
+
+    Ok(Clustering { clusters, noise, silhouette })
+}
+
+#[cfg(test)]
+mod cluster_tests {
+    use super::*;
+
+    fn utterances() -> Vec<&'static str> {
+        vec![
+            "please reset my password today",
+            "forgot my password, reset it",
+            "the password reset link expired",
+            "how do I reset a lost password",
+            "cancel my subscription now",
+            "I want to cancel the annual subscription",
+            "cancel subscription before renewal",
+            "stop and cancel my subscription",
+            "what is my refund status",
+            "refund status for order",
+            "check the status of my refund",
+            "refund status still pending",
+        ]
+    }
+
+    #[test]
+    fn distinct_drops_blanks_and_repeats() {
+        assert_eq!(distinct(&["Hi there ", "hi there", " ", "bye"]), vec!["Hi there", "bye"]);
+    }
+
+    #[test]
+    fn finds_k_and_names_clusters() {
+        let utterances = utterances();
+        let clustering = cluster(&utterances, tfidf(&utterances), &ClusterParams::default()).unwrap();
+
+        assert_eq!(clustering.clusters.len(), 3, "{clustering:?}");
+        assert!(clustering.silhouette.unwrap() > 0.0);
+        assert!(clustering.noise.is_empty());
+
+        let mut suggested = clustering.clusters.iter().map(|cluster| cluster.suggested_intent.clone()).collect::<Vec<String>>();
+        suggested.sort();
+        assert_eq!(suggested, vec!["cancel", "password", "refund"]);
+        for cluster in &clustering.clusters {
+            assert_eq!(cluster.size, 4);
+            assert_eq!(cluster.examples.len(), 4);
+            assert!(cluster.examples.iter().all(|example| example.contains(cluster.suggested_intent.as_str())));
+        }
+    }
+
+    #[test]
+    fn dbscan_leaves_out_noise() {
+        let mut utterances = utterances();
+        utterances.push("what is the weather on mars");
+        let params = ClusterParams {
+            algorithm: Algorithm::Dbscan { min_points: 2, tolerance: 1.25 },
+            ..Default::default()
+        };
+        let clustering = cluster(&utterances, tfidf(&utterances), &params).unwrap();
+
+        assert_eq!(clustering.noise, vec!["what is the weather on mars"]);
+        assert_eq!(clustering.clusters.len(), 3);
+        assert_eq!(clustering.silhouette, None);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(matches!(cluster(&["one"], tfidf(&["one"]), &ClusterParams::default()), Err(ClusterError::Invalid(_))));
+        assert!(matches!(cluster(&["one", "two"], tfidf(&["one"]), &ClusterParams::default()), Err(ClusterError::Invalid(_))));
+        assert!(matches!(embeddings(&[vec![1.0, 2.0], vec![1.0]]), Err(ClusterError::Invalid(_))));
+        assert_eq!(embeddings(&[vec![1.0, 2.0], vec![3.0, 4.0]]).unwrap().dim(), (2, 2));
+    }
+
+    #[test]
+    fn two_utterances_make_one_cluster() {
+        let utterances = ["reset my password", "password reset"];
+        let clustering = cluster(&utterances, tfidf(&utterances), &ClusterParams::default()).unwrap();
+        assert_eq!(clustering.clusters.len(), 1);
+        assert_eq!(clustering.clusters[0].suggested_intent, "password");
+    }
+}
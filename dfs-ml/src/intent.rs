@@ -0,0 +1,538 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use linfa::prelude::*;
+use ndarray::{Array1, Array2, Axis};
+use rand_xoshiro::Xoshiro256Plus;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the saved layout changes, older artifacts have to be trained again
+const ARTIFACT_VERSION: u32 = 1;
+
+/// Utterance labelled with the intent it expresses
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub utterance: String,
+    pub intent: String,
+}
+
+impl Example {
+    pub fn new<U: Into<String>, I: Into<String>>(utterance: U, intent: I) -> Self {
+        Self {
+            utterance: utterance.into(),
+            intent: intent.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TrainParams {
+    /// Longest run of words used as a feature, 2 adds word pairs to the single words
+    pub max_ngram: usize,
+    /// Features seen in fewer utterances are dropped
+    pub min_df: usize,
+    pub epochs: usize,
+    pub learning_rate: f64,
+    /// Weight decay, keeps rare words from dominating on small sets
+    pub l2: f64,
+    /// Share of each intent's examples held out to evaluate on before training on all of them
+    pub validation_ratio: f64,
+    pub seed: u64,
+}
+
+impl Default for TrainParams {
+    fn default() -> Self {
+        Self {
+            max_ngram: 2,
+            min_df: 1,
+            epochs: 300,
+            learning_rate: 2.0,
+            l2: 1e-4,
+            validation_ratio: 0.2,
+            seed: 42,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntentError {
+    Invalid(String),
+    Io(String),
+    Artifact(String),
+    Evaluation(String),
+}
+
+impl fmt::Display for IntentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntentError::Invalid(message) => write!(f, "Invalid training examples: {message}"),
+            IntentError::Io(message) => write!(f, "{message}"),
+            IntentError::Artifact(message) => write!(f, "Invalid intent model: {message}"),
+            IntentError::Evaluation(message) => write!(f, "Failed to evaluate the intent model: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for IntentError {}
+
+/// Lower cased words and the runs of up to `max_ngram` of them
+pub(crate) fn terms(text: &str, max_ngram: usize) -> Vec<String> {
+    let words = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+    let mut terms = vec![];
+    for n in 1..=max_ngram.max(1) {
+        terms.extend(words.windows(n).map(|window| window.join(" ")));
+    }
+    terms
+}
+
+/// Term frequency times smoothed inverse document frequency, scaled to unit length
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TfIdf {
+    max_ngram: usize,
+    vocabulary: HashMap<String, usize>,
+    idf: Vec<f64>,
+}
+
+impl TfIdf {
+    pub fn fit(texts: &[&str], max_ngram: usize, min_df: usize) -> Self {
+        let mut document_frequency = BTreeMap::<String, usize>::new();
+        for text in texts {
+            let mut seen = terms(text, max_ngram);
+            seen.sort();
+            seen.dedup();
+            for term in seen {
+                *document_frequency.entry(term).or_default() += 1;
+            }
+        }
+
+        let documents = texts.len() as f64;
+        let mut vocabulary = HashMap::new();
+        let mut idf = vec![];
+        for (term, frequency) in document_frequency.into_iter().filter(|(_, frequency)| *frequency >= min_df) {
+            vocabulary.insert(term, idf.len());
+            idf.push(((1.0 + documents) / (1.0 + frequency as f64)).ln() + 1.0);
+        }
+        Self { max_ngram, vocabulary, idf }
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.idf.len()
+    }
+
+    /// Non zero features of `text` by index, words never seen in training are left out
+    pub fn transform(&self, text: &str) -> Vec<(usize, f64)> {
+        let mut counts = BTreeMap::<usize, f64>::new();
+        for term in terms(text, self.max_ngram) {
+            if let Some(&idx) = self.vocabulary.get(&term) {
+                *counts.entry(idx).or_default() += 1.0;
+            }
+        }
+
+        let mut features = counts
+            .into_iter()
+            .map(|(idx, count)| (idx, count * self.idf[idx]))
+            .collect::<Vec<(usize, f64)>>();
+        let norm = features.iter().map(|(_, value)| value * value).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            features.iter_mut().for_each(|(_, value)| *value /= norm);
+        }
+        features
+    }
+
+    pub(crate) fn matrix(&self, texts: &[&str]) -> Array2<f64> {
+        let mut matrix = Array2::zeros((texts.len(), self.dimensions()));
+        for (row, text) in texts.iter().enumerate() {
+            for (idx, value) in self.transform(text) {
+                matrix[[row, idx]] = value;
+            }
+        }
+        matrix
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RankedIntent {
+    pub intent: String,
+    /// Probability of the intent, the scores of a prediction add up to 1
+    pub score: f64,
+}
+
+/// Held out scores of a training run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    pub examples: usize,
+    pub accuracy: f32,
+    /// Matthews correlation coefficient, 1 is perfect and 0 no better than chance
+    pub mcc: f32,
+    pub precision: f32,
+    pub recall: f32,
+    pub f1: f32,
+    pub labels: Vec<String>,
+    /// Counts by actual intent (row) and predicted intent (column), both in the order of `labels`
+    pub confusion: Vec<Vec<usize>>,
+}
+
+/// Supervised intent classifier, softmax regression over TF-IDF features
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntentModel {
+    version: u32,
+    labels: Vec<String>,
+    features: TfIdf,
+    /// One row of feature weights per label
+    weights: Vec<Vec<f64>>,
+    bias: Vec<f64>,
+}
+
+/// Model trained on every example, with its scores on the held out ones when there were enough
+#[derive(Debug, Clone)]
+pub struct Trained {
+    pub model: IntentModel,
+    pub evaluation: Option<Evaluation>,
+}
+
+fn softmax(logits: &mut [f64]) {
+    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let mut sum = 0.0;
+    for logit in logits.iter_mut() {
+        *logit = (*logit - max).exp();
+        sum += *logit;
+    }
+    logits.iter_mut().for_each(|logit| *logit /= sum);
+}
+
+/// Fisher-Yates with a seeded generator, so a training run can be repeated
+fn shuffle<T>(items: &mut [T], rng: &mut Xoshiro256Plus) {
+    for idx in (1..items.len()).rev() {
+        let other = (rng.next_u64() % (idx as u64 + 1)) as usize;
+        items.swap(idx, other);
+    }
+}
+
+fn validate(examples: &[Example]) -> Result<Vec<String>, IntentError> {
+    if let Some(idx) = examples.iter().position(|example| example.utterance.trim().is_empty() || example.intent.trim().is_empty()) {
+        return Err(IntentError::Invalid(format!("example {idx} needs an utterance and an intent")));
+    }
+
+    let mut labels = examples.iter().map(|example| example.intent.clone()).collect::<Vec<String>>();
+    labels.sort();
+    labels.dedup();
+    if labels.len() < 2 {
+        return Err(IntentError::Invalid(format!("examples of at least 2 intents are needed, got {labels:?}")));
+    }
+    Ok(labels)
+}
+
+impl IntentModel {
+    /// Trains on every example without holding any out
+    pub fn fit(examples: &[Example], params: &TrainParams) -> Result<Self, IntentError> {
+        let labels = validate(examples)?;
+        let utterances = examples.iter().map(|example| example.utterance.as_str()).collect::<Vec<&str>>();
+        let features = TfIdf::fit(&utterances, params.max_ngram, params.min_df);
+        let records = features.matrix(&utterances);
+        let targets = examples
+            .iter()
+            .map(|example| labels.binary_search(&example.intent).unwrap_or_default())
+            .collect::<Array1<usize>>();
+
+        let samples = examples.len() as f64;
+        let mut weights = Array2::<f64>::zeros((labels.len(), features.dimensions()));
+        let mut bias = Array1::<f64>::zeros(labels.len());
+        for _ in 0..params.epochs {
+            let mut gradient = records.dot(&weights.t()) + &bias;
+            for (mut row, &target) in gradient.rows_mut().into_iter().zip(targets.iter()) {
+                softmax(row.as_slice_mut().expect("rows of a standard layout array are contiguous"));
+                row[target] -= 1.0;
+            }
+            gradient /= samples;
+
+            let weight_gradient = gradient.t().dot(&records) + &weights * params.l2;
+            weights.scaled_add(-params.learning_rate, &weight_gradient);
+            bias.scaled_add(-params.learning_rate, &gradient.sum_axis(Axis(0)));
+        }
+
+        Ok(Self {
+            version: ARTIFACT_VERSION,
+            labels,
+            features,
+            weights: weights.rows().into_iter().map(|row| row.to_vec()).collect(),
+            bias: bias.to_vec(),
+        })
+    }
+
+    /// Holds out `params.validation_ratio` of each intent's examples to evaluate on, then trains on all of them
+    pub fn train(examples: &[Example], params: &TrainParams) -> Result<Trained, IntentError> {
+        validate(examples)?;
+
+        let mut by_intent = BTreeMap::<&str, Vec<&Example>>::new();
+        for example in examples {
+            by_intent.entry(example.intent.as_str()).or_default().push(example);
+        }
+        let mut rng = Xoshiro256Plus::seed_from_u64(params.seed);
+        let (mut training, mut validation) = (vec![], vec![]);
+        for (_, mut group) in by_intent {
+            shuffle(&mut group, &mut rng);
+            // every intent keeps at least one example to train on
+            let held_out = ((group.len() as f64 * params.validation_ratio).floor() as usize).min(group.len() - 1);
+            validation.extend(group.drain(..held_out).cloned());
+            training.extend(group.into_iter().cloned());
+        }
+
+        let evaluation = if validation.is_empty() {
+            None
+        } else {
+            Some(Self::fit(&training, params)?.evaluate(&validation)?)
+        };
+        Ok(Trained {
+            model: Self::fit(examples, params)?,
+            evaluation,
+        })
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Every intent the model knows, most likely first
+    pub fn predict(&self, utterance: &str) -> Vec<RankedIntent> {
+        let features = self.features.transform(utterance);
+        let mut scores = self.weights
+            .iter()
+            .zip(&self.bias)
+            .map(|(weights, bias)| bias + features.iter().map(|(idx, value)| weights[*idx] * value).sum::<f64>())
+            .collect::<Vec<f64>>();
+        softmax(&mut scores);
+
+        let mut ranked = self.labels
+            .iter()
+            .zip(scores)
+            .map(|(intent, score)| RankedIntent { intent: intent.clone(), score })
+            .collect::<Vec<RankedIntent>>();
+        ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+        ranked
+    }
+
+    fn label_of(&self, intent: &str) -> Result<usize, IntentError> {
+        self.labels
+            .iter()
+            .position(|label| label == intent)
+            .ok_or_else(|| IntentError::Evaluation(format!("the model was not trained on intent '{intent}'")))
+    }
+
+    /// Scores the model on labelled examples it has not been trained on
+    pub fn evaluate(&self, examples: &[Example]) -> Result<Evaluation, IntentError> {
+        if examples.is_empty() {
+            return Err(IntentError::Evaluation("no examples to evaluate on".into()));
+        }
+
+        let targets = examples
+            .iter()
+            .map(|example| self.label_of(&example.intent))
+            .collect::<Result<Array1<usize>, IntentError>>()?;
+        let predictions = examples
+            .iter()
+            .map(|example| self.label_of(&self.predict(&example.utterance)[0].intent))
+            .collect::<Result<Array1<usize>, IntentError>>()?;
+
+        let dataset = Dataset::new(Array2::<f64>::zeros((examples.len(), 1)), targets.clone());
+        let cm = predictions
+            .confusion_matrix(&dataset)
+            .map_err(|err| IntentError::Evaluation(err.to_string()))?;
+
+        // counted here too, the linfa matrix only has the intents that came up and keeps its counts private
+        let mut confusion = vec![vec![0; self.labels.len()]; self.labels.len()];
+        for (&actual, &predicted) in targets.iter().zip(predictions.iter()) {
+            confusion[actual][predicted] += 1;
+        }
+
+        Ok(Evaluation {
+            examples: examples.len(),
+            accuracy: cm.accuracy(),
+            mcc: cm.mcc(),
+            precision: cm.precision(),
+            recall: cm.recall(),
+            f1: cm.f1_score(),
+            labels: self.labels.clone(),
+            confusion,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IntentError> {
+        let path = path.as_ref();
+        let json = serde_json::to_string(self).map_err(|err| IntentError::Artifact(err.to_string()))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|err| IntentError::Io(format!("Failed to create {}: {err}", dir.display())))?;
+        }
+        std::fs::write(path, json).map_err(|err| IntentError::Io(format!("Failed to write {}: {err}", path.display())))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IntentError> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|err| IntentError::Io(format!("Failed to read {}: {err}", path.display())))?;
+        let model = serde_json::from_str::<Self>(&json).map_err(|err| IntentError::Artifact(format!("{}: {err}", path.display())))?;
+        if model.version != ARTIFACT_VERSION {
+            return Err(IntentError::Artifact(format!(
+                "{} is version {}, train it again for version {ARTIFACT_VERSION}",
+                path.display(),
+                model.version
+            )));
+        }
+        Ok(model)
+    }
+}
+
+/// Examples of a `.csv` file with `utterance` and `intent` columns, or of a file with one json example per line
+pub fn read_examples<P: AsRef<Path>>(path: P) -> Result<Vec<Example>, IntentError> {
+    let path = path.as_ref();
+    let read_error = |err: String| IntentError::Io(format!("Failed to read examples from {}: {err}", path.display()));
+
+    if path.extension().map_or(false, |extension| extension == "csv") {
+        let mut reader = csv::Reader::from_path(path).map_err(|err| read_error(err.to_string()))?;
+        return reader
+            .deserialize::<Example>()
+            .map(|example| example.map_err(|err| read_error(err.to_string())))
+            .collect();
+    }
+
+    std::fs::read_to_string(path)
+        .map_err(|err| read_error(err.to_string()))?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| serde_json::from_str::<Example>(line).map_err(|err| read_error(format!("line {}: {err}", idx + 1))))
+        .collect()
+}
+
+#[cfg(test)]
+mod intent_tests {
+    use super::*;
+
+    fn examples() -> Vec<Example> {
+        let mut examples = vec![];
+        for utterance in [
+            "How do I pay my bill?",
+            "Where do I send the money?",
+            "Can I pay by card?",
+            "What do I owe this month?",
+            "Why was my card charged twice?",
+            "I want a refund on my invoice",
+        ] {
+            examples.push(Example::new(utterance, "billing"));
+        }
+        for utterance in [
+            "I am locked out!",
+            "I forgot my password",
+            "Reset my password please",
+            "The login page rejects me",
+            "I can't sign in",
+            "My account is locked",
+        ] {
+            examples.push(Example::new(utterance, "login issue"));
+        }
+        for utterance in [
+            "Can I talk to a person?",
+            "Put me through to support",
+            "I need a human",
+            "How do I contact support?",
+            "Give me your phone number",
+            "Is there someone I can call?",
+        ] {
+            examples.push(Example::new(utterance, "contact support"));
+        }
+        examples
+    }
+
+    #[test]
+    fn terms_include_word_pairs() {
+        assert_eq!(terms("Locked out!", 2), vec!["locked", "out", "locked out"]);
+        assert_eq!(terms("  ", 2), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ranks_intents() {
+        let trained = IntentModel::train(&examples(), &TrainParams::default()).unwrap();
+
+        let ranked = trained.model.predict("I forgot my password and I am locked out");
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(ranked[0].intent, "login issue");
+        assert!(ranked[0].score > ranked[1].score);
+        assert!((ranked.iter().map(|intent| intent.score).sum::<f64>() - 1.0).abs() < 1e-9);
+
+        assert_eq!(trained.model.predict("how can I pay")[0].intent, "billing");
+        assert_eq!(trained.model.predict("let me talk to support")[0].intent, "contact support");
+    }
+
+    #[test]
+    fn evaluates_held_out_examples() {
+        let trained = IntentModel::train(&examples(), &TrainParams { validation_ratio: 0.34, ..Default::default() }).unwrap();
+        let evaluation = trained.evaluation.unwrap();
+
+        assert_eq!(evaluation.examples, 6);
+        assert_eq!(evaluation.labels, vec!["billing", "contact support", "login issue"]);
+        assert_eq!(evaluation.confusion.iter().flatten().sum::<usize>(), 6);
+        assert!((0.0..=1.0).contains(&evaluation.accuracy));
+
+        let perfect = trained.model.evaluate(&examples()).unwrap();
+        assert_eq!(perfect.accuracy, 1.0);
+        assert_eq!(perfect.confusion[0], vec![6, 0, 0]);
+    }
+
+    #[test]
+    fn skips_evaluation_without_enough_examples() {
+        let examples = vec![Example::new("pay", "billing"), Example::new("locked out", "login issue")];
+        let trained = IntentModel::train(&examples, &TrainParams::default()).unwrap();
+        assert_eq!(trained.evaluation, None);
+    }
+
+    #[test]
+    fn rejects_invalid_examples() {
+        assert!(matches!(IntentModel::fit(&[], &TrainParams::default()), Err(IntentError::Invalid(_))));
+        assert!(matches!(
+            IntentModel::fit(&[Example::new("pay", "billing"), Example::new("card", "billing")], &TrainParams::default()),
+            Err(IntentError::Invalid(_))
+        ));
+        assert!(matches!(
+            IntentModel::fit(&[Example::new(" ", "billing"), Example::new("locked", "login")], &TrainParams::default()),
+            Err(IntentError::Invalid(_))
+        ));
+
+        let model = IntentModel::fit(&examples(), &TrainParams::default()).unwrap();
+        assert!(matches!(model.evaluate(&[Example::new("hello", "greeting")]), Err(IntentError::Evaluation(_))));
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let model = IntentModel::fit(&examples(), &TrainParams::default()).unwrap();
+        let path = std::env::temp_dir().join(format!("dfs-ml-intent-{}.json", std::process::id()));
+
+        model.save(&path).unwrap();
+        assert_eq!(IntentModel::load(&path).unwrap(), model);
+
+        std::fs::write(&path, "{}").unwrap();
+        assert!(matches!(IntentModel::load(&path), Err(IntentError::Artifact(_))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(IntentModel::load(&path), Err(IntentError::Io(_))));
+    }
+
+    #[test]
+    fn reads_examples() {
+        let dir = std::env::temp_dir();
+        let csv = dir.join(format!("dfs-ml-examples-{}.csv", std::process::id()));
+        let jsonl = dir.join(format!("dfs-ml-examples-{}.jsonl", std::process::id()));
+        std::fs::write(&csv, "utterance,intent\n\"Can I pay, by card?\",billing\n").unwrap();
+        std::fs::write(&jsonl, "{\"utterance\": \"I am locked out\", \"intent\": \"login issue\"}\n\n").unwrap();
+
+        assert_eq!(read_examples(&csv).unwrap(), vec![Example::new("Can I pay, by card?", "billing")]);
+        assert_eq!(read_examples(&jsonl).unwrap(), vec![Example::new("I am locked out", "login issue")]);
+        std::fs::remove_file(&csv).unwrap();
+        std::fs::remove_file(&jsonl).unwrap();
+    }
+}
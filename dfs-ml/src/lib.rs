@@ -1,4 +1,6 @@
-// mod cluster;
 // mod svm;
 pub mod bert;
+pub mod cluster;
+pub mod intent;
 pub mod neo;
+pub mod registry;
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rust_bert::RustBertError;
+use rust_bert::pipelines::common::ModelType;
+use rust_bert::pipelines::ner::{Entity, NERModel};
+use rust_bert::pipelines::question_answering::{Answer, QaInput};
+use rust_bert::pipelines::sequence_classification::Label;
+use rust_bert::pipelines::text_generation::{TextGenerationConfig, TextGenerationModel};
+use rust_bert::pipelines::token_classification::{LabelAggregationOption, TokenClassificationConfig};
+use rust_bert::pipelines::zero_shot_classification::{ZeroShotClassificationConfig, ZeroShotClassificationModel};
+use rust_bert::resources::LocalResource;
+use serde::{Deserialize, Serialize};
+
+use crate::bert::ul::{self, QaModel, SentenceEncoder};
+
+/// Weights file of every kind of model, its size stands in for the memory the model takes
+const WEIGHTS: &str = "rust_model.ot";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    QuestionAnswering,
+    ZeroShot,
+    SentenceEmbeddings,
+    TextGeneration,
+    /// Named entity recognition, labels such as `PER`, `ORG`, `LOC` and `MISC`
+    Ner,
+}
+
+impl ModelKind {
+    /// Files the model directory has to hold
+    pub fn files(&self) -> &'static [&'static str] {
+        match self {
+            ModelKind::QuestionAnswering | ModelKind::Ner => &[WEIGHTS, "config.json", "vocab.txt"],
+            ModelKind::ZeroShot | ModelKind::TextGeneration => &[WEIGHTS, "config.json", "vocab.json", "merges.txt"],
+            ModelKind::SentenceEmbeddings => &[WEIGHTS, "config.json", "modules.json"],
+        }
+    }
+}
+
+impl fmt::Display for ModelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ModelKind::QuestionAnswering => "question_answering",
+            ModelKind::ZeroShot => "zero_shot",
+            ModelKind::SentenceEmbeddings => "sentence_embeddings",
+            ModelKind::TextGeneration => "text_generation",
+            ModelKind::Ner => "ner",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Where a model's weights are and how to read them, nothing is ever downloaded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelSpec {
+    pub name: String,
+    pub kind: ModelKind,
+    /// Directory with the files of `kind.files()`
+    pub dir: PathBuf,
+    /// Architecture of the weights, `bert`/`distilbert` for question answering and ner, `bart` for zero shot and
+    /// `gpt_neo`/`gpt2` for text generation. Sentence embeddings read it from the directory.
+    #[serde(default)]
+    pub model_type: String,
+    #[serde(default)]
+    pub lower_case: bool,
+    /// Loaded by `ModelRegistry::warm_up` rather than on first use
+    #[serde(default)]
+    pub warm_up: bool,
+    /// Decoding of text generation models, other kinds ignore it
+    #[serde(default)]
+    pub generation: GenerationParams,
+}
+
+/// Beam search settings of a text generation model
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct GenerationParams {
+    pub num_beams: i64,
+    /// N-grams of this size are never repeated, 0 allows any repetition
+    pub no_repeat_ngram_size: i64,
+    /// Most tokens of a generated sequence, the prompt included
+    pub max_length: i64,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            num_beams: 5,
+            no_repeat_ngram_size: 2,
+            max_length: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelError {
+    Unknown(String),
+    MissingWeights {
+        name: String,
+        path: PathBuf,
+    },
+    WrongKind {
+        name: String,
+        expected: ModelKind,
+        actual: ModelKind,
+    },
+    Load {
+        name: String,
+        message: String,
+    },
+}
+
+impl ModelError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ModelError::Unknown(_) => "unknown_model",
+            ModelError::MissingWeights { .. } => "missing_weights",
+            ModelError::WrongKind { .. } => "wrong_model_kind",
+            ModelError::Load { .. } => "model_load_failed",
+        }
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Unknown(name) => write!(f, "No model named '{name}' is registered"),
+            ModelError::MissingWeights { name, path } => {
+                write!(f, "Model '{name}' is missing {}, copy the weights there, they are not downloaded", path.display())
+            }
+            ModelError::WrongKind { name, expected, actual } => write!(f, "Model '{name}' is {actual}, not {expected}"),
+            ModelError::Load { name, message } => write!(f, "Failed to load model '{name}': {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// A loaded model, ready to run
+pub enum Model {
+    QuestionAnswering(QaModel),
+    ZeroShot(ZeroShotClassificationModel),
+    SentenceEmbeddings(SentenceEncoder),
+    TextGeneration(TextGenerationModel),
+    Ner(NERModel),
+}
+
+impl Model {
+    pub fn kind(&self) -> ModelKind {
+        match self {
+            Model::QuestionAnswering(_) => ModelKind::QuestionAnswering,
+            Model::ZeroShot(_) => ModelKind::ZeroShot,
+            Model::SentenceEmbeddings(_) => ModelKind::SentenceEmbeddings,
+            Model::TextGeneration(_) => ModelKind::TextGeneration,
+            Model::Ner(_) => ModelKind::Ner,
+        }
+    }
+
+    /// Reads the model of `spec` from disk, every file is checked first so a missing one is named
+    pub fn load(spec: &ModelSpec) -> Result<Self, ModelError> {
+        for file in spec.kind.files() {
+            let path = spec.dir.join(file);
+            if !path.is_file() {
+                return Err(ModelError::MissingWeights { name: spec.name.clone(), path });
+            }
+        }
+
+        let load_error = |err: RustBertError| ModelError::Load { name: spec.name.clone(), message: err.to_string() };
+        let model_type = |supported: &[(&str, ModelType)]| {
+            supported
+                .iter()
+                .find(|(name, _)| *name == spec.model_type)
+                .map(|(_, model_type)| *model_type)
+                .ok_or_else(|| ModelError::Load {
+                    name: spec.name.clone(),
+                    message: format!("unsupported model type '{}' for {}", spec.model_type, spec.kind),
+                })
+        };
+        let resource = |file: &str| LocalResource::from(spec.dir.join(file));
+
+        Ok(match spec.kind {
+            ModelKind::QuestionAnswering => {
+                Model::QuestionAnswering(QaModel::from_dir(&spec.dir, &spec.model_type, spec.lower_case).map_err(load_error)?)
+            }
+            ModelKind::ZeroShot => {
+                let config = ZeroShotClassificationConfig::new(
+                    model_type(&[("bart", ModelType::Bart)])?,
+                    resource(WEIGHTS),
+                    resource("config.json"),
+                    resource("vocab.json"),
+                    Some(resource("merges.txt")),
+                    spec.lower_case,
+                    None,
+                    None,
+                );
+                Model::ZeroShot(ZeroShotClassificationModel::new(config).map_err(load_error)?)
+            }
+            ModelKind::SentenceEmbeddings => Model::SentenceEmbeddings(SentenceEncoder::from_dir(&spec.dir).map_err(load_error)?),
+            ModelKind::TextGeneration => {
+                let config = TextGenerationConfig {
+                    model_type: model_type(&[("gpt_neo", ModelType::GPTNeo), ("gpt2", ModelType::GPT2)])?,
+                    model_resource: Box::new(resource(WEIGHTS)),
+                    config_resource: Box::new(resource("config.json")),
+                    vocab_resource: Box::new(resource("vocab.json")),
+                    merges_resource: Box::new(resource("merges.txt")),
+                    num_beams: spec.generation.num_beams,
+                    no_repeat_ngram_size: spec.generation.no_repeat_ngram_size,
+                    max_length: Some(spec.generation.max_length),
+                    ..Default::default()
+                };
+                Model::TextGeneration(TextGenerationModel::new(config).map_err(load_error)?)
+            }
+            ModelKind::Ner => {
+                let config = TokenClassificationConfig::new(
+                    model_type(&[("bert", ModelType::Bert), ("distilbert", ModelType::DistilBert)])?,
+                    resource(WEIGHTS),
+                    resource("config.json"),
+                    resource("vocab.txt"),
+                    None,
+                    spec.lower_case,
+                    None,
+                    None,
+                    LabelAggregationOption::Mode,
+                );
+                Model::Ner(NERModel::new(config).map_err(load_error)?)
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ModelState {
+    NotLoaded,
+    Loading,
+    Ready,
+    Failed { error: String },
+}
+
+/// What `/models/health` reports per model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelHealth {
+    pub name: String,
+    pub kind: ModelKind,
+    pub dir: PathBuf,
+    #[serde(flatten)]
+    pub state: ModelState,
+    /// Size of the weights on disk, roughly the memory the model takes once loaded
+    pub weights_bytes: Option<u64>,
+    /// Seconds since the epoch
+    pub loaded_at: Option<u64>,
+    pub load_ms: Option<u64>,
+    /// Times the model was replaced while serving
+    pub swaps: u32,
+    /// Why the last swap failed, the previous model kept serving
+    pub swap_error: Option<String>,
+}
+
+struct Entry {
+    spec: ModelSpec,
+    state: ModelState,
+    model: Option<Arc<Mutex<Model>>>,
+    /// Held while the model loads, so two callers never load it twice
+    loading: Arc<Mutex<()>>,
+    weights_bytes: Option<u64>,
+    loaded_at: Option<u64>,
+    load_ms: Option<u64>,
+    swaps: u32,
+    swap_error: Option<String>,
+}
+
+impl Entry {
+    fn new(spec: ModelSpec) -> Self {
+        Self {
+            spec,
+            state: ModelState::NotLoaded,
+            model: None,
+            loading: Arc::new(Mutex::new(())),
+            weights_bytes: None,
+            loaded_at: None,
+            load_ms: None,
+            swaps: 0,
+            swap_error: None,
+        }
+    }
+
+    fn install(&mut self, model: Arc<Mutex<Model>>, spec: &ModelSpec, load_ms: u64) {
+        self.model = Some(model);
+        self.state = ModelState::Ready;
+        self.weights_bytes = weights_bytes(&spec.dir);
+        self.loaded_at = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|elapsed| elapsed.as_secs());
+        self.load_ms = Some(load_ms);
+        self.swap_error = None;
+    }
+
+    fn health(&self) -> ModelHealth {
+        ModelHealth {
+            name: self.spec.name.clone(),
+            kind: self.spec.kind,
+            dir: self.spec.dir.clone(),
+            state: self.state.clone(),
+            weights_bytes: self.weights_bytes,
+            loaded_at: self.loaded_at,
+            load_ms: self.load_ms,
+            swaps: self.swaps,
+            swap_error: self.swap_error.clone(),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Models read from local directories, each loaded once on first use (or by `warm_up`) and shared after.
+/// A model runs one call at a time, callers on other threads wait for it.
+#[derive(Default)]
+pub struct ModelRegistry {
+    entries: RwLock<HashMap<String, Entry>>,
+}
+
+impl ModelRegistry {
+    pub fn new(specs: Vec<ModelSpec>) -> Self {
+        let registry = Self::default();
+        for spec in specs {
+            registry.register(spec);
+        }
+        registry
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, Entry>> {
+        self.entries.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, Entry>> {
+        self.entries.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Adds `spec`, or replaces the spec and drops the model of one with the same name
+    pub fn register(&self, spec: ModelSpec) {
+        self.write().insert(spec.name.clone(), Entry::new(spec));
+    }
+
+    pub fn spec(&self, name: &str) -> Option<ModelSpec> {
+        self.read().get(name).map(|entry| entry.spec.clone())
+    }
+
+    /// The model named `name`, loaded from disk by the first caller
+    pub fn get(&self, name: &str) -> Result<Arc<Mutex<Model>>, ModelError> {
+        let loading = {
+            let entries = self.read();
+            let entry = entries.get(name).ok_or_else(|| ModelError::Unknown(name.into()))?;
+            if let Some(model) = &entry.model {
+                return Ok(model.clone());
+            }
+            entry.loading.clone()
+        };
+
+        let _loading = lock(&loading);
+        // loaded by whoever held the lock before us
+        let spec = {
+            let mut entries = self.write();
+            let entry = entries.get_mut(name).ok_or_else(|| ModelError::Unknown(name.into()))?;
+            if let Some(model) = &entry.model {
+                return Ok(model.clone());
+            }
+            entry.state = ModelState::Loading;
+            entry.spec.clone()
+        };
+
+        let loaded = self.load(&spec);
+        let mut entries = self.write();
+        let entry = entries.get_mut(name).ok_or_else(|| ModelError::Unknown(name.into()))?;
+        match loaded {
+            Ok((model, load_ms)) => {
+                let model = Arc::new(Mutex::new(model));
+                entry.install(model.clone(), &spec, load_ms);
+                Ok(model)
+            }
+            Err(err) => {
+                entry.state = ModelState::Failed { error: err.to_string() };
+                Err(err)
+            }
+        }
+    }
+
+    fn load(&self, spec: &ModelSpec) -> Result<(Model, u64), ModelError> {
+        log::info!("Loading model {} from {}", spec.name, spec.dir.display());
+        let started = Instant::now();
+        let model = Model::load(spec).map_err(|err| {
+            log::error!("{err}");
+            err
+        })?;
+        let load_ms = started.elapsed().as_millis() as u64;
+        log::info!("Loaded model {} in {load_ms}ms", spec.name);
+        Ok((model, load_ms))
+    }
+
+    /// Loads every model marked `warm_up`, so the first request does not wait for it
+    pub fn warm_up(&self) -> Vec<(String, Result<(), ModelError>)> {
+        let names = self.read()
+            .values()
+            .filter(|entry| entry.spec.warm_up)
+            .map(|entry| entry.spec.name.clone())
+            .collect::<Vec<String>>();
+        names
+            .into_iter()
+            .map(|name| {
+                let loaded = self.get(&name).map(|_| ());
+                (name, loaded)
+            })
+            .collect()
+    }
+
+    /// Loads `spec` next to the model it replaces and swaps it in once ready. Calls in flight finish on
+    /// the old model. When loading fails the old model keeps serving and the error is reported in its health.
+    pub fn swap(&self, spec: ModelSpec) -> Result<ModelHealth, ModelError> {
+        let loading = {
+            let mut entries = self.write();
+            entries
+                .entry(spec.name.clone())
+                .or_insert_with(|| Entry::new(spec.clone()))
+                .loading
+                .clone()
+        };
+        let _loading = lock(&loading);
+
+        let loaded = self.load(&spec);
+        let mut entries = self.write();
+        let entry = entries.get_mut(&spec.name).ok_or_else(|| ModelError::Unknown(spec.name.clone()))?;
+        match loaded {
+            Ok((model, load_ms)) => {
+                if entry.model.is_some() {
+                    entry.swaps += 1;
+                }
+                entry.install(Arc::new(Mutex::new(model)), &spec, load_ms);
+                entry.spec = spec;
+                Ok(entry.health())
+            }
+            Err(err) => {
+                if entry.model.is_some() {
+                    entry.swap_error = Some(err.to_string());
+                } else {
+                    entry.spec = spec;
+                    entry.state = ModelState::Failed { error: err.to_string() };
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Swaps in the model's own spec again, picking up new weights in the same directory
+    pub fn reload(&self, name: &str) -> Result<ModelHealth, ModelError> {
+        let spec = self.spec(name).ok_or_else(|| ModelError::Unknown(name.into()))?;
+        self.swap(spec)
+    }
+
+    /// Load status of every model, by name
+    pub fn health(&self) -> Vec<ModelHealth> {
+        let mut health = self.read().values().map(Entry::health).collect::<Vec<ModelHealth>>();
+        health.sort_by(|a, b| a.name.cmp(&b.name));
+        health
+    }
+
+    fn with_model<T, F>(&self, name: &str, kind: ModelKind, run: F) -> Result<T, ModelError>
+    where
+        F: FnOnce(&Model) -> Result<T, ModelError>,
+    {
+        let model = self.get(name)?;
+        let model = lock(&model);
+        if model.kind() != kind {
+            return Err(ModelError::WrongKind { name: name.into(), expected: kind, actual: model.kind() });
+        }
+        run(&model)
+    }
+
+    /// Up to `top_k` answers per input, best first
+    pub fn answer(&self, name: &str, inputs: &[QaInput], top_k: i64, max_answer_length: usize) -> Result<Vec<Vec<Answer>>, ModelError> {
+        self.with_model(name, ModelKind::QuestionAnswering, |model| match model {
+            Model::QuestionAnswering(model) => Ok(model.answer(inputs, top_k, max_answer_length)),
+            _ => unreachable!("checked by with_model"),
+        })
+    }
+
+    /// Scores of every label per sentence
+    pub fn classify(&self, name: &str, sentences: &[&str], labels: &[&str]) -> Result<Vec<Vec<Label>>, ModelError> {
+        self.with_model(name, ModelKind::ZeroShot, |model| match model {
+            Model::ZeroShot(model) => Ok(ul::intent(model, sentences, labels)),
+            _ => unreachable!("checked by with_model"),
+        })
+    }
+
+    /// One vector per sentence
+    pub fn encode(&self, name: &str, sentences: &[&str]) -> Result<Vec<Vec<f32>>, ModelError> {
+        self.with_model(name, ModelKind::SentenceEmbeddings, |model| match model {
+            Model::SentenceEmbeddings(model) => model
+                .encode(sentences)
+                .map_err(|err| ModelError::Load { name: name.into(), message: err.to_string() }),
+            _ => unreachable!("checked by with_model"),
+        })
+    }
+
+    /// Named entities per sentence, with their character offsets in it
+    pub fn entities(&self, name: &str, sentences: &[&str]) -> Result<Vec<Vec<Entity>>, ModelError> {
+        self.with_model(name, ModelKind::Ner, |model| match model {
+            Model::Ner(model) => Ok(model.predict(sentences)),
+            _ => unreachable!("checked by with_model"),
+        })
+    }
+
+    /// Continuations of each prompt, starting with `prefix` when there is one
+    pub fn generate(&self, name: &str, prompts: &[&str], prefix: Option<&str>) -> Result<Vec<String>, ModelError> {
+        self.with_model(name, ModelKind::TextGeneration, |model| match model {
+            Model::TextGeneration(model) => Ok(model.generate(prompts, prefix)),
+            _ => unreachable!("checked by with_model"),
+        })
+    }
+}
+
+fn weights_bytes(dir: &Path) -> Option<u64> {
+    std::fs::metadata(dir.join(WEIGHTS)).ok().map(|metadata| metadata.len())
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+
+    fn spec(name: &str, kind: ModelKind, dir: &str) -> ModelSpec {
+        ModelSpec {
+            name: name.into(),
+            kind,
+            dir: dir.into(),
+            model_type: "distilbert".into(),
+            lower_case: false,
+            warm_up: true,
+            generation: GenerationParams::default(),
+        }
+    }
+
+    #[test]
+    fn unknown_model() {
+        let registry = ModelRegistry::default();
+        assert_eq!(registry.get("qa").err(), Some(ModelError::Unknown("qa".into())));
+        assert_eq!(registry.reload("qa").err(), Some(ModelError::Unknown("qa".into())));
+        assert!(registry.health().is_empty());
+    }
+
+    #[test]
+    fn generation_defaults_to_beam_search() {
+        let spec = serde_json::from_str::<ModelSpec>(
+            r#"{"name": "neo", "kind": "text_generation", "dir": "models/neo", "generation": {"max_length": 40}}"#,
+        ).unwrap();
+        assert_eq!(spec.generation, GenerationParams { max_length: 40, ..Default::default() });
+        assert_eq!(GenerationParams::default(), GenerationParams { num_beams: 5, no_repeat_ngram_size: 2, max_length: 100 });
+    }
+
+    #[test]
+    fn names_the_missing_weights() {
+        let registry = ModelRegistry::new(vec![spec("qa", ModelKind::QuestionAnswering, "/does/not/exist")]);
+        assert_eq!(registry.health()[0].state, ModelState::NotLoaded);
+
+        let err = registry.answer("qa", &[], 1, 16).unwrap_err();
+        assert_eq!(
+            err,
+            ModelError::MissingWeights { name: "qa".into(), path: PathBuf::from("/does/not/exist/rust_model.ot") }
+        );
+        assert!(err.to_string().contains("/does/not/exist/rust_model.ot"));
+
+        let health = registry.health();
+        assert_eq!(health[0].state, ModelState::Failed { error: err.to_string() });
+        assert_eq!(health[0].weights_bytes, None);
+    }
+
+    #[test]
+    fn warms_up_marked_models() {
+        let registry = ModelRegistry::new(vec![
+            spec("qa", ModelKind::QuestionAnswering, "/does/not/exist"),
+            ModelSpec { warm_up: false, ..spec("intent", ModelKind::ZeroShot, "/does/not/exist") },
+        ]);
+
+        let warmed = registry.warm_up();
+        assert_eq!(warmed.len(), 1);
+        assert_eq!(warmed[0].0, "qa");
+        assert!(matches!(warmed[0].1, Err(ModelError::MissingWeights { .. })));
+
+        let health = registry.health();
+        assert_eq!(health[0].name, "intent");
+        assert_eq!(health[0].state, ModelState::NotLoaded);
+    }
+
+    #[test]
+    fn failed_swap_of_unloaded_model_takes_the_new_spec() {
+        let registry = ModelRegistry::new(vec![spec("qa", ModelKind::QuestionAnswering, "/does/not/exist")]);
+        let moved = spec("qa", ModelKind::QuestionAnswering, "/does/not/exist/either");
+
+        assert!(registry.swap(moved.clone()).is_err());
+        assert_eq!(registry.spec("qa"), Some(moved));
+        assert_eq!(registry.health()[0].swaps, 0);
+    }
+
+    #[test]
+    fn ner_models_are_checked_like_the_others() {
+        let registry = ModelRegistry::new(vec![spec("ner", ModelKind::Ner, "/does/not/exist")]);
+        assert_eq!(serde_json::to_value(ModelKind::Ner).unwrap(), "ner");
+        assert!(matches!(registry.entities("ner", &["Jane works at Acme"]), Err(ModelError::MissingWeights { .. })));
+        assert!(matches!(registry.entities("qa", &["Jane"]), Err(ModelError::Unknown(_))));
+    }
+
+    #[test]
+    #[ignore = "needs the weights in models/distilbert-qa"]
+    fn shares_loaded_models() {
+        let registry = ModelRegistry::new(vec![spec("qa", ModelKind::QuestionAnswering, "models/distilbert-qa")]);
+        let first = registry.get("qa").unwrap();
+        let second = registry.get("qa").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(registry.health()[0].weights_bytes.unwrap() > 0);
+
+        registry.reload("qa").unwrap();
+        assert!(!Arc::ptr_eq(&first, &registry.get("qa").unwrap()));
+        assert_eq!(registry.health()[0].swaps, 1);
+        assert!(matches!(registry.encode("qa", &["hi"]), Err(ModelError::WrongKind { .. })));
+    }
+}
@@ -118,20 +118,4 @@ mod svm_tests {
         let (_model, _cm) = train(ops).unwrap();
 
     }
-
-    #[test]
-    fn intents() {
-        let feature_names = vec![
-            "text",
-            "intent",
-        ];
-        let ops = SvmOps {
-            csv_gz_path: String::from("./intents.csv.gz"),
-            feature_names: feature_names.iter().map(|v| v.to_string()).collect(),
-            target_col_idx: 1,
-        };
-
-        let (_model, _cm) = train(ops).unwrap();
-
-    }
 }
\ No newline at end of file
@@ -0,0 +1,250 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderValue;
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::config::{ApiKeyConfig, GLOBAL_MUTEX};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    SpecRead,
+    SpecWrite,
+    Eval,
+    Chat,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SpecRead => "spec:read",
+            Scope::SpecWrite => "spec:write",
+            Scope::Eval => "eval",
+            Scope::Chat => "chat",
+        }
+    }
+
+    pub fn parse<S: AsRef<str>>(scope: S) -> Option<Self> {
+        match scope.as_ref().trim() {
+            "spec:read" => Some(Scope::SpecRead),
+            "spec:write" => Some(Scope::SpecWrite),
+            "eval" => Some(Scope::Eval),
+            "chat" => Some(Scope::Chat),
+            _ => None,
+        }
+    }
+}
+
+/// Key that was accepted for the current request, stored in the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyIdentity {
+    pub id: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKeyIdentity {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// For requests that need more than the scope of their route
+    pub fn require(&self, scope: Scope) -> Result<(), AuthError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden { key_id: self.id.clone(), scope })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Forbidden { key_id: String, scope: Scope },
+}
+
+impl AuthError {
+    pub fn message(&self) -> String {
+        match self {
+            AuthError::Missing => format!("Missing api key; provide it in the '{API_KEY_HEADER}' header"),
+            AuthError::Invalid => "Invalid api key".to_string(),
+            AuthError::Forbidden { scope, .. } => {
+                format!("Api key is missing the required scope '{}'", scope.as_str())
+            }
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let body = json!({
+            "error": true,
+            "message": self.message(),
+            "response": null,
+        });
+
+        match self {
+            AuthError::Missing | AuthError::Invalid => HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "ApiKey"))
+                .json(body),
+            AuthError::Forbidden { .. } => HttpResponse::Forbidden().json(body),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 digest of a raw api key, this is what gets stored in the config
+pub fn hash_key<S: AsRef<str>>(key: S) -> String {
+    hex::encode(Sha256::digest(key.as_ref().as_bytes()))
+}
+
+/// Looks up the key whose stored hash matches `presented`.
+/// Every configured hash is compared in constant time so the lookup does not leak which prefix matched.
+pub fn authenticate<S: AsRef<str>>(keys: &[ApiKeyConfig], presented: S) -> Option<ApiKeyIdentity> {
+    let presented = hash_key(presented);
+    let mut found = None;
+
+    for key in keys {
+        let stored = key.hash.trim().to_ascii_lowercase();
+        let matched: bool = stored.as_bytes().ct_eq(presented.as_bytes()).into();
+        if matched && found.is_none() {
+            found = Some(ApiKeyIdentity {
+                id: key.id.clone(),
+                scopes: key.scopes.iter().filter_map(Scope::parse).collect(),
+            });
+        }
+    }
+
+    found
+}
+
+pub fn authorize(
+    keys: &[ApiKeyConfig],
+    header: Option<&HeaderValue>,
+    scope: Scope,
+) -> Result<ApiKeyIdentity, AuthError> {
+    let presented = header
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .ok_or(AuthError::Missing)?;
+
+    let identity = authenticate(keys, presented).ok_or(AuthError::Invalid)?;
+    identity.require(scope)?;
+    Ok(identity)
+}
+
+fn header_or_none(req: &ServiceRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+fn audit(req: &ServiceRequest, scope: Scope, key_id: Option<&str>, outcome: &str) {
+    let record = json!({
+        "traceId": header_or_none(req, "x-trace-id"),
+        "spanId": header_or_none(req, "x-span-id"),
+        "apiKeyId": key_id,
+        "scope": scope.as_str(),
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "outcome": outcome,
+    });
+    log::info!(target: "audit", "{record}");
+}
+
+/// Middleware rejecting requests that do not carry an api key granting `scope`
+///
+/// ```rust
+/// #[post("/condition", wrap = "RequireScope::new(Scope::Eval)")]
+/// async fn test_condition(req_body: String) -> impl Responder { /* ... */ }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope {
+    scope: Scope,
+}
+
+impl RequireScope {
+    pub fn new(scope: Scope) -> Self {
+        Self { scope }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware {
+            service,
+            scope: self.scope,
+        }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: S,
+    scope: Scope,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let keys = match global!() {
+            Some(Some(g)) => g.config.api_keys,
+            _ => {
+                log::error!("Failed to get global config for api key check");
+                vec![]
+            }
+        };
+
+        match authorize(&keys, req.headers().get(API_KEY_HEADER), self.scope) {
+            Ok(identity) => {
+                audit(&req, self.scope, Some(&identity.id), "granted");
+                req.extensions_mut().insert(identity);
+
+                let fut = self.service.call(req);
+                Box::pin(async move {
+                    fut.await.map(ServiceResponse::map_into_left_body)
+                })
+            }
+            Err(error) => {
+                let key_id = match &error {
+                    AuthError::Forbidden { key_id, .. } => Some(key_id.as_str()),
+                    _ => None,
+                };
+                audit(&req, self.scope, key_id, "denied");
+
+                let response = req
+                    .into_response(error.to_response())
+                    .map_into_right_body();
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::GlobalCognitoConfig;
+
+/// How long fetched signing keys are trusted before the JWKS is downloaded again
+const JWKS_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Minimum time between two JWKS downloads triggered by an unknown `kid`
+const JWKS_REFRESH_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kid: String,
+    pub kty: String,
+    pub n: String,
+    pub e: String,
+    #[serde(default)]
+    pub alg: Option<String>,
+    #[serde(default, rename = "use")]
+    pub key_use: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+    Id,
+    Access,
+}
+
+/// Claims shared by Cognito ID and access tokens
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CognitoClaims {
+    pub sub: String,
+    pub iss: String,
+    pub exp: u64,
+    pub token_use: TokenUse,
+    /// Present on ID tokens
+    #[serde(default)]
+    pub aud: Option<String>,
+    /// Present on access tokens
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default, alias = "cognito:username")]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default, rename = "cognito:groups")]
+    pub groups: Vec<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl CognitoClaims {
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope
+            .as_deref()
+            .map(|scope| scope.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CognitoError {
+    MissingToken,
+    Malformed(String),
+    UnknownKey(String),
+    Invalid(String),
+    WrongClient,
+    WrongTokenUse(TokenUse),
+    NotConfigured,
+    Jwks(String),
+}
+
+impl fmt::Display for CognitoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CognitoError::MissingToken => write!(f, "Missing bearer token"),
+            CognitoError::Malformed(reason) => write!(f, "Malformed token: {reason}"),
+            CognitoError::UnknownKey(kid) => write!(f, "Token signed with unknown key '{kid}'"),
+            CognitoError::Invalid(reason) => write!(f, "Invalid token: {reason}"),
+            CognitoError::WrongClient => write!(f, "Token was not issued for this client"),
+            CognitoError::WrongTokenUse(token_use) => write!(f, "Token use '{token_use:?}' is not accepted"),
+            CognitoError::NotConfigured => write!(f, "Token validation is not configured"),
+            CognitoError::Jwks(reason) => write!(f, "Failed to get signing keys: {reason}"),
+        }
+    }
+}
+
+impl ResponseError for CognitoError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CognitoError::NotConfigured | CognitoError::Jwks(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header(("WWW-Authenticate", "Bearer error=\"invalid_token\""));
+        }
+
+        response.json(json!({
+            "error": true,
+            "message": self.to_string(),
+            "response": null,
+        }))
+    }
+}
+
+struct CachedKeys {
+    keys: HashMap<String, Jwk>,
+    fetched_at: Option<Instant>,
+}
+
+/// Validates Cognito ID and access tokens against the user pool's JWKS.
+/// Keys are cached for [`JWKS_TTL`] and refreshed early when a token names a `kid` we have not seen yet.
+pub struct CognitoVerifier {
+    issuer: String,
+    client_ids: Vec<String>,
+    jwks_url: Option<String>,
+    cache: RwLock<CachedKeys>,
+}
+
+impl CognitoVerifier {
+    pub fn new<S: Into<String>>(issuer: S, client_ids: Vec<String>, jwks_url: Option<String>) -> Self {
+        Self {
+            issuer: issuer.into(),
+            client_ids,
+            jwks_url,
+            cache: RwLock::new(CachedKeys {
+                keys: HashMap::new(),
+                fetched_at: None,
+            }),
+        }
+    }
+
+    pub fn from_config(config: &GlobalCognitoConfig) -> Self {
+        let issuer = config.issuer();
+        let jwks_url = config
+            .jwks_url
+            .clone()
+            .or_else(|| (!issuer.is_empty()).then(|| format!("{issuer}/.well-known/jwks.json")));
+
+        Self::new(issuer, vec![config.id.clone()], jwks_url)
+    }
+
+    /// Verifier that only ever trusts `jwks`, nothing is downloaded
+    pub fn with_jwks<S: Into<String>>(issuer: S, client_ids: Vec<String>, jwks: JwkSet) -> Self {
+        let verifier = Self::new(issuer, client_ids, None);
+        verifier.store(jwks);
+        verifier
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    fn store(&self, jwks: JwkSet) {
+        if let Ok(mut cache) = self.cache.write() {
+            cache.keys = jwks
+                .keys
+                .into_iter()
+                .map(|key| (key.kid.clone(), key))
+                .collect();
+            cache.fetched_at = Some(Instant::now());
+        }
+    }
+
+    fn cached_key(&self, kid: &str) -> (Option<Jwk>, bool) {
+        match self.cache.read() {
+            Ok(cache) => {
+                let age = cache.fetched_at.map(|fetched_at| fetched_at.elapsed());
+                let stale = age.map(|age| age > JWKS_TTL).unwrap_or(true);
+                let can_refresh = age.map(|age| age > JWKS_REFRESH_COOLDOWN).unwrap_or(true);
+                let key = cache.keys.get(kid).cloned();
+                let needs_fetch = stale || (key.is_none() && can_refresh);
+                (key, needs_fetch)
+            }
+            Err(_) => (None, true),
+        }
+    }
+
+    async fn fetch(&self, url: &str) -> Result<(), CognitoError> {
+        log::info!("Fetching JWKS: url={url:?}");
+        let jwks = reqwest::get(url)
+            .await
+            .map_err(|err| CognitoError::Jwks(err.to_string()))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|err| CognitoError::Jwks(err.to_string()))?;
+
+        self.store(jwks);
+        Ok(())
+    }
+
+    async fn key(&self, kid: &str) -> Result<Jwk, CognitoError> {
+        let (key, needs_fetch) = self.cached_key(kid);
+        if needs_fetch {
+            if let Some(url) = &self.jwks_url {
+                match self.fetch(url).await {
+                    Ok(()) => return self.cached_key(kid).0.ok_or_else(|| CognitoError::UnknownKey(kid.into())),
+                    // keep serving the stale keys rather than locking everyone out
+                    Err(error) if key.is_some() => log::error!("{error}"),
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        key.ok_or_else(|| CognitoError::UnknownKey(kid.into()))
+    }
+
+    pub async fn verify(&self, token: &str, accepted: &[TokenUse]) -> Result<CognitoClaims, CognitoError> {
+        if self.issuer.is_empty() {
+            return Err(CognitoError::NotConfigured);
+        }
+
+        let header = decode_header(token).map_err(|err| CognitoError::Malformed(err.to_string()))?;
+        let kid = header.kid.ok_or_else(|| CognitoError::Malformed("missing kid".into()))?;
+        let key = self.key(&kid).await?;
+
+        self.verify_with_key(token, &key, accepted)
+    }
+
+    pub fn verify_with_key(&self, token: &str, key: &Jwk, accepted: &[TokenUse]) -> Result<CognitoClaims, CognitoError> {
+        let decoding_key = DecodingKey::from_rsa_components(&key.n, &key.e)
+            .map_err(|err| CognitoError::Jwks(err.to_string()))?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[&self.issuer]);
+
+        let claims = decode::<CognitoClaims>(token, &decoding_key, &validation)
+            .map_err(|err| CognitoError::Invalid(format!("{:?}", err.kind())))?
+            .claims;
+
+        if !accepted.contains(&claims.token_use) {
+            return Err(CognitoError::WrongTokenUse(claims.token_use));
+        }
+
+        let client_id = match claims.token_use {
+            TokenUse::Id => claims.aud.as_ref(),
+            TokenUse::Access => claims.client_id.as_ref(),
+        };
+        match client_id {
+            Some(client_id) if self.client_ids.contains(client_id) => Ok(claims),
+            _ => Err(CognitoError::WrongClient),
+        }
+    }
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            let (scheme, token) = value.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+        })
+        .filter(|token| !token.is_empty())
+}
+
+/// Extractor for a verified Cognito user, accepts both ID and access tokens.
+/// Add it as a handler argument to require a signed in user on that route,
+/// or take `Option<CognitoUser>` when signing in is optional.
+///
+/// The verifier must be registered with `App::app_data(web::Data<CognitoVerifier>)`.
+#[derive(Debug, Clone)]
+pub struct CognitoUser(pub CognitoClaims);
+
+impl FromRequest for CognitoUser {
+    type Error = CognitoError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let verifier = req.app_data::<web::Data<CognitoVerifier>>().cloned();
+        let token = bearer_token(req);
+
+        Box::pin(async move {
+            let verifier = verifier.ok_or(CognitoError::NotConfigured)?;
+            let token = token.ok_or(CognitoError::MissingToken)?;
+            verifier
+                .verify(&token, &[TokenUse::Id, TokenUse::Access])
+                .await
+                .map(CognitoUser)
+        })
+    }
+}
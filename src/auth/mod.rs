@@ -0,0 +1,4 @@
+pub mod api_key;
+pub mod cognito;
+pub mod oidc;
+pub mod webhook;
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use oauth2::basic::BasicClient;
+use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, RevocationUrl, TokenUrl};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{OidcEndpoints, OidcProviderConfig, GLOBAL_MUTEX};
+
+pub const DEFAULT_PROVIDER: &str = "cognito";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OidcError {
+    UnknownProvider(String),
+    Discovery(String),
+    InvalidEndpoint(String),
+    RedirectNotAllowed(String),
+    UserInfoUnsupported(String),
+    UserInfo(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::UnknownProvider(name) => write!(f, "Unknown identity provider '{name}'"),
+            OidcError::Discovery(reason) => write!(f, "Failed to discover provider endpoints: {reason}"),
+            OidcError::InvalidEndpoint(reason) => write!(f, "Invalid provider endpoint: {reason}"),
+            OidcError::RedirectNotAllowed(url) => write!(f, "redirect_uri '{url}' is not allowed"),
+            OidcError::UserInfoUnsupported(name) => write!(f, "Provider '{name}' has no userinfo endpoint"),
+            OidcError::UserInfo(reason) => write!(f, "Failed to get user info: {reason}"),
+        }
+    }
+}
+
+/// User information normalized across providers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub username: Option<String>,
+    pub picture: Option<String>,
+    /// Claims exactly as the provider returned them
+    pub claims: Value,
+}
+
+impl Identity {
+    pub fn from_claims<S: Into<String>>(provider: S, claims: Value) -> Option<Self> {
+        let string = |keys: &[&str]| {
+            keys.iter()
+                .filter_map(|key| claims.get(*key))
+                .find_map(|value| match value {
+                    Value::String(v) if !v.is_empty() => Some(v.clone()),
+                    Value::Number(v) => Some(v.to_string()),
+                    _ => None,
+                })
+        };
+
+        let subject = string(&["sub", "id", "user_id"])?;
+        let name = string(&["name"]).or_else(|| {
+            let full = [string(&["given_name"]), string(&["family_name"])]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>()
+                .join(" ");
+            (!full.is_empty()).then_some(full)
+        });
+        let email_verified = claims.get("email_verified").and_then(|value| match value {
+            Value::Bool(v) => Some(*v),
+            // Cognito sends booleans as strings
+            Value::String(v) => v.parse::<bool>().ok(),
+            _ => None,
+        });
+
+        Some(Self {
+            provider: provider.into(),
+            subject,
+            email: string(&["email"]),
+            email_verified,
+            name,
+            username: string(&["preferred_username", "cognito:username", "username", "login", "nickname"]),
+            picture: string(&["picture", "avatar_url"]),
+            claims,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub config: OidcProviderConfig,
+    pub endpoints: OidcEndpoints,
+}
+
+impl OidcProvider {
+    /// Resolves the provider endpoints, fetching the discovery document when none are configured
+    pub async fn discover<S: Into<String>>(name: S, config: OidcProviderConfig) -> Result<Self, OidcError> {
+        let name = name.into();
+        let endpoints = match (&config.endpoints, &config.discovery_url) {
+            (Some(endpoints), _) => endpoints.clone(),
+            (None, Some(url)) => {
+                log::info!("Fetching OIDC discovery document: provider={name:?} url={url:?}");
+                reqwest::get(url)
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|err| OidcError::Discovery(err.to_string()))?
+                    .json::<OidcEndpoints>()
+                    .await
+                    .map_err(|err| OidcError::Discovery(err.to_string()))?
+            }
+            (None, None) => {
+                return Err(OidcError::Discovery(format!("'{name}' has neither endpoints nor a discovery_url")))
+            }
+        };
+
+        Ok(Self { name, config, endpoints })
+    }
+
+    /// Only the configured `redirect_urls` are allowed, a provider without any accepts no redirect
+    pub fn is_allowed_redirect(&self, redirect_uri: &str) -> bool {
+        self.config.redirect_urls.iter().any(|v| v == redirect_uri)
+    }
+
+    pub fn default_scopes(&self) -> Vec<String> {
+        if self.config.scopes.is_empty() {
+            vec!["openid".into(), "email".into(), "profile".into()]
+        } else {
+            self.config.scopes.clone()
+        }
+    }
+
+    pub fn client(&self, redirect_uri: Option<&str>) -> Result<BasicClient, OidcError> {
+        let endpoints = &self.endpoints;
+        let auth_url = AuthUrl::new(endpoints.authorization_endpoint.clone())
+            .map_err(|err| OidcError::InvalidEndpoint(format!("authorization_endpoint: {err}")))?;
+        let token_url = TokenUrl::new(endpoints.token_endpoint.clone())
+            .map_err(|err| OidcError::InvalidEndpoint(format!("token_endpoint: {err}")))?;
+
+        let mut client = BasicClient::new(
+            ClientId::new(self.config.client_id.clone()),
+            self.config.client_secret.clone().map(ClientSecret::new),
+            auth_url,
+            Some(token_url),
+        );
+
+        if let Some(revocation_endpoint) = &endpoints.revocation_endpoint {
+            let revoke_url = RevocationUrl::new(revocation_endpoint.clone())
+                .map_err(|err| OidcError::InvalidEndpoint(format!("revocation_endpoint: {err}")))?;
+            client = client.set_revocation_uri(revoke_url);
+        }
+
+        if let Some(redirect_uri) = redirect_uri {
+            if !self.is_allowed_redirect(redirect_uri) {
+                return Err(OidcError::RedirectNotAllowed(redirect_uri.into()));
+            }
+            let redirect_url = RedirectUrl::new(redirect_uri.to_string())
+                .map_err(|err| OidcError::InvalidEndpoint(format!("redirect_uri: {err}")))?;
+            client = client.set_redirect_uri(redirect_url);
+        }
+
+        Ok(client)
+    }
+
+    pub async fn user_info(&self, access_token: &str) -> Result<Identity, OidcError> {
+        let url = self.endpoints
+            .userinfo_endpoint
+            .as_ref()
+            .ok_or_else(|| OidcError::UserInfoUnsupported(self.name.clone()))?;
+
+        let claims = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(access_token)
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| OidcError::UserInfo(err.to_string()))?
+            .json::<Value>()
+            .await
+            .map_err(|err| OidcError::UserInfo(err.to_string()))?;
+
+        Identity::from_claims(self.name.clone(), claims)
+            .ok_or_else(|| OidcError::UserInfo("response has no subject".into()))
+    }
+}
+
+/// Named providers from the global config, discovery documents are fetched once and reused
+/// until the provider's config changes.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: RwLock<HashMap<String, Arc<OidcProvider>>>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cached(&self, name: &str, config: &OidcProviderConfig) -> Option<Arc<OidcProvider>> {
+        self.providers
+            .read()
+            .ok()?
+            .get(name)
+            .filter(|provider| &provider.config == config)
+            .cloned()
+    }
+
+    pub async fn resolve(&self, name: &str, config: OidcProviderConfig) -> Result<Arc<OidcProvider>, OidcError> {
+        if let Some(provider) = self.cached(name, &config) {
+            return Ok(provider);
+        }
+
+        let provider = Arc::new(OidcProvider::discover(name, config).await?);
+        if let Ok(mut providers) = self.providers.write() {
+            providers.insert(name.to_string(), provider.clone());
+        }
+
+        Ok(provider)
+    }
+
+    /// Looks up `name` (or [`DEFAULT_PROVIDER`]) in the current global config
+    pub async fn get(&self, name: Option<&str>) -> Result<Arc<OidcProvider>, OidcError> {
+        let name = name.unwrap_or(DEFAULT_PROVIDER);
+        let config = match global!() {
+            Some(Some(g)) => g.config.identity_provider(name),
+            _ => None,
+        };
+        let config = config.ok_or_else(|| OidcError::UnknownProvider(name.into()))?;
+
+        self.resolve(name, config).await
+    }
+}
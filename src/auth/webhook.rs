@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+pub const DELIVERY_HEADER: &str = "X-GitHub-Delivery";
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+/// How long delivery ids are remembered for replay detection
+const DELIVERY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_TRACKED_DELIVERIES: usize = 10_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub id: String,
+    pub event: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebhookError {
+    MissingHeader(&'static str),
+    InvalidUserAgent,
+    MalformedSignature,
+    SignatureMismatch,
+    NoSecrets,
+    Replayed(String),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookError::MissingHeader(name) => write!(f, "Missing header '{name}'"),
+            WebhookError::InvalidUserAgent => write!(f, "Unexpected user agent"),
+            WebhookError::MalformedSignature => write!(f, "Malformed signature"),
+            WebhookError::SignatureMismatch => write!(f, "Signature does not match payload"),
+            WebhookError::NoSecrets => write!(f, "No webhook secrets are configured"),
+            WebhookError::Replayed(id) => write!(f, "Delivery '{id}' was already processed"),
+        }
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::MissingHeader(_)
+            | WebhookError::InvalidUserAgent
+            | WebhookError::MalformedSignature => StatusCode::BAD_REQUEST,
+            WebhookError::SignatureMismatch => StatusCode::UNAUTHORIZED,
+            WebhookError::NoSecrets => StatusCode::SERVICE_UNAVAILABLE,
+            WebhookError::Replayed(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": true,
+            "message": self.to_string(),
+        }))
+    }
+}
+
+/// Value GitHub sends in [`SIGNATURE_HEADER`] for `body` signed with `secret`
+pub fn sign<S: AsRef<str>>(secret: S, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_ref().as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("{SIGNATURE_PREFIX}{}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Checks `signature` against every secret so old and new secrets both work while rotating,
+/// returns the index of the secret that matched.
+pub fn verify_signature(secrets: &[String], signature: &str, body: &[u8]) -> Result<usize, WebhookError> {
+    if secrets.is_empty() {
+        return Err(WebhookError::NoSecrets);
+    }
+
+    let expected = signature
+        .trim()
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|digest| hex::decode(digest).ok())
+        .ok_or(WebhookError::MalformedSignature)?;
+
+    let mut matched = None;
+    for (idx, secret) in secrets.iter().enumerate() {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(body);
+        // verify_slice compares in constant time
+        if mac.verify_slice(&expected).is_ok() && matched.is_none() {
+            matched = Some(idx);
+        }
+    }
+
+    matched.ok_or(WebhookError::SignatureMismatch)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, WebhookError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .ok_or(WebhookError::MissingHeader(name))
+}
+
+/// Verifies GitHub style webhook deliveries and remembers their ids to reject replays
+pub struct WebhookVerifier {
+    deliveries: Mutex<HashMap<String, Instant>>,
+    ttl: Duration,
+}
+
+impl Default for WebhookVerifier {
+    fn default() -> Self {
+        Self::new(DELIVERY_TTL)
+    }
+}
+
+impl WebhookVerifier {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            deliveries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Records `id`, returns `false` when it was seen within the ttl
+    fn record(&self, id: &str) -> bool {
+        let mut deliveries = match self.deliveries.lock() {
+            Ok(deliveries) => deliveries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let ttl = self.ttl;
+        deliveries.retain(|_, seen| seen.elapsed() < ttl);
+        if deliveries.contains_key(id) {
+            return false;
+        }
+
+        if deliveries.len() >= MAX_TRACKED_DELIVERIES {
+            let oldest = deliveries
+                .iter()
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                deliveries.remove(&oldest);
+            }
+        }
+
+        deliveries.insert(id.to_string(), Instant::now());
+        true
+    }
+
+    pub fn verify(&self, secrets: &[String], headers: &HeaderMap, body: &[u8]) -> Result<Delivery, WebhookError> {
+        let signature = header(headers, SIGNATURE_HEADER)?;
+        let event = header(headers, EVENT_HEADER)?;
+        let delivery = header(headers, DELIVERY_HEADER)?;
+        let user_agent = header(headers, "User-Agent")?;
+
+        if !user_agent.starts_with("GitHub-Hookshot/") {
+            return Err(WebhookError::InvalidUserAgent);
+        }
+
+        verify_signature(secrets, signature, body)?;
+
+        // only signed deliveries are remembered, otherwise anyone could burn delivery ids
+        if !self.record(delivery) {
+            return Err(WebhookError::Replayed(delivery.to_string()));
+        }
+
+        Ok(Delivery {
+            id: delivery.to_string(),
+            event: event.to_string(),
+        })
+    }
+}
@@ -228,10 +228,16 @@ pub mod server {
 pub mod session {
     use std::time::{Duration, Instant};
 
+    use std::sync::Arc;
+
     use actix::prelude::*;
     use actix_web_actors::ws;
+    use dfs_ml::registry::ModelRegistry;
 
     use super::server;
+    use crate::config::GLOBAL_MUTEX;
+    use crate::openai::isla;
+    use crate::openai::provider::{LlmError, StreamEvent};
 
     /// How often heartbeat pings are sent
     const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -428,4 +434,128 @@ pub mod session {
             }
         }
     }
+
+    /// Websocket session streaming Isla's replies, every text frame is a `ChatbotRequest`.
+    /// A request sent while a reply is still streaming is rejected.
+    pub struct IslaSession {
+        /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
+        /// otherwise we drop connection.
+        pub hb: Instant,
+
+        /// Id of the api key that opened the session
+        pub tenant: Option<String>,
+
+        /// Set from a request until the last event of its reply is sent
+        in_flight: bool,
+
+        /// Holds the model of a local retrieval embedder
+        models: Arc<ModelRegistry>,
+    }
+
+    impl IslaSession {
+        pub fn new(tenant: Option<String>, models: Arc<ModelRegistry>) -> Self {
+            Self { hb: Instant::now(), tenant, in_flight: false, models }
+        }
+
+        fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
+            ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+                if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+                    log::info!("Isla websocket client heartbeat failed, disconnecting");
+                    ctx.stop();
+                    return;
+                }
+
+                ctx.ping(b"");
+            });
+        }
+
+        fn error(ctx: &mut ws::WebsocketContext<Self>, code: &str, message: String) {
+            ctx.text(serde_json::json!({ "type": "error", "code": code, "message": message }).to_string());
+        }
+    }
+
+    impl Actor for IslaSession {
+        type Context = ws::WebsocketContext<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            self.hb(ctx);
+        }
+    }
+
+    /// Events of an Isla completion stream, forwarded to the peer websocket as json
+    impl StreamHandler<Result<StreamEvent, LlmError>> for IslaSession {
+        fn handle(&mut self, event: Result<StreamEvent, LlmError>, ctx: &mut Self::Context) {
+            ctx.text(isla::stream_event_json(&event));
+        }
+
+        fn finished(&mut self, _: &mut Self::Context) {
+            self.in_flight = false;
+        }
+    }
+
+    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for IslaSession {
+        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+            let msg = match msg {
+                Err(_) => {
+                    ctx.stop();
+                    return;
+                }
+                Ok(msg) => msg,
+            };
+
+            match msg {
+                ws::Message::Ping(msg) => {
+                    self.hb = Instant::now();
+                    ctx.pong(&msg);
+                }
+                ws::Message::Pong(_) => {
+                    self.hb = Instant::now();
+                }
+                ws::Message::Text(text) => {
+                    if self.in_flight {
+                        return Self::error(
+                            ctx,
+                            "request_in_flight",
+                            "Wait for the reply to the previous request to finish".into(),
+                        );
+                    }
+                    let req = match serde_json::from_str::<isla::ChatbotRequest>(&text) {
+                        Ok(req) => req,
+                        Err(err) => {
+                            return Self::error(ctx, "invalid_request", format!("Failed to parse incoming request: {err:?}"));
+                        }
+                    };
+
+                    let config = match global!() {
+                        Some(Some(config)) => config,
+                        _ => return Self::error(ctx, "config_unavailable", "Failed to get essential settings".into()),
+                    };
+
+                    self.in_flight = true;
+                    let (tenant, models) = (self.tenant.clone(), self.models.clone());
+                    let completion = async move { isla::get_response_stream(&config, req, tenant.as_deref(), models).await }
+                        .into_actor(self)
+                        .map(|res, act, ctx| match res {
+                            Ok(stream) => {
+                                ctx.add_stream(stream);
+                            }
+                            Err(err) => {
+                                act.in_flight = false;
+                                Self::error(ctx, err.code(), format!("Failed to get response from bot: {err}"))
+                            }
+                        });
+                    ctx.spawn(completion);
+                }
+                ws::Message::Binary(_) => log::debug!("Unexpected binary frame on isla websocket"),
+                ws::Message::Close(reason) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
+                ws::Message::Continuation(_) => {
+                    ctx.stop();
+                }
+                ws::Message::Nop => (),
+            }
+        }
+    }
 }
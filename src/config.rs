@@ -9,6 +9,146 @@ pub struct GlobalCognitoConfig {
     pub secret: String,
     pub auth_url: String,
     pub token_url: String,
+    #[serde(default)]
+    pub region: String,
+    #[serde(default)]
+    pub user_pool_id: String,
+    /// Overrides the JWKS location derived from `region` and `user_pool_id`
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// Defaults to the `/oauth2/revoke` endpoint next to `token_url`
+    #[serde(default)]
+    pub revoke_url: Option<String>,
+    /// Redirect urls `/authorize` accepts, no redirect is accepted when empty
+    #[serde(default)]
+    pub redirect_urls: Vec<String>,
+}
+
+impl GlobalCognitoConfig {
+    /// `iss` claim of tokens minted by the user pool, empty when the pool is not configured
+    pub fn issuer(&self) -> String {
+        if self.region.is_empty() || self.user_pool_id.is_empty() {
+            return "".into();
+        }
+
+        format!("https://cognito-idp.{}.amazonaws.com/{}", self.region, self.user_pool_id)
+    }
+
+    pub fn revoke_url(&self) -> String {
+        self.revoke_url
+            .clone()
+            .unwrap_or_else(|| self.token_url.replace("/oauth2/token", "/oauth2/revoke"))
+    }
+}
+
+/// Endpoints of an OpenID Connect provider, the shape of its discovery document
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize, Default, Hash)]
+pub struct OidcEndpoints {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub revocation_endpoint: Option<String>,
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize, Default, Hash)]
+pub struct OidcProviderConfig {
+    /// e.g. `https://accounts.google.com/.well-known/openid-configuration`
+    #[serde(default)]
+    pub discovery_url: Option<String>,
+    /// Used as is when set, otherwise fetched from `discovery_url`
+    #[serde(default)]
+    pub endpoints: Option<OidcEndpoints>,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Redirect urls `/authorize` accepts, no redirect is accepted when empty
+    #[serde(default)]
+    pub redirect_urls: Vec<String>,
+}
+
+impl From<&GlobalCognitoConfig> for OidcProviderConfig {
+    fn from(cognito: &GlobalCognitoConfig) -> Self {
+        Self {
+            discovery_url: None,
+            endpoints: Some(OidcEndpoints {
+                issuer: cognito.issuer(),
+                authorization_endpoint: cognito.auth_url.clone(),
+                token_endpoint: cognito.token_url.clone(),
+                userinfo_endpoint: Some(cognito.token_url.replace("/oauth2/token", "/oauth2/userInfo")),
+                revocation_endpoint: Some(cognito.revoke_url()),
+                jwks_uri: cognito.jwks_url.clone(),
+            }),
+            client_id: cognito.id.clone(),
+            client_secret: Some(cognito.secret.clone()),
+            scopes: vec!["openid".into(), "email".into(), "profile".into()],
+            redirect_urls: cognito.redirect_urls.clone(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, serde::Deserialize, serde::Serialize, Default, Hash)]
+pub struct ApiKeyConfig {
+    pub id: String,
+    /// Hex encoded SHA-256 digest of the key, the raw key is never stored
+    pub hash: String,
+    pub scopes: Vec<String>,
+}
+
+/// Which LLM backend answers for Isla, secrets default to `openai_secret` where a provider accepts it
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize, Hash)]
+#[serde(tag = "kind")]
+pub enum LlmProviderSettings {
+    #[serde(rename = "openai")]
+    OpenAi {
+        #[serde(default)]
+        base_url: Option<String>,
+        #[serde(default)]
+        api_key: Option<String>,
+    },
+    #[serde(rename = "azure_openai")]
+    AzureOpenAi {
+        endpoint: String,
+        deployment: String,
+        api_version: String,
+        #[serde(default)]
+        api_key: Option<String>,
+    },
+    #[serde(rename = "anthropic")]
+    Anthropic {
+        #[serde(default)]
+        base_url: Option<String>,
+        #[serde(default)]
+        version: Option<String>,
+        #[serde(default)]
+        api_key: Option<String>,
+    },
+    /// Any OpenAI compatible server, e.g. llama.cpp or Ollama
+    #[serde(rename = "local")]
+    Local {
+        base_url: String,
+        #[serde(default)]
+        api_key: Option<String>,
+    },
+    #[serde(rename = "mock")]
+    Mock {
+        reply: String,
+    },
+}
+
+impl Default for LlmProviderSettings {
+    fn default() -> Self {
+        LlmProviderSettings::OpenAi {
+            base_url: None,
+            api_key: None,
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, serde::Deserialize, serde::Serialize, Default)]
@@ -18,7 +158,455 @@ pub struct IslaSettings {
     pub max_tokens: u32,
     pub top_p: f32,
     pub frequency_penalty: f32,
-    pub presence_penalty: f32
+    pub presence_penalty: f32,
+    #[serde(default)]
+    pub provider: LlmProviderSettings,
+    /// Context window of `model` in tokens, known models are looked up when unset
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// Persona used when neither the request nor the tenant picks one, defaults to `isla`
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Api key id to persona name
+    #[serde(default)]
+    pub tenant_personas: HashMap<String, String>,
+    #[serde(default)]
+    pub personas: HashMap<String, PersonaConfig>,
+    /// Directory of `<name>.yaml` or `<name>.json` persona files, checked after `personas`
+    #[serde(default)]
+    pub persona_dir: Option<String>,
+    /// Spec answered from before falling back to the persona, yaml or json
+    #[serde(default)]
+    pub spec_path: Option<String>,
+    #[serde(default)]
+    pub intent_classifier: IntentClassifierSettings,
+    #[serde(default)]
+    pub entities: EntitySettings,
+    #[serde(default)]
+    pub upstream: UpstreamSettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub guardrails: GuardrailSettings,
+    #[serde(default)]
+    pub usage: UsageSettings,
+    #[serde(default)]
+    pub tools: ToolSettings,
+    #[serde(default)]
+    pub retrieval: RetrievalSettings,
+}
+
+/// Passages of a local document corpus added to the prompt, they are returned to clients as citations
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetrievalSettings {
+    pub enabled: bool,
+    /// Markdown or text files, directories are searched for `.md`, `.markdown` and `.txt` files
+    pub documents: Vec<String>,
+    /// Longest chunk in characters, chunks end at paragraphs where they can
+    pub chunk_chars: usize,
+    /// Characters a chunk repeats from the previous one when a paragraph has to be split
+    pub chunk_overlap: usize,
+    /// Passages added to the prompt per user message
+    pub top_k: usize,
+    /// Cosine similarity below which passages are left out
+    pub min_score: f32,
+    pub embedder: EmbedderSettings,
+    pub store: VectorStoreSettings,
+}
+
+impl Default for RetrievalSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            documents: vec![],
+            chunk_chars: 1_000,
+            chunk_overlap: 200,
+            top_k: 3,
+            min_score: 0.3,
+            embedder: EmbedderSettings::default(),
+            store: VectorStoreSettings::default(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize, Hash)]
+#[serde(tag = "kind")]
+pub enum EmbedderSettings {
+    /// Sentence embeddings computed in process by `dfs-ml`
+    #[serde(rename = "local")]
+    Local {
+        /// Name of a `sentence_embeddings` model in `models`
+        #[serde(default = "EmbedderSettings::default_local_model")]
+        model: String,
+    },
+    /// Embeddings api of `isla_settings.provider`
+    #[serde(rename = "provider")]
+    Provider {
+        model: String,
+    },
+}
+
+impl Default for EmbedderSettings {
+    fn default() -> Self {
+        EmbedderSettings::Provider {
+            model: "text-embedding-ada-002".into(),
+        }
+    }
+}
+
+impl EmbedderSettings {
+    fn default_local_model() -> String {
+        "all-MiniLM-L12-v2".into()
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize, Hash)]
+#[serde(tag = "kind")]
+pub enum VectorStoreSettings {
+    /// Vectors are kept in process and embedded again after a restart
+    #[serde(rename = "memory")]
+    Memory,
+    #[serde(rename = "surrealdb")]
+    SurrealDb {
+        /// Websocket address, e.g. `127.0.0.1:8000`
+        addr: String,
+        username: String,
+        password: String,
+        namespace: String,
+        database: String,
+        #[serde(default = "VectorStoreSettings::default_table")]
+        table: String,
+    },
+}
+
+impl Default for VectorStoreSettings {
+    fn default() -> Self {
+        VectorStoreSettings::Memory
+    }
+}
+
+impl VectorStoreSettings {
+    fn default_table() -> String {
+        "isla_chunk".into()
+    }
+}
+
+/// Server side tools the model may call while answering
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ToolSettings {
+    /// Names of the built-in tools to offer: `evaluate_condition`, `current_time` and `site_config`
+    pub enabled: Vec<String>,
+    /// Rounds of tool calls before the model has to answer without tools
+    pub max_iterations: u32,
+    pub timeout_ms: u64,
+    /// Longer tool results are cut off before they go back to the model
+    pub max_result_chars: usize,
+}
+
+impl Default for ToolSettings {
+    fn default() -> Self {
+        Self {
+            enabled: vec![],
+            max_iterations: 4,
+            timeout_ms: 2_000,
+            max_result_chars: 4_000,
+        }
+    }
+}
+
+/// Prices and monthly quotas for metering LLM calls per tenant
+#[derive(PartialEq, Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct UsageSettings {
+    /// Model name or prefix, e.g. `gpt-4` also prices `gpt-4-0613`, the longest match wins
+    pub prices: HashMap<String, ModelPrice>,
+    /// Quota of tenants without their own entry in `quotas`
+    pub default_quota: Quota,
+    /// Api key id to quota
+    pub quotas: HashMap<String, Quota>,
+    pub store: UsageStoreSettings,
+}
+
+/// Where usage totals are kept, read back at startup so quotas hold across restarts
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "kind")]
+pub enum UsageStoreSettings {
+    /// Totals are kept in process and start over when the server restarts
+    #[serde(rename = "memory")]
+    Memory,
+    #[serde(rename = "surrealdb")]
+    SurrealDb {
+        /// Websocket address, e.g. `127.0.0.1:8000`
+        addr: String,
+        username: String,
+        password: String,
+        namespace: String,
+        database: String,
+        #[serde(default = "UsageStoreSettings::default_table")]
+        table: String,
+    },
+}
+
+impl Default for UsageStoreSettings {
+    fn default() -> Self {
+        UsageStoreSettings::Memory
+    }
+}
+
+impl UsageStoreSettings {
+    fn default_table() -> String {
+        "isla_usage".into()
+    }
+}
+
+/// Dollars per 1000 tokens
+#[derive(PartialEq, Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Limits per calendar month (UTC), unset limits are not enforced
+#[derive(PartialEq, Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Quota {
+    pub monthly_tokens: Option<u64>,
+    pub monthly_cost: Option<f64>,
+}
+
+/// Checks run on what users send to Isla and on what the model sends back
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct GuardrailSettings {
+    pub enabled: bool,
+    /// Words or phrases that get a message refused, matched as whole words ignoring case
+    pub blocklist: Vec<String>,
+    /// Regexes that get a message refused
+    pub patterns: Vec<String>,
+    /// Replaces emails, phone numbers and card numbers before they reach the provider or the user
+    pub redact_pii: bool,
+    /// Refuses messages that try to override the persona's instructions
+    pub detect_injection: bool,
+    /// Answer given instead of a refused message or reply
+    pub refusal: String,
+    pub moderation: ModerationSettings,
+}
+
+impl Default for GuardrailSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            blocklist: vec![],
+            patterns: vec![],
+            redact_pii: true,
+            detect_injection: true,
+            refusal: "Nice try. I'm not answering that.".into(),
+            moderation: ModerationSettings::None,
+        }
+    }
+}
+
+/// External moderation asked about both input and output, the secret defaults to `openai_secret`
+#[derive(Eq, PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "kind")]
+pub enum ModerationSettings {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "openai")]
+    OpenAi {
+        #[serde(default)]
+        base_url: Option<String>,
+        #[serde(default)]
+        api_key: Option<String>,
+    },
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        ModerationSettings::None
+    }
+}
+
+/// Caching of completions for identical prompts, off unless `enabled`
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    /// Entries kept in memory, the oldest go first. Redis relies on the ttl alone.
+    pub max_entries: usize,
+    /// Requests with a higher temperature are not cached, their replies are meant to vary
+    pub max_temperature: f32,
+    /// Address of a redis compatible server such as `127.0.0.1:6379`, memory is used when unset
+    pub redis_addr: Option<String>,
+    pub key_prefix: String,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 300,
+            max_entries: 1_000,
+            max_temperature: 0.7,
+            redis_addr: None,
+            key_prefix: "isla:".into(),
+        }
+    }
+}
+
+/// Timeouts, retries and circuit breaking for calls to the LLM provider
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct UpstreamSettings {
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    /// Retries after the first attempt, only for timeouts, connection errors, 429 and 5xx
+    pub max_retries: u32,
+    /// First retry waits this long, every following retry twice as long as the one before
+    pub backoff_ms: u64,
+    /// Longest wait between attempts, a `Retry-After` longer than this is not waited for
+    pub max_backoff_ms: u64,
+    /// Failed calls in a row that open the circuit
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects calls before letting one through to probe the provider
+    pub cooldown_ms: u64,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 5_000,
+            request_timeout_ms: 60_000,
+            max_retries: 2,
+            backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            failure_threshold: 5,
+            cooldown_ms: 30_000,
+        }
+    }
+}
+
+/// Extraction of the entities a spec declares
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct EntitySettings {
+    /// Name of a `ner` model in `models`, `ner` entities of a spec are never found without one
+    pub ner_model: Option<String>,
+    /// NER entities scored lower are dropped
+    pub min_score: f64,
+}
+
+impl Default for EntitySettings {
+    fn default() -> Self {
+        Self {
+            ner_model: None,
+            min_score: 0.5,
+        }
+    }
+}
+
+/// How a message is matched to one of `Spec.intents`
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "kind")]
+pub enum IntentClassifierSettings {
+    /// Intent names appearing as whole words in the message
+    #[serde(rename = "keyword")]
+    Keyword,
+    /// Asks the configured LLM provider to choose, answers below `min_confidence` count as no intent
+    #[serde(rename = "llm")]
+    Llm {
+        #[serde(default = "IntentClassifierSettings::default_min_confidence")]
+        min_confidence: f32,
+    },
+    /// Model trained by `dfs-ml` on labelled utterances. It is read from `model` when that is newer than
+    /// `examples`, otherwise trained on `examples` (a `.csv` or json lines file) and saved to `model`.
+    /// Without `examples` a spec with training data is trained on in memory.
+    #[serde(rename = "trained")]
+    Trained {
+        model: String,
+        #[serde(default)]
+        examples: Option<String>,
+        #[serde(default = "IntentClassifierSettings::default_min_confidence")]
+        min_confidence: f32,
+        /// Models kept in memory, the least recently used goes first
+        #[serde(default = "IntentClassifierSettings::default_max_models")]
+        max_models: usize,
+        /// Most examples and negatives a spec may have to be trained on
+        #[serde(default = "IntentClassifierSettings::default_max_examples")]
+        max_examples: usize,
+    },
+}
+
+impl IntentClassifierSettings {
+    fn default_min_confidence() -> f32 {
+        0.5
+    }
+
+    fn default_max_models() -> usize {
+        16
+    }
+
+    fn default_max_examples() -> usize {
+        5_000
+    }
+}
+
+impl Default for IntentClassifierSettings {
+    fn default() -> Self {
+        IntentClassifierSettings::Keyword
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PersonaExample {
+    pub user: String,
+    pub assistant: String,
+}
+
+/// Prompt data for a persona, `{{name}}` placeholders are filled in when the prompt is rendered
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PersonaConfig {
+    pub system_prompt: String,
+    #[serde(default)]
+    pub examples: Vec<PersonaExample>,
+    #[serde(default = "PersonaConfig::default_user_label")]
+    pub user_label: String,
+    #[serde(default = "PersonaConfig::default_assistant_label")]
+    pub assistant_label: String,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Tags the model may emit, e.g. `N4V2RE$UME` for `[N4V2RE$UME]`, returned to clients as actions
+    #[serde(default)]
+    pub actions: HashMap<String, ActionConfig>,
+    /// Placeholder text such as `current time` for `[current time]` to the variable it is filled with
+    #[serde(default)]
+    pub placeholders: HashMap<String, String>,
+    /// Answer given while the provider is unavailable, callers get the upstream error when unset
+    #[serde(default)]
+    pub unavailable_reply: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ActionConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+impl PersonaConfig {
+    fn default_user_label() -> String {
+        "You".into()
+    }
+
+    fn default_assistant_label() -> String {
+        "Isla".into()
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, serde::Deserialize, serde::Serialize, Default)]
@@ -41,10 +629,85 @@ pub struct GlobalConfig {
     reload_events: Vec<String>,
     github_secret: String,
     concord_secret: String,
+    /// Additional webhook secrets, lets a new secret be rolled out before the old one is removed
+    #[serde(default)]
+    webhook_secrets: Vec<String>,
     error: bool,
     pub(crate) cognito: GlobalCognitoConfig,
     pub(crate) openai_secret: String,
     pub(crate) isla_settings: IslaSettings,
+    #[serde(default)]
+    pub(crate) api_keys: Vec<ApiKeyConfig>,
+    /// Named OpenID Connect providers, `cognito` is always available from the settings above
+    #[serde(default)]
+    pub(crate) identity_providers: HashMap<String, OidcProviderConfig>,
+    /// Read at startup only, changing it takes a restart
+    #[serde(default)]
+    pub(crate) qa: QaSettings,
+    /// Local models served by `dfs-ml`. New ones are picked up at startup, a changed one on `/models/{name}/reload`.
+    #[serde(default)]
+    pub(crate) models: Vec<dfs_ml::registry::ModelSpec>,
+    #[serde(default)]
+    pub(crate) discovery: DiscoverySettings,
+}
+
+/// Clustering of utterances no intent matched, served on `/intents/discover`
+#[derive(PartialEq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    /// Unmatched utterances kept in memory, the oldest are dropped first
+    pub max_unmatched: usize,
+    /// Most utterances clustered per request
+    pub max_utterances: usize,
+    /// Name of a `sentence_embeddings` model in `models`, utterances are featurized by TF-IDF when unset
+    pub embedding_model: Option<String>,
+    pub clustering: dfs_ml::cluster::ClusterParams,
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            max_unmatched: 1_000,
+            max_utterances: 5_000,
+            embedding_model: None,
+            clustering: dfs_ml::cluster::ClusterParams::default(),
+        }
+    }
+}
+
+/// Extractive question answering served on `/qa`
+#[derive(PartialEq, Eq, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct QaSettings {
+    pub enabled: bool,
+    /// Name of a `question_answering` model in `models`
+    pub model: String,
+    /// Threads taking batches off the queue. They share the model of the registry, which answers one batch at a time.
+    pub workers: usize,
+    /// Batches waiting for a worker, more are turned away as busy
+    pub queue_size: usize,
+    /// Most questions in a batch
+    pub max_batch: usize,
+    /// Answers per question
+    pub top_k: usize,
+    /// Longest answer in tokens
+    pub max_answer_length: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for QaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: "distilbert-qa".into(),
+            workers: 1,
+            queue_size: 16,
+            max_batch: 32,
+            top_k: 3,
+            max_answer_length: 64,
+            timeout_ms: 30_000,
+        }
+    }
 }
 
 impl GlobalConfig {
@@ -97,20 +760,39 @@ impl GlobalConfig {
             reload_events: vec![],
             github_secret: "invalid".into(),
             concord_secret: "invalid".into(),
+            webhook_secrets: vec![],
             motd: "[FAIL] How do you do?".into(),
             error: true,
             cognito: Default::default(),
             openai_secret: "<secret>".into(),
             isla_settings: Default::default(),
             dustindiaz_io: Default::default(),
+            api_keys: vec![],
+            identity_providers: Default::default(),
+            qa: Default::default(),
+            models: vec![],
+            discovery: Default::default(),
         }
     }
 
-    pub fn cmp_webhook_secret<S: AsRef<str>>(&self, other: S) -> bool {
-        let mut map = std::collections::HashSet::with_capacity(5);
-        map.insert(self.github_secret.clone());
-        map.insert(self.concord_secret.clone());
-        map.contains(other.as_ref())
+    /// Secrets webhook payloads may be signed with, empty when running on the fallback config
+    pub fn webhook_secrets(&self) -> Vec<String> {
+        if self.error {
+            return vec![];
+        }
+
+        let mut secrets = vec![self.github_secret.clone(), self.concord_secret.clone()];
+        secrets.extend(self.webhook_secrets.iter().cloned());
+        secrets.retain(|secret| !secret.is_empty());
+        secrets.dedup();
+        secrets
+    }
+
+    pub fn identity_provider<S: AsRef<str>>(&self, name: S) -> Option<OidcProviderConfig> {
+        match name.as_ref() {
+            "cognito" if !self.identity_providers.contains_key("cognito") => Some((&self.cognito).into()),
+            name => self.identity_providers.get(name).cloned(),
+        }
     }
 
     pub fn is_expected_reload_event<S: AsRef<str>>(&self, ev: S) -> bool {
@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use dfs_ml::registry::ModelRegistry;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::core::spec::Spec;
+use crate::entities::{self, Entity};
+use crate::intent::{self, Classification, IntentClassifier};
+use crate::openai::actions::{self, Action};
+use crate::openai::isla::{self, ChatbotRequest, Grounding, HistoryEntry};
+use crate::openai::persona::Persona;
+use crate::openai::provider::{LlmError, LlmProvider, Usage};
+use crate::openai::retrieval::Citation;
+use crate::openai::tools::{self, ToolTrace};
+use crate::openai::usage;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConversationRequest {
+    /// Spec to answer from, `isla_settings.spec_path` is used when missing. Needs the `spec:write` scope.
+    #[serde(default)]
+    pub spec: Option<Spec>,
+    #[serde(flatten)]
+    pub chat: ChatbotRequest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Dialog,
+    Llm,
+}
+
+/// How the reply was produced
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub source: Source,
+    pub intent: Option<String>,
+    /// Classifier confidence in `intent`, missing when no classification was made
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    #[serde(default)]
+    pub rationale: Option<String>,
+    /// Index of the dialog case that answered
+    pub case: Option<usize>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub text: String,
+    pub decision: Decision,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    #[serde(default)]
+    pub dropped_turns: usize,
+    /// See [`isla::ChatbotResponse::degraded`]
+    #[serde(default)]
+    pub degraded: bool,
+    /// Missing for dialog answers, they are free
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub cost: f64,
+    /// See [`isla::ChatbotResponse::tool_calls`]
+    #[serde(default)]
+    pub tool_calls: Vec<ToolTrace>,
+    /// See [`isla::ChatbotResponse::citations`]
+    #[serde(default)]
+    pub citations: Vec<Citation>,
+    /// Entities the spec declares found in the last user message
+    #[serde(default)]
+    pub entities: Vec<Entity>,
+}
+
+/// Reads a yaml or json spec, json is valid yaml so both go through the yaml parser
+pub fn load_spec(path: &str) -> Result<Spec, LlmError> {
+    Spec::load(path).map_err(LlmError::Config)
+}
+
+/// Answers from the spec dialogs when `classifier` finds an intent and one of its cases matches,
+/// otherwise asks the persona through `provider`. Case conditions read `entities` as `ctx.entities`.
+#[allow(clippy::too_many_arguments)]
+pub async fn respond(
+    provider: &dyn LlmProvider,
+    classifier: &dyn IntentClassifier,
+    settings: &config::IslaSettings,
+    persona: &Persona,
+    spec: Option<&Spec>,
+    entities: &[Entity],
+    hist: Vec<HistoryEntry>,
+    grounding: &Grounding,
+) -> Result<Reply, LlmError> {
+    let message = isla::last_user_message(persona, &hist);
+
+    let mut decision = Decision {
+        source: Source::Llm,
+        intent: None,
+        confidence: None,
+        rationale: None,
+        case: None,
+        reason: String::new(),
+    };
+
+    match (spec, message) {
+        (None, _) => decision.reason = "no spec configured".into(),
+        (_, None) => decision.reason = "no user message".into(),
+        (Some(spec), Some(message)) => {
+            let classification = match classifier.classify(&message, &spec.intents).await {
+                Ok(classification) => classification,
+                Err(err) => {
+                    // without an intent the persona can still answer
+                    log::warn!("Intent classifier {} failed: {err}", classifier.name());
+                    Classification::none(format!("intent classification failed: {}", err.code()))
+                }
+            };
+            decision.confidence = Some(classification.confidence);
+            decision.rationale = Some(classification.rationale);
+
+            match classification.intent {
+                None => decision.reason = "no intent matched".into(),
+                Some(intent) => {
+                    match spec.match_case_with(&intent, &entities::values(entities)) {
+                        Some((idx, case)) => {
+                            let processed = actions::process(persona, &case.reply);
+                            decision.source = Source::Dialog;
+                            decision.case = Some(idx);
+                            decision.reason = format!("case {idx} of '{intent}' matched");
+                            decision.intent = Some(intent);
+
+                            return Ok(Reply {
+                                text: processed.text,
+                                decision,
+                                actions: processed.actions,
+                                dropped_turns: 0,
+                                degraded: false,
+                                usage: None,
+                                cost: 0.0,
+                                tool_calls: vec![],
+                                citations: vec![],
+                                entities: entities.to_vec(),
+                            });
+                        }
+                        None if spec.dialogs.contains_key(&intent) => {
+                            decision.reason = format!("no case of '{intent}' matched");
+                        }
+                        None => decision.reason = format!("intent '{intent}' has no dialog"),
+                    }
+                    decision.intent = Some(intent);
+                }
+            }
+        }
+    }
+
+    let response = isla::respond_grounded(provider, settings, persona, hist, grounding).await?;
+    let text = response.choices
+        .as_ref()
+        .and_then(|choices| choices.first())
+        .map(|choice| choice.text.clone())
+        .unwrap_or_default();
+
+    Ok(Reply {
+        text,
+        decision,
+        actions: response.actions,
+        dropped_turns: response.dropped_turns,
+        degraded: response.degraded,
+        usage: response.usage,
+        cost: response.cost,
+        tool_calls: response.tool_calls,
+        citations: response.citations,
+        entities: entities.to_vec(),
+    })
+}
+
+/// `tenant` is the id of the api key that made the request, see [`isla::get_response`].
+/// `models` holds the NER model of `isla_settings.entities` and the model of a local retrieval embedder.
+pub async fn get_response(
+    config: &config::Global,
+    request: ConversationRequest,
+    tenant: Option<&str>,
+    models: Arc<ModelRegistry>,
+) -> Result<Reply, LlmError> {
+    let settings = &config.config.isla_settings;
+    let spec = match (request.spec, &settings.spec_path) {
+        (Some(spec), _) => {
+            spec.validate().map_err(|err| LlmError::Config(format!("Invalid spec: {err}")))?;
+            Some(spec)
+        }
+        (None, Some(path)) => Some(load_spec(path)?),
+        (None, None) => None,
+    };
+
+    let persona = isla::request_persona(config, &request.chat, tenant)?;
+    let estimate = isla::estimate(settings, &persona, &request.chat.hist);
+    let reservation = usage::reserve(&settings.usage, tenant, &settings.model, &estimate)?;
+    let provider = isla::request_provider(config, &request.chat)?;
+    let classifier = intent::from_config(&config.config, spec.as_ref(), tenant).await?;
+    let grounding = Grounding {
+        toolbox: tools::from_config(&config.config, spec.as_ref()),
+        passages: isla::request_passages(config, models.clone(), &persona, &request.chat.hist).await,
+    };
+    let message = isla::last_user_message(&persona, &request.chat.hist);
+    let entities = match (&spec, &message) {
+        (Some(spec), Some(message)) => entities::extract(&settings.entities, models, spec, message).await?,
+        _ => vec![],
+    };
+    let mut reply = respond(
+        provider.as_ref(),
+        classifier.as_ref(),
+        settings,
+        &persona,
+        spec.as_ref(),
+        &entities,
+        request.chat.hist,
+        &grounding,
+    ).await?;
+    if let (Some(message), Some(_), None) = (message, reply.decision.confidence, &reply.decision.intent) {
+        // classified without finding an intent, a hint that the spec lacks one
+        intent::record_unmatched(&message, config.config.discovery.max_unmatched);
+    }
+    if reply.decision.source == Source::Llm {
+        reply.cost = reservation.record(reply.usage.as_ref()).cost;
+    }
+    Ok(reply)
+}
@@ -6,8 +6,10 @@ mod utils;
 pub mod spec {
     use std::collections::{BTreeMap, HashMap};
 
+    use regex::Regex;
     use resolver;
     use serde::{Deserialize, Serialize};
+    use serde_json::Value;
     use eval_utility::eval_wrapper::{ExprWrapper, EvalConfig};
 
     pub mod web {
@@ -44,12 +46,100 @@ pub mod spec {
         pub cases: Vec<Case>,
     }
 
+    /// Span of an example naming an entity, in characters of its text
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct EntityAnnotation {
+        pub entity: String,
+        pub start: usize,
+        pub end: usize,
+        /// What the span normalizes to, such as `visa` for "Visa card", the span's text when missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub value: Option<String>,
+    }
+
+    /// Untagged form of [`TrainingExample`], so an example without entities can be a plain string
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum TrainingExampleDef {
+        Text(String),
+        Annotated {
+            text: String,
+            #[serde(default)]
+            entities: Vec<EntityAnnotation>,
+        },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(from = "TrainingExampleDef", into = "TrainingExampleDef")]
+    pub struct TrainingExample {
+        pub text: String,
+        pub entities: Vec<EntityAnnotation>,
+    }
+
+    impl From<TrainingExampleDef> for TrainingExample {
+        fn from(def: TrainingExampleDef) -> Self {
+            match def {
+                TrainingExampleDef::Text(text) => TrainingExample { text, entities: vec![] },
+                TrainingExampleDef::Annotated { text, entities } => TrainingExample { text, entities },
+            }
+        }
+    }
+
+    impl From<TrainingExample> for TrainingExampleDef {
+        fn from(example: TrainingExample) -> Self {
+            if example.entities.is_empty() {
+                TrainingExampleDef::Text(example.text)
+            } else {
+                TrainingExampleDef::Annotated { text: example.text, entities: example.entities }
+            }
+        }
+    }
+
+    /// What classifiers learn an intent from. Zero-shot and LLM classifiers read the description,
+    /// supervised ones train on the examples and negatives.
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct IntentTraining {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub description: Option<String>,
+        #[serde(default)]
+        pub examples: Vec<TrainingExample>,
+        /// Utterances that look like the intent but are not it
+        #[serde(default)]
+        pub negatives: Vec<String>,
+    }
+
+    /// How the values of an entity are found in a message. Conditions read the first one found as
+    /// `ctx.entities.<name>`, typed as described per kind.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    pub enum EntityDef {
+        /// Email addresses, lower cased
+        Email,
+        /// Dates such as `2024-03-05`, `3/5/2024`, `March 5`, `5th of March 2024` or `tomorrow`, as `YYYY-MM-DD`
+        Date,
+        /// Sums of money such as `$1,250.50` or `30 EUR`, as a number without the currency
+        Amount,
+        Number,
+        /// Text matching `pattern`, or its first group when it has one
+        Regex { pattern: String },
+        /// Whole words naming one of the keys of `values` or one of its synonyms in any case, as the key
+        Dictionary { values: BTreeMap<String, Vec<String>> },
+        /// Entities the configured NER model labels `label`, such as `PER`, `ORG` or `LOC`
+        Ner { label: String },
+    }
+
     #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
     pub struct Spec {
         pub intents: Vec<String>,
         pub context: HashMap<String, String>,
         pub system: HashMap<String, String>,
         pub dialogs: BTreeMap<String, Dialog>,
+        /// Training data by intent, every intent is optional
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub training: BTreeMap<String, IntentTraining>,
+        /// Entities extracted from messages by name, which has to be usable in `ctx.entities.<name>`
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub entities: BTreeMap<String, EntityDef>,
     }
 
     impl Case {
@@ -93,9 +183,126 @@ pub mod spec {
                 dialogs: dialogs_map,
                 context,
                 system,
+                training: BTreeMap::new(),
+                entities: BTreeMap::new(),
             }
         }
 
+        pub fn with_training<S: Into<String>>(mut self, intent: S, training: IntentTraining) -> Self {
+            self.training.insert(intent.into(), training);
+            self
+        }
+
+        pub fn with_entity<S: Into<String>>(mut self, name: S, entity: EntityDef) -> Self {
+            self.entities.insert(name.into(), entity);
+            self
+        }
+
+        /// Every problem of a deserialized spec, which unlike [`Spec::new`] does not check anything.
+        /// Dialogs and training data have to be of declared intents, examples have to be unambiguous,
+        /// entity spans have to lie within their example and entity patterns have to compile.
+        pub fn validate(&self) -> Result<(), String> {
+            let mut problems = vec![];
+            for (key, dialog) in &self.dialogs {
+                if !self.intents.contains(&dialog.intent) {
+                    problems.push(format!("dialog of '{}' is of an undeclared intent", dialog.intent));
+                } else if key != &dialog.intent {
+                    problems.push(format!("dialog '{key}' is of intent '{}'", dialog.intent));
+                }
+            }
+
+            let mut seen = HashMap::<String, &str>::new();
+            for (intent, training) in &self.training {
+                if !self.intents.contains(intent) {
+                    problems.push(format!("training data of '{intent}' is of an undeclared intent"));
+                }
+                for (idx, example) in training.examples.iter().enumerate() {
+                    let text = example.text.trim();
+                    if text.is_empty() {
+                        problems.push(format!("example {idx} of '{intent}' is blank"));
+                        continue;
+                    }
+                    match seen.insert(text.to_lowercase(), intent) {
+                        Some(other) if other != intent => {
+                            problems.push(format!("example \"{text}\" is of both '{other}' and '{intent}'"))
+                        }
+                        Some(_) => problems.push(format!("example \"{text}\" of '{intent}' is repeated")),
+                        None => {}
+                    }
+
+                    let length = example.text.chars().count();
+                    for annotation in &example.entities {
+                        if annotation.entity.trim().is_empty() {
+                            problems.push(format!("an entity of example \"{text}\" has no name"));
+                        } else if annotation.start >= annotation.end || annotation.end > length {
+                            problems.push(format!(
+                                "entity '{}' of example \"{text}\" spans {}..{} of {length} characters",
+                                annotation.entity, annotation.start, annotation.end
+                            ));
+                        }
+                    }
+                }
+                for negative in &training.negatives {
+                    if training.examples.iter().any(|example| example.text.trim().eq_ignore_ascii_case(negative.trim())) {
+                        problems.push(format!("\"{}\" is both an example and a negative of '{intent}'", negative.trim()));
+                    }
+                }
+            }
+
+            for (name, entity) in &self.entities {
+                let mut chars = name.chars();
+                let identifier = chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !identifier {
+                    problems.push(format!("entity '{name}' needs a name of letters, digits and underscores"));
+                }
+                match entity {
+                    EntityDef::Regex { pattern } => {
+                        if let Err(err) = Regex::new(pattern) {
+                            problems.push(format!("pattern of entity '{name}' does not compile: {err}"));
+                        }
+                    }
+                    EntityDef::Dictionary { values } if values.is_empty() => {
+                        problems.push(format!("dictionary of entity '{name}' has no values"))
+                    }
+                    EntityDef::Ner { label } if label.trim().is_empty() => {
+                        problems.push(format!("entity '{name}' needs a NER label"))
+                    }
+                    _ => {}
+                }
+            }
+
+            if problems.is_empty() {
+                Ok(())
+            } else {
+                Err(problems.join("; "))
+            }
+        }
+
+        /// Description of `intent` for classifiers that read one, the intent's name without one
+        pub fn describe<'a>(&'a self, intent: &'a str) -> &'a str {
+            self.training
+                .get(intent)
+                .and_then(|training| training.description.as_deref())
+                .unwrap_or(intent)
+        }
+
+        /// `(utterance, intent)` pairs of every example, in intent order
+        pub fn examples(&self) -> Vec<(&str, &str)> {
+            self.training
+                .iter()
+                .flat_map(|(intent, training)| training.examples.iter().map(move |example| (example.text.as_str(), intent.as_str())))
+                .collect()
+        }
+
+        /// Negatives of every intent
+        pub fn negatives(&self) -> Vec<&str> {
+            self.training
+                .values()
+                .flat_map(|training| training.negatives.iter().map(String::as_str))
+                .collect()
+        }
+
         pub fn default() -> Self {
             let intents = vec![
                 "billing".to_owned(),
@@ -117,8 +324,23 @@ pub mod spec {
         }
 
         pub fn expr(&self, expression: String) -> ExprWrapper {
+            self.expr_over(expression, &self.context)
+        }
+
+        /// Expression over `ctx` with the entities extracted from a message as `ctx.entities`,
+        /// which hides a context value named `entities`
+        pub fn expr_with(&self, expression: String, entities: &BTreeMap<String, Value>) -> ExprWrapper {
+            let mut ctx = self.context
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                .collect::<serde_json::Map<String, Value>>();
+            ctx.insert("entities".into(), Value::Object(entities.clone().into_iter().collect()));
+            self.expr_over(expression, &ctx)
+        }
+
+        fn expr_over<T: Serialize>(&self, expression: String, ctx: &T) -> ExprWrapper {
             ExprWrapper::new(expression)
-                .value("ctx", &self.context)
+                .value("ctx", ctx)
                 .value("sys", &self.system)
                 .config(EvalConfig {
                     include_maths: true,
@@ -131,7 +353,16 @@ pub mod spec {
 
         pub fn eval<S: AsRef<str>>(&self, expression: S) -> Result<resolver::Value, String> {
             let str_like = expression.as_ref().to_owned();
-            let result = self.expr(str_like).exec();
+            Self::exec(expression, self.expr(str_like))
+        }
+
+        pub fn eval_with<S: AsRef<str>>(&self, expression: S, entities: &BTreeMap<String, Value>) -> Result<resolver::Value, String> {
+            let str_like = expression.as_ref().to_owned();
+            Self::exec(expression, self.expr_with(str_like, entities))
+        }
+
+        fn exec<S: AsRef<str>>(expression: S, wrapper: ExprWrapper) -> Result<resolver::Value, String> {
+            let result = wrapper.exec();
             match result {
                 Ok(result) => Ok(result),
                 Err(error) => {
@@ -159,6 +390,33 @@ pub mod spec {
             }
         }
 
+        /// First case of the intent's dialog whose condition evaluates to `true`, with its index.
+        /// Conditions that fail to evaluate are skipped.
+        pub fn match_case<S: AsRef<str>>(&self, intent: S) -> Option<(usize, &Case)> {
+            self.find_case(intent.as_ref(), |condition| self.eval(condition))
+        }
+
+        /// [`Spec::match_case`] with the entities of the message in `ctx.entities`
+        pub fn match_case_with<S: AsRef<str>>(&self, intent: S, entities: &BTreeMap<String, Value>) -> Option<(usize, &Case)> {
+            self.find_case(intent.as_ref(), |condition| self.eval_with(condition, entities))
+        }
+
+        fn find_case<F>(&self, intent: &str, eval: F) -> Option<(usize, &Case)>
+        where
+            F: Fn(&str) -> Result<resolver::Value, String>,
+        {
+            let dialog = self.dialogs.get(intent)?;
+            dialog.cases.iter().enumerate().find(|(_, case)| {
+                match eval(&case.condition) {
+                    Ok(value) => value == true,
+                    Err(message) => {
+                        log::warn!("Skipping case of '{}': {message}", dialog.intent);
+                        false
+                    }
+                }
+            })
+        }
+
         pub fn from_yaml(content: &str) -> Self {
             serde_yaml::from_str(content).unwrap()
         }
@@ -167,6 +425,16 @@ pub mod spec {
             serde_json::from_str(content).unwrap()
         }
 
+        /// Spec in the yaml file at `path`, json being valid yaml too
+        pub fn load(path: &str) -> Result<Self, String> {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| format!("Failed to read spec '{path}': {err}"))?;
+            let spec = serde_yaml::from_str::<Self>(&content)
+                .map_err(|err| format!("Invalid spec '{path}': {err}"))?;
+            spec.validate().map_err(|err| format!("Invalid spec '{path}': {err}"))?;
+            Ok(spec)
+        }
+
         pub fn to_yaml(&self) -> String {
             serde_yaml::to_string(self).unwrap()
         }
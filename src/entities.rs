@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono::{Datelike, Duration, NaiveDate};
+use chrono_tz::Tz;
+use dfs_ml::registry::ModelRegistry;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::EntitySettings;
+use crate::core::spec::{EntityDef, Spec};
+use crate::openai::provider::LlmError;
+
+const MONTHS: &str = r"(jan(?:uary)?|feb(?:ruary)?|mar(?:ch)?|apr(?:il)?|may|june?|july?|aug(?:ust)?|sep(?:t(?:ember)?)?|oct(?:ober)?|nov(?:ember)?|dec(?:ember)?)\.?";
+
+lazy_static! {
+    static ref EMAIL: Regex = Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b").unwrap();
+    static ref ISO_DATE: Regex = Regex::new(r"\b(\d{4})-(\d{1,2})-(\d{1,2})\b").unwrap();
+    /// Month first, as written in the US
+    static ref SLASH_DATE: Regex = Regex::new(r"\b(\d{1,2})/(\d{1,2})/(\d{4})\b").unwrap();
+    static ref MONTH_DAY: Regex = Regex::new(&format!(r"(?i)\b{MONTHS}\s+(\d{{1,2}})(?:st|nd|rd|th)?\b(?:,?\s+(\d{{4}})\b)?")).unwrap();
+    static ref DAY_MONTH: Regex = Regex::new(&format!(r"(?i)\b(\d{{1,2}})(?:st|nd|rd|th)?\s+(?:of\s+)?{MONTHS}(?:,?\s+(\d{{4}})\b)?")).unwrap();
+    static ref RELATIVE_DATE: Regex = Regex::new(r"(?i)\b(yesterday|today|tomorrow)\b").unwrap();
+    static ref AMOUNT: Regex = Regex::new(
+        r"(?i)[$€£]\s?(\d{1,3}(?:,\d{3})+(?:\.\d+)?|\d+(?:\.\d+)?)|\b(\d{1,3}(?:,\d{3})+(?:\.\d+)?|\d+(?:\.\d+)?)\s?(?:usd|eur|gbp|dollars?|euros?|pounds?)\b"
+    ).unwrap();
+    static ref NUMBER: Regex = Regex::new(r"-?\b\d+(?:\.\d+)?\b").unwrap();
+}
+
+/// Value of an entity found in a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entity {
+    /// Name the spec declares the entity under
+    pub entity: String,
+    /// Typed as [`EntityDef`] describes, `ctx.entities.<entity>` in conditions
+    pub value: Value,
+    pub text: String,
+    /// Character span of `text` in the message
+    pub start: usize,
+    pub end: usize,
+    /// Confidence of the NER model, rules always score 1
+    pub score: f64,
+}
+
+/// Entity a NER model labelled, with its character span
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recognized {
+    pub label: String,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    pub score: f64,
+}
+
+/// Named entity recognition, a model of the registry in production. Blocks while the model runs.
+pub trait Recognizer: Send + Sync {
+    fn recognize(&self, text: &str) -> Result<Vec<Recognized>, String>;
+}
+
+/// NER model of a `ModelRegistry`, looked up per message so a swapped model takes over straight away
+pub struct RegisteredRecognizer {
+    registry: Arc<ModelRegistry>,
+    name: String,
+}
+
+impl RegisteredRecognizer {
+    pub fn new(registry: Arc<ModelRegistry>, name: &str) -> Self {
+        Self {
+            registry,
+            name: name.into(),
+        }
+    }
+}
+
+impl Recognizer for RegisteredRecognizer {
+    fn recognize(&self, text: &str) -> Result<Vec<Recognized>, String> {
+        let entities = self.registry.entities(&self.name, &[text]).map_err(|err| err.to_string())?;
+        Ok(entities
+            .into_iter()
+            .flatten()
+            .map(|entity| Recognized {
+                label: entity.label,
+                text: entity.word,
+                start: entity.offset.begin as usize,
+                end: entity.offset.end as usize,
+                score: entity.score,
+            })
+            .collect())
+    }
+}
+
+enum Matcher {
+    Email,
+    Date,
+    Amount,
+    Number,
+    Regex(Regex),
+    /// Key of the dictionary and the pattern of its names
+    Dictionary(Vec<(String, Regex)>),
+    Ner(String),
+}
+
+/// Value found by a rule, with its span in bytes
+struct Found {
+    start: usize,
+    end: usize,
+    value: Value,
+}
+
+impl Found {
+    fn of(captures: &Captures, value: Option<Value>) -> Option<Self> {
+        let whole = captures.get(0)?;
+        Some(Found { start: whole.start(), end: whole.end(), value: value? })
+    }
+}
+
+fn month(name: &str) -> Option<u32> {
+    let name = name.to_lowercase();
+    ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"]
+        .iter()
+        .position(|month| name.starts_with(month))
+        .map(|idx| idx as u32 + 1)
+}
+
+fn date(year: Option<i32>, month: Option<u32>, day: Option<u32>) -> Option<Value> {
+    let date = NaiveDate::from_ymd_opt(year?, month?, day?)?;
+    Some(Value::String(date.format("%Y-%m-%d").to_string()))
+}
+
+fn dates(text: &str, today: NaiveDate) -> Vec<Found> {
+    let number = |captures: &Captures, idx: usize| captures.get(idx).and_then(|group| group.as_str().parse::<u32>().ok());
+    let year = |captures: &Captures, idx: usize| {
+        captures.get(idx).map_or(Some(today.year()), |group| group.as_str().parse::<i32>().ok())
+    };
+
+    let mut found = vec![];
+    found.extend(ISO_DATE.captures_iter(text).filter_map(|captures| {
+        Found::of(&captures, date(year(&captures, 1), number(&captures, 2), number(&captures, 3)))
+    }));
+    found.extend(SLASH_DATE.captures_iter(text).filter_map(|captures| {
+        Found::of(&captures, date(year(&captures, 3), number(&captures, 1), number(&captures, 2)))
+    }));
+    found.extend(MONTH_DAY.captures_iter(text).filter_map(|captures| {
+        Found::of(&captures, date(year(&captures, 3), month(&captures[1]), number(&captures, 2)))
+    }));
+    found.extend(DAY_MONTH.captures_iter(text).filter_map(|captures| {
+        Found::of(&captures, date(year(&captures, 3), month(&captures[2]), number(&captures, 1)))
+    }));
+    found.extend(RELATIVE_DATE.captures_iter(text).filter_map(|captures| {
+        let days = match captures[1].to_lowercase().as_str() {
+            "yesterday" => -1,
+            "tomorrow" => 1,
+            _ => 0,
+        };
+        let day = today + Duration::days(days);
+        Found::of(&captures, date(Some(day.year()), Some(day.month()), Some(day.day())))
+    }));
+    found
+}
+
+fn number(text: &str) -> Option<Value> {
+    let number = text.replace(',', "").parse::<f64>().ok()?;
+    serde_json::Number::from_f64(number).map(Value::Number)
+}
+
+/// Keeps the first of overlapping values, after sorting them by where they start
+fn without_overlaps(mut found: Vec<Found>) -> Vec<Found> {
+    found.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| b.end.cmp(&a.end)));
+    let mut kept: Vec<Found> = vec![];
+    for value in found {
+        if kept.last().map_or(true, |last| value.start >= last.end) {
+            kept.push(value);
+        }
+    }
+    kept
+}
+
+impl Matcher {
+    fn new(entity: &EntityDef) -> Result<Self, String> {
+        Ok(match entity {
+            EntityDef::Email => Matcher::Email,
+            EntityDef::Date => Matcher::Date,
+            EntityDef::Amount => Matcher::Amount,
+            EntityDef::Number => Matcher::Number,
+            EntityDef::Regex { pattern } => Matcher::Regex(Regex::new(pattern).map_err(|err| err.to_string())?),
+            EntityDef::Dictionary { values } => {
+                let mut dictionary = vec![];
+                for (key, synonyms) in values {
+                    let mut names = synonyms.iter().chain(std::iter::once(key)).map(|name| name.trim()).filter(|name| !name.is_empty()).collect::<Vec<&str>>();
+                    // the longest name wins, so "visa debit" is not taken for "visa"
+                    names.sort_by(|a, b| b.len().cmp(&a.len()));
+                    let alternatives = names.iter().map(|name| regex::escape(name)).collect::<Vec<String>>().join("|");
+                    let pattern = Regex::new(&format!(r"(?i)\b(?:{alternatives})\b")).map_err(|err| err.to_string())?;
+                    dictionary.push((key.clone(), pattern));
+                }
+                Matcher::Dictionary(dictionary)
+            }
+            EntityDef::Ner { label } => Matcher::Ner(label.clone()),
+        })
+    }
+
+    fn find(&self, text: &str, today: NaiveDate) -> Vec<Found> {
+        let found = match self {
+            Matcher::Email => EMAIL
+                .find_iter(text)
+                .map(|email| Found { start: email.start(), end: email.end(), value: Value::String(email.as_str().to_lowercase()) })
+                .collect(),
+            Matcher::Date => dates(text, today),
+            Matcher::Amount => AMOUNT
+                .captures_iter(text)
+                .filter_map(|captures| {
+                    let amount = captures.get(1).or_else(|| captures.get(2))?;
+                    Found::of(&captures, number(amount.as_str()))
+                })
+                .collect(),
+            Matcher::Number => NUMBER
+                .find_iter(text)
+                .filter_map(|found| Some(Found { start: found.start(), end: found.end(), value: number(found.as_str())? }))
+                .collect(),
+            Matcher::Regex(pattern) => pattern
+                .captures_iter(text)
+                .filter_map(|captures| {
+                    let value = captures.get(1).or_else(|| captures.get(0))?;
+                    Some(Found { start: value.start(), end: value.end(), value: Value::String(value.as_str().into()) })
+                })
+                .collect(),
+            Matcher::Dictionary(dictionary) => dictionary
+                .iter()
+                .flat_map(|(key, pattern)| {
+                    pattern.find_iter(text).map(move |name| Found { start: name.start(), end: name.end(), value: Value::String(key.clone()) })
+                })
+                .collect(),
+            Matcher::Ner(_) => vec![],
+        };
+        without_overlaps(found)
+    }
+}
+
+/// Whether a label such as `I-PER` or `B-PER` is `label`
+fn same_label(predicted: &str, label: &str) -> bool {
+    let predicted = predicted.strip_prefix("B-").or_else(|| predicted.strip_prefix("I-")).unwrap_or(predicted);
+    predicted.eq_ignore_ascii_case(label)
+}
+
+/// Finds the entities a spec declares in messages
+pub struct EntityExtractor {
+    matchers: Vec<(String, Matcher)>,
+    recognizer: Option<Box<dyn Recognizer>>,
+    min_score: f64,
+}
+
+impl EntityExtractor {
+    pub fn new(spec: &Spec) -> Result<Self, String> {
+        let mut matchers = vec![];
+        for (name, entity) in &spec.entities {
+            let matcher = Matcher::new(entity).map_err(|err| format!("entity '{name}': {err}"))?;
+            matchers.push((name.clone(), matcher));
+        }
+        Ok(Self {
+            matchers,
+            recognizer: None,
+            min_score: 0.0,
+        })
+    }
+
+    /// Finds the `ner` entities, those scored below `min_score` are dropped
+    pub fn with_recognizer(mut self, recognizer: Box<dyn Recognizer>, min_score: f64) -> Self {
+        self.recognizer = Some(recognizer);
+        self.min_score = min_score;
+        self
+    }
+
+    pub fn needs_recognizer(&self) -> bool {
+        self.matchers.iter().any(|(_, matcher)| matches!(matcher, Matcher::Ner(_)))
+    }
+
+    /// Entities of `message` by where they start, `today` is what relative dates count from
+    pub fn extract(&self, message: &str, today: NaiveDate) -> Vec<Entity> {
+        let chars = |byte: usize| message[..byte].chars().count();
+        let mut entities = vec![];
+        for (name, matcher) in &self.matchers {
+            entities.extend(matcher.find(message, today).into_iter().map(|found| Entity {
+                entity: name.clone(),
+                value: found.value,
+                text: message[found.start..found.end].to_string(),
+                start: chars(found.start),
+                end: chars(found.end),
+                score: 1.0,
+            }));
+        }
+
+        let recognized = match &self.recognizer {
+            Some(recognizer) if self.needs_recognizer() => recognizer.recognize(message).unwrap_or_else(|err| {
+                log::warn!("Failed to recognize entities: {err}");
+                vec![]
+            }),
+            _ => vec![],
+        };
+        for (name, matcher) in &self.matchers {
+            if let Matcher::Ner(label) = matcher {
+                entities.extend(
+                    recognized
+                        .iter()
+                        .filter(|recognized| same_label(&recognized.label, label) && recognized.score >= self.min_score)
+                        .map(|recognized| Entity {
+                            entity: name.clone(),
+                            value: Value::String(recognized.text.clone()),
+                            text: recognized.text.clone(),
+                            start: recognized.start,
+                            end: recognized.end,
+                            score: recognized.score,
+                        }),
+                );
+            }
+        }
+
+        entities.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.entity.cmp(&b.entity)));
+        entities
+    }
+}
+
+/// First value of every entity, what conditions read as `ctx.entities`
+pub fn values(entities: &[Entity]) -> BTreeMap<String, Value> {
+    let mut values = BTreeMap::new();
+    for entity in entities {
+        values.entry(entity.entity.clone()).or_insert_with(|| entity.value.clone());
+    }
+    values
+}
+
+/// Today in the spec's `sys.timezone`, UTC without one
+pub fn today(spec: &Spec) -> NaiveDate {
+    let timezone = spec.system
+        .get("timezone")
+        .and_then(|name| name.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC);
+    chrono::Utc::now().with_timezone(&timezone).naive_local().date()
+}
+
+/// Entities of `message` that `spec` declares. With `ner` entities the NER model of `settings` runs on a
+/// blocking thread, without a model configured they are never found.
+pub async fn extract(settings: &EntitySettings, models: Arc<ModelRegistry>, spec: &Spec, message: &str) -> Result<Vec<Entity>, LlmError> {
+    if spec.entities.is_empty() {
+        return Ok(vec![]);
+    }
+    let extractor = EntityExtractor::new(spec).map_err(|err| LlmError::Config(format!("Invalid spec: {err}")))?;
+    let today = today(spec);
+    if !extractor.needs_recognizer() {
+        return Ok(extractor.extract(message, today));
+    }
+
+    let extractor = match &settings.ner_model {
+        Some(model) => extractor.with_recognizer(Box::new(RegisteredRecognizer::new(models, model)), settings.min_score),
+        None => {
+            log::warn!("The spec declares ner entities but no entities.ner_model is configured");
+            extractor
+        }
+    };
+    let message = message.to_string();
+    // the model blocks for as long as it runs
+    Ok(tokio::task::spawn_blocking(move || extractor.extract(&message, today))
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("Entity extraction failed: {err}");
+            vec![]
+        }))
+}
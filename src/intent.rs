@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use async_trait::async_trait;
+use dfs_ml::intent::{IntentModel, TrainParams};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::{GlobalConfig, IntentClassifierSettings, IslaSettings};
+use crate::core::spec::{IntentTraining, Spec};
+use crate::openai::{guardrails, usage};
+use crate::openai::provider::{self, ChatMessage, CompletionRequest, LlmError, LlmProvider};
+
+lazy_static! {
+    /// Trained models by artifact path or by hash of a spec's examples, loaded or trained once while they are in use
+    static ref TRAINED: Mutex<HashMap<String, Trained>> = Mutex::new(HashMap::new());
+    /// Latest messages no intent matched, oldest first, clustered on `/intents/discover`
+    static ref UNMATCHED: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+}
+
+const CLASSIFIER_PROMPT: &str = "You classify a user's message into exactly one of the given intents. \
+Reply with a single JSON object and nothing else: \
+{\"intent\": <one of the intents, or null when none apply>, \"confidence\": <number from 0 to 1>, \"rationale\": <one short sentence>}";
+
+/// Examples and negatives of an intent shown to the LLM classifier, the rest only make the prompt longer
+const PROMPT_EXAMPLES: usize = 5;
+
+/// Label the negatives of a spec are trained as, a message closest to them has no intent
+const NO_INTENT: &str = "(none)";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Classification {
+    /// Always one of the candidate intents
+    pub intent: Option<String>,
+    pub confidence: f32,
+    pub rationale: String,
+}
+
+impl Classification {
+    pub fn none<S: Into<String>>(rationale: S) -> Self {
+        Self {
+            intent: None,
+            confidence: 0.0,
+            rationale: rationale.into(),
+        }
+    }
+}
+
+/// Picks which of a spec's intents a message is about
+#[async_trait]
+pub trait IntentClassifier: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn classify(&self, message: &str, intents: &[String]) -> Result<Classification, LlmError>;
+}
+
+/// Deterministic classifier matching intent names as whole words, the longest name wins
+/// so "login issue" beats "login". Needs no model, which also makes it the one to test with.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeywordClassifier;
+
+#[async_trait]
+impl IntentClassifier for KeywordClassifier {
+    fn name(&self) -> &str {
+        "keyword"
+    }
+
+    async fn classify(&self, message: &str, intents: &[String]) -> Result<Classification, LlmError> {
+        let message = message.to_lowercase();
+        let intent = intents
+            .iter()
+            .filter(|intent| {
+                let pattern = format!(r"\b{}\b", regex::escape(&intent.to_lowercase()));
+                Regex::new(&pattern).map(|re| re.is_match(&message)).unwrap_or(false)
+            })
+            .max_by_key(|intent| intent.len())
+            .cloned();
+
+        Ok(match intent {
+            Some(intent) => Classification {
+                rationale: format!("message mentions '{intent}'"),
+                intent: Some(intent),
+                confidence: 1.0,
+            },
+            None => Classification::none("message mentions no intent"),
+        })
+    }
+}
+
+/// Asks an LLM to choose among the intents
+pub struct LlmClassifier {
+    provider: Box<dyn LlmProvider>,
+    settings: IslaSettings,
+    min_confidence: f32,
+    training: BTreeMap<String, IntentTraining>,
+    /// Api key id the tokens of each classification are recorded for
+    tenant: Option<String>,
+}
+
+impl LlmClassifier {
+    pub fn new(provider: Box<dyn LlmProvider>, settings: IslaSettings, min_confidence: f32) -> Self {
+        Self { provider, settings, min_confidence, training: BTreeMap::new(), tenant: None }
+    }
+
+    pub fn with_tenant(mut self, tenant: Option<&str>) -> Self {
+        self.tenant = tenant.map(String::from);
+        self
+    }
+
+    /// Describes the intents to the model with the spec's descriptions, examples and negatives
+    pub fn with_training(mut self, training: BTreeMap<String, IntentTraining>) -> Self {
+        self.training = training;
+        self
+    }
+
+    fn request(&self, message: &str, intents: &[String]) -> CompletionRequest {
+        let mut described = String::new();
+        for intent in intents {
+            let training = match self.training.get(intent) {
+                Some(training) => training,
+                None => continue,
+            };
+            described.push_str(&format!("\n- {intent}"));
+            if let Some(description) = &training.description {
+                described.push_str(&format!(": {description}"));
+            }
+            let quoted = |texts: Vec<&str>| serde_json::to_string(&texts).unwrap_or_default();
+            if !training.examples.is_empty() {
+                let examples = training.examples.iter().take(PROMPT_EXAMPLES).map(|example| example.text.as_str()).collect();
+                described.push_str(&format!(" Examples: {}", quoted(examples)));
+            }
+            if !training.negatives.is_empty() {
+                let negatives = training.negatives.iter().take(PROMPT_EXAMPLES).map(String::as_str).collect();
+                described.push_str(&format!(" Not: {}", quoted(negatives)));
+            }
+        }
+        if !described.is_empty() {
+            described = format!("\nAbout the intents:{described}");
+        }
+
+        let user = format!(
+            "Intents: {}{described}\nMessage: {message}",
+            serde_json::to_string(intents).unwrap_or_default(),
+        );
+
+        let mut request = CompletionRequest::new(&self.settings, vec![
+            ChatMessage::system(CLASSIFIER_PROMPT),
+            ChatMessage::user(user),
+        ]);
+        request.temperature = 0.0;
+        request.top_p = 1.0;
+        request.frequency_penalty = 0.0;
+        request.presence_penalty = 0.0;
+        request.max_tokens = 256;
+        request
+    }
+
+    /// Reads the json object out of the reply, models like to wrap it in prose or code fences
+    fn parse(&self, text: &str, intents: &[String]) -> Result<Classification, LlmError> {
+        let json = text
+            .find('{')
+            .zip(text.rfind('}'))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| &text[start..=end])
+            .ok_or_else(|| LlmError::Parse(format!("classifier reply has no json: {text:?}")))?;
+        let value = serde_json::from_str::<Value>(json).map_err(|err| LlmError::Parse(err.to_string()))?;
+
+        let confidence = value
+            .get("confidence")
+            .and_then(|confidence| confidence.as_f64())
+            .unwrap_or(0.0)
+            .clamp(0.0, 1.0) as f32;
+        let rationale = value
+            .get("rationale")
+            .and_then(|rationale| rationale.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        // the model may answer with a label that was never offered, that counts as no intent
+        let intent = value
+            .get("intent")
+            .and_then(|intent| intent.as_str())
+            .and_then(|intent| intents.iter().find(|known| known.eq_ignore_ascii_case(intent.trim())))
+            .cloned();
+
+        Ok(match intent {
+            Some(_) if confidence < self.min_confidence => Classification {
+                intent: None,
+                confidence,
+                rationale: format!("confidence below {}: {rationale}", self.min_confidence),
+            },
+            intent => Classification { intent, confidence, rationale },
+        })
+    }
+}
+
+#[async_trait]
+impl IntentClassifier for LlmClassifier {
+    fn name(&self) -> &str {
+        "llm"
+    }
+
+    async fn classify(&self, message: &str, intents: &[String]) -> Result<Classification, LlmError> {
+        if intents.is_empty() {
+            return Ok(Classification::none("spec declares no intents"));
+        }
+
+        let completion = self.provider.complete(&self.request(message, intents)).await?;
+        usage::record(&self.settings.usage, self.tenant.as_deref(), &self.settings.model, completion.usage.as_ref());
+        if completion.finish_reason.as_deref() == Some(guardrails::REFUSED_FINISH_REASON) {
+            return Ok(Classification::none("message refused by the guardrails"));
+        }
+        self.parse(&completion.text, intents)
+    }
+}
+
+/// Ranks intents with a model trained on labelled utterances, so it runs in process without an LLM
+pub struct TrainedClassifier {
+    model: Arc<IntentModel>,
+    min_confidence: f32,
+}
+
+impl TrainedClassifier {
+    pub fn new(model: Arc<IntentModel>, min_confidence: f32) -> Self {
+        Self { model, min_confidence }
+    }
+
+    /// Reads the artifact at `model`, training it on `examples` first when it is missing or older than them.
+    /// Blocks for as long as training takes.
+    pub fn from_files(model: &str, examples: Option<&str>, min_confidence: f32, max_models: usize) -> Result<Self, LlmError> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let examples_modified = examples.and_then(modified);
+        if let Some(cached) = cached(model, examples_modified) {
+            return Ok(Self::new(cached, min_confidence));
+        }
+
+        let stale = match (modified(model), examples_modified) {
+            (None, _) => true,
+            (Some(model), Some(examples)) => model < examples,
+            (Some(_), None) => false,
+        };
+
+        let loaded = match (stale, examples) {
+            (true, Some(examples)) => train(model, examples)?,
+            (true, None) => return Err(LlmError::Config(format!("No intent model at {model} and no examples to train one"))),
+            (false, _) => IntentModel::load(model).map_err(|err| LlmError::Config(err.to_string()))?,
+        };
+        let loaded = Arc::new(loaded);
+        cache(model.into(), loaded.clone(), examples_modified, max_models);
+        Ok(Self::new(loaded, min_confidence))
+    }
+
+    /// Trains on the examples of `spec`, with its negatives as examples of no intent, unless it has more
+    /// than `max_examples`. Kept in memory only, a spec is trained on again once its examples change.
+    /// Blocks for as long as training takes.
+    pub fn from_spec(spec: &Spec, min_confidence: f32, max_examples: usize, max_models: usize) -> Result<Self, LlmError> {
+        let mut examples = spec
+            .examples()
+            .into_iter()
+            .map(|(utterance, intent)| dfs_ml::intent::Example::new(utterance, intent))
+            .collect::<Vec<_>>();
+        examples.extend(spec.negatives().into_iter().map(|utterance| dfs_ml::intent::Example::new(utterance, NO_INTENT)));
+        if examples.len() > max_examples {
+            return Err(LlmError::Config(format!(
+                "Spec has {} examples and negatives, at most {max_examples} are trained on",
+                examples.len(),
+            )));
+        }
+
+        let fingerprint = serde_json::to_vec(&examples).map_err(|err| LlmError::Config(err.to_string()))?;
+        let key = format!("spec:{:x}", Sha256::digest(&fingerprint));
+        if let Some(cached) = cached(&key, None) {
+            return Ok(Self::new(cached, min_confidence));
+        }
+
+        // two requests with a new spec may both train it, neither holds the cache while it does
+        let model = IntentModel::fit(&examples, &TrainParams::default()).map_err(|err| LlmError::Config(err.to_string()))?;
+        log::info!("Trained intent model on {} examples of the spec", examples.len());
+        let model = Arc::new(model);
+        cache(key, model.clone(), None, max_models);
+        Ok(Self::new(model, min_confidence))
+    }
+}
+
+struct Trained {
+    model: Arc<IntentModel>,
+    /// Modification time of the examples file the model was loaded or trained for
+    examples_modified: Option<SystemTime>,
+    used: Instant,
+}
+
+/// Model under `key`, unless its examples file was modified since it was cached
+fn cached(key: &str, examples_modified: Option<SystemTime>) -> Option<Arc<IntentModel>> {
+    let mut trained = TRAINED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    trained
+        .get_mut(key)
+        .filter(|cached| cached.examples_modified == examples_modified)
+        .map(|cached| {
+            cached.used = Instant::now();
+            cached.model.clone()
+        })
+}
+
+/// Keeps `model` under `key`, dropping the least recently used models beyond `max`
+fn cache(key: String, model: Arc<IntentModel>, examples_modified: Option<SystemTime>, max: usize) {
+    let mut trained = TRAINED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    trained.insert(key, Trained { model, examples_modified, used: Instant::now() });
+    while trained.len() > max {
+        let oldest = trained.iter().min_by_key(|(_, cached)| cached.used).map(|(key, _)| key.clone());
+        match oldest {
+            Some(oldest) => trained.remove(&oldest),
+            None => break,
+        };
+    }
+}
+
+/// Trains on the examples file and saves the model to `model`, a model that fails to save is still used
+fn train(model: &str, examples: &str) -> Result<IntentModel, LlmError> {
+    let examples = dfs_ml::intent::read_examples(examples).map_err(|err| LlmError::Config(err.to_string()))?;
+    let trained = IntentModel::train(&examples, &TrainParams::default()).map_err(|err| LlmError::Config(err.to_string()))?;
+    match &trained.evaluation {
+        Some(evaluation) => log::info!(
+            "Trained intent model on {} examples, held out accuracy {:.3}, mcc {:.3}, confusion {:?} of {:?}",
+            examples.len(),
+            evaluation.accuracy,
+            evaluation.mcc,
+            evaluation.confusion,
+            evaluation.labels,
+        ),
+        None => log::info!("Trained intent model on {} examples, too few to hold any out", examples.len()),
+    }
+
+    if let Err(err) = trained.model.save(Path::new(model)) {
+        log::warn!("Not saving the intent model: {err}");
+    }
+    Ok(trained.model)
+}
+
+#[async_trait]
+impl IntentClassifier for TrainedClassifier {
+    fn name(&self) -> &str {
+        "trained"
+    }
+
+    async fn classify(&self, message: &str, intents: &[String]) -> Result<Classification, LlmError> {
+        // intents the spec no longer declares are skipped, ones the model never saw cannot come up
+        let best = self.model
+            .predict(message)
+            .into_iter()
+            .find(|ranked| ranked.intent == NO_INTENT || intents.contains(&ranked.intent));
+
+        Ok(match best {
+            None => Classification::none("the model knows none of the intents"),
+            Some(ranked) if ranked.intent == NO_INTENT => Classification {
+                intent: None,
+                confidence: ranked.score as f32,
+                rationale: "message is closest to a negative example".into(),
+            },
+            Some(ranked) if (ranked.score as f32) < self.min_confidence => Classification {
+                intent: None,
+                confidence: ranked.score as f32,
+                rationale: format!("confidence below {}: '{}' ranked first", self.min_confidence, ranked.intent),
+            },
+            Some(ranked) => Classification {
+                rationale: format!("trained model ranks '{}' first", ranked.intent),
+                confidence: ranked.score as f32,
+                intent: Some(ranked.intent),
+            },
+        })
+    }
+}
+
+/// Keeps `message` for intent discovery with its personal data redacted, dropping the oldest beyond `max`
+pub fn record_unmatched(message: &str, max: usize) {
+    let message = guardrails::redact_pii(message.trim());
+    if message.is_empty() || max == 0 {
+        return;
+    }
+    if let Ok(mut unmatched) = UNMATCHED.lock() {
+        unmatched.push_back(message);
+        while unmatched.len() > max {
+            unmatched.pop_front();
+        }
+    }
+}
+
+/// Messages kept by [`record_unmatched`], oldest first
+pub fn unmatched() -> Vec<String> {
+    UNMATCHED
+        .lock()
+        .map(|unmatched| unmatched.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Classifier of `isla_settings.intent_classifier`, learning from the training data of `spec` when it has some.
+/// Tokens the LLM classifier spends are recorded for `tenant`.
+pub async fn from_config(config: &GlobalConfig, spec: Option<&Spec>, tenant: Option<&str>) -> Result<Box<dyn IntentClassifier>, LlmError> {
+    let settings = &config.isla_settings;
+    let trained_spec = spec.filter(|spec| !spec.examples().is_empty());
+    Ok(match &settings.intent_classifier {
+        IntentClassifierSettings::Keyword => Box::new(KeywordClassifier),
+        IntentClassifierSettings::Llm { min_confidence } => Box::new(
+            LlmClassifier::new(guardrails::wrap(provider::from_config(config)?, config)?, settings.clone(), *min_confidence)
+                .with_training(spec.map(|spec| spec.training.clone()).unwrap_or_default())
+                .with_tenant(tenant),
+        ),
+        IntentClassifierSettings::Trained { model, examples, min_confidence, max_models, max_examples } => {
+            let (model, examples, spec) = (model.clone(), examples.clone(), trained_spec.cloned());
+            let (min_confidence, max_models, max_examples) = (*min_confidence, *max_models, *max_examples);
+            // loading and training block for as long as they take
+            let classifier = tokio::task::spawn_blocking(move || match (examples, spec) {
+                (None, Some(spec)) => TrainedClassifier::from_spec(&spec, min_confidence, max_examples, max_models),
+                (examples, _) => TrainedClassifier::from_files(&model, examples.as_deref(), min_confidence, max_models),
+            })
+                .await
+                .map_err(|err| LlmError::Config(format!("Intent model training failed: {err}")))??;
+            Box::new(classifier)
+        }
+    })
+}
@@ -5,14 +5,11 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
 use actix::{Actor, Addr};
-use futures::future::{ok, err, Ready};
-use actix_web::{get, options, http, post, web, App, HttpResponse, HttpServer, Responder, HttpRequest, FromRequest};
+use actix_web::{get, options, http, post, web, App, HttpResponse, HttpServer, Responder, HttpRequest};
 use actix_web::{dev::Service as _};
 use futures_util::future::FutureExt;
+use futures::StreamExt;
 use actix_cors::Cors;
-use actix_web::dev::Payload;
-use actix_web::Error as ActixWebError;
-use actix_web::error::{ErrorNotFound};
 use actix_web::middleware::Logger as AuditLogger;
 use actix_web_actors::ws;
 use clap::builder::Str;
@@ -28,8 +25,12 @@ use uuid::Uuid;
 
 #[macro_use]
 mod config;
+mod auth;
 mod core;
 mod chat_app;
+mod conversation;
+mod entities;
+mod intent;
 mod ml;
 mod token;
 mod openai;
@@ -39,6 +40,10 @@ use crate::{
     config::*,
 };
 use crate::chat_app::{server, session};
+use crate::auth::api_key::{ApiKeyIdentity, AuthError, RequireScope, Scope};
+use crate::auth::cognito::{CognitoClaims, CognitoUser, CognitoVerifier};
+use crate::auth::oidc::ProviderRegistry;
+use crate::auth::webhook::{WebhookError, WebhookVerifier};
 
 const TRACE_ID: &str = "x-trace-id";
 const SPAN_ID: &str = "x-span-id";
@@ -103,34 +108,6 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
     format!("Visitors: {current_count}")
 }
 
-// #[post("/qa")]
-// async fn test_qa(req_body: String) -> web::Json<Value> {
-//     let req = serde_json::from_str::<Vec<QaInput>>(req_body.as_str());
-//
-//     match req {
-//         Ok(req) => {
-//             let answers = dfs_ml::bert::ul::qa(&req);
-//             web::Json(serde_json::json! {{
-//                 "message": "Evaluated QAs".to_string(),
-//                 "result": Some(answers),
-//                 "error": false,
-//             }})
-//         }
-//         Err(error) => {
-//             let message = format!("Failed to parse request: {:?}", error.to_string());
-//             web::Json(serde_json::json! {{
-//                 "message": message,
-//                 "result": null,
-//                 "error": true,
-//             }})
-//         }
-//     }
-// }
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatbotRequest {
-    hist: Vec<String>,
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 struct DustinDiazIoRequest {
     host: String,
@@ -140,6 +117,61 @@ struct DustinDiazIoRequest {
 pub struct ChatbotResponse {
     response: Option<openai::isla::ChatbotResponse>,
     error: bool,
+    /// Machine readable reason of the error, see `LlmError::code` for upstream errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConversationResponse {
+    response: Option<conversation::Reply>,
+    error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UsageResponse {
+    response: Option<openai::usage::Report>,
+    error: bool,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UsageQuery {
+    /// First day to report, `YYYY-MM-DD`, defaults to the first of the month
+    from: Option<chrono::NaiveDate>,
+    /// Last day to report, defaults to today
+    to: Option<chrono::NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QaResponse {
+    /// Answers per question, best first
+    response: Option<Vec<Vec<ml::QaAnswer>>>,
+    error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModelsResponse {
+    response: Option<Vec<dfs_ml::registry::ModelHealth>>,
+    error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DiscoverResponse {
+    response: Option<dfs_ml::cluster::Clustering>,
+    error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
     message: String,
 }
 
@@ -160,13 +192,28 @@ pub struct DustinDiazIoResponse {
 //     HttpResponse::Ok().finish()
 // }
 
-#[post("/isla-response")]
-async fn chatbot(req_body: String) -> web::Json<ChatbotResponse> {
-    let req = serde_json::from_str::<ChatbotRequest>(req_body.as_str());
+/// Status of a failed chat. Used up quotas are a 429 so clients back off, other errors keep
+/// being reported in the body of a 200 as they always have.
+fn upstream_error_status(err: &openai::provider::LlmError) -> actix_web::HttpResponseBuilder {
+    match err {
+        openai::provider::LlmError::QuotaExceeded { .. } => HttpResponse::TooManyRequests(),
+        _ => HttpResponse::Ok(),
+    }
+}
+
+/// Id of the api key that passed `RequireScope`, personas can be picked per key
+fn api_key_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<ApiKeyIdentity>().map(|identity| identity.id.clone())
+}
+
+#[post("/isla-response", wrap = "RequireScope::new(Scope::Chat)")]
+async fn chatbot(http_req: HttpRequest, models: web::Data<dfs_ml::registry::ModelRegistry>, req_body: String) -> HttpResponse {
+    let req = serde_json::from_str::<openai::isla::ChatbotRequest>(req_body.as_str());
 
     if req.is_err() {
-        return web::Json(ChatbotResponse {
+        return HttpResponse::Ok().json(ChatbotResponse {
             error: true,
+            code: Some("invalid_request".into()),
             response: None,
             message: format!("Failed to parse incoming request: {req:?}")
         })
@@ -175,30 +222,36 @@ async fn chatbot(req_body: String) -> web::Json<ChatbotResponse> {
     if let Some(Some(config)) = global!() {
         // checked if is error
         let req = req.unwrap();
+        let tenant = api_key_id(&http_req);
         let res = openai::isla::get_response(
             &config,
-            req.hist
+            req,
+            tenant.as_deref(),
+            models.into_inner()
         ).await;
 
         match res {
             Ok(res) => {
-                web::Json(ChatbotResponse {
+                HttpResponse::Ok().json(ChatbotResponse {
                     error: false,
+                    code: None,
                     response: Some(res),
                     message: "".into()
                 })
             },
             Err(err) => {
-                web::Json(ChatbotResponse {
+                upstream_error_status(&err).json(ChatbotResponse {
                     error: true,
+                    code: Some(err.code().into()),
                     response: None,
-                    message: format!("Failed to get response from bot: {err:?}")
+                    message: format!("Failed to get response from bot: {err}")
                 })
             }
         }
     } else {
-        web::Json(ChatbotResponse {
+        HttpResponse::Ok().json(ChatbotResponse {
             error: true,
+            code: Some("config_unavailable".into()),
             response: None,
             message: "Failed to get essential settings".into()
         })
@@ -206,7 +259,294 @@ async fn chatbot(req_body: String) -> web::Json<ChatbotResponse> {
 
 }
 
-#[post("/dustindiaz_io")]
+/// Extractive answers to a batch of questions, each one taken from the context sent with it
+#[post("/qa", wrap = "RequireScope::new(Scope::Chat)")]
+async fn qa(service: web::Data<ml::QaService>, req_body: String) -> HttpResponse {
+    let req = match serde_json::from_str::<ml::QaRequest>(req_body.as_str()) {
+        Ok(req) => req,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(QaResponse {
+                error: true,
+                code: Some("invalid_request".into()),
+                response: None,
+                message: format!("Failed to parse incoming request: {err}"),
+            })
+        }
+    };
+
+    match service.answer(req).await {
+        Ok(answers) => HttpResponse::Ok().json(QaResponse {
+            error: false,
+            code: None,
+            response: Some(answers),
+            message: "".into(),
+        }),
+        Err(err) => {
+            let mut response = match err {
+                ml::QaError::Invalid(_) => HttpResponse::BadRequest(),
+                ml::QaError::Unavailable | ml::QaError::Busy => HttpResponse::ServiceUnavailable(),
+                ml::QaError::Timeout => HttpResponse::GatewayTimeout(),
+                ml::QaError::Failed(_) => HttpResponse::InternalServerError(),
+            };
+            response.json(QaResponse {
+                error: true,
+                code: Some(err.code().into()),
+                response: None,
+                message: err.to_string(),
+            })
+        }
+    }
+}
+
+/// Load status, weights size and swaps of every local model
+#[get("/models/health", wrap = "RequireScope::new(Scope::SpecRead)")]
+async fn models_health(registry: web::Data<dfs_ml::registry::ModelRegistry>) -> web::Json<ModelsResponse> {
+    web::Json(ModelsResponse {
+        error: false,
+        code: None,
+        response: Some(registry.health()),
+        message: "".into(),
+    })
+}
+
+/// Loads the model again, from its spec in the current config, and swaps it in once ready.
+/// Requests keep being answered by the old model meanwhile, and after a failed swap.
+#[post("/models/{name}/reload", wrap = "RequireScope::new(Scope::SpecWrite)")]
+async fn models_reload(registry: web::Data<dfs_ml::registry::ModelRegistry>, name: web::Path<String>) -> HttpResponse {
+    let name = name.into_inner();
+    let spec = global!()
+        .flatten()
+        .and_then(|config| config.config.models.iter().find(|spec| spec.name == name).cloned())
+        .or_else(|| registry.spec(&name));
+
+    let swapped = match spec {
+        None => Err(dfs_ml::registry::ModelError::Unknown(name)),
+        Some(spec) => {
+            let registry = registry.into_inner();
+            // loading reads the weights from disk for seconds
+            web::block(move || registry.swap(spec))
+                .await
+                .unwrap_or_else(|err| Err(dfs_ml::registry::ModelError::Load { name, message: err.to_string() }))
+        }
+    };
+
+    match swapped {
+        Ok(health) => HttpResponse::Ok().json(ModelsResponse {
+            error: false,
+            code: None,
+            response: Some(vec![health]),
+            message: "".into(),
+        }),
+        Err(err) => {
+            let mut response = match err {
+                dfs_ml::registry::ModelError::Unknown(_) => HttpResponse::NotFound(),
+                _ => HttpResponse::InternalServerError(),
+            };
+            response.json(ModelsResponse {
+                error: true,
+                code: Some(err.code().into()),
+                response: None,
+                message: err.to_string(),
+            })
+        }
+    }
+}
+
+/// Clusters utterances no intent matched, sent in the body and/or kept from recent conversations,
+/// into candidates for new intents with their keywords and most typical utterances
+#[post("/intents/discover", wrap = "RequireScope::new(Scope::SpecWrite)")]
+async fn intents_discover(registry: web::Data<dfs_ml::registry::ModelRegistry>, req_body: String) -> HttpResponse {
+    let req = match serde_json::from_str::<ml::discovery::DiscoverRequest>(req_body.as_str()) {
+        Ok(req) => req,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(DiscoverResponse {
+                error: true,
+                code: Some("invalid_request".into()),
+                response: None,
+                message: format!("Failed to parse incoming request: {err}"),
+            })
+        }
+    };
+    let settings = global!()
+        .flatten()
+        .map(|config| config.config.discovery.clone())
+        .unwrap_or_default();
+
+    let registry = registry.into_inner();
+    let unmatched = intent::unmatched();
+    // embedding and clustering thousands of utterances takes a while
+    let clustering = web::block(move || ml::discovery::discover(req, unmatched, &settings, &registry))
+        .await
+        .unwrap_or_else(|err| Err(ml::discovery::DiscoveryError::Failed(err.to_string())));
+
+    match clustering {
+        Ok(clustering) => HttpResponse::Ok().json(DiscoverResponse {
+            error: false,
+            code: None,
+            response: Some(clustering),
+            message: "".into(),
+        }),
+        Err(err) => {
+            let mut response = match err {
+                ml::discovery::DiscoveryError::Invalid(_) => HttpResponse::BadRequest(),
+                _ => HttpResponse::InternalServerError(),
+            };
+            response.json(DiscoverResponse {
+                error: true,
+                code: Some(err.code().into()),
+                response: None,
+                message: err.to_string(),
+            })
+        }
+    }
+}
+
+/// Answers from the spec dialogs first and falls back to Isla, `decision` says which one answered.
+/// Sending a `spec` along takes the `spec:write` scope as well.
+#[post("/converse", wrap = "RequireScope::new(Scope::Chat)")]
+async fn converse(http_req: HttpRequest, models: web::Data<dfs_ml::registry::ModelRegistry>, req_body: String) -> HttpResponse {
+    let req = match serde_json::from_str::<conversation::ConversationRequest>(req_body.as_str()) {
+        Ok(req) => req,
+        Err(err) => {
+            return HttpResponse::Ok().json(ConversationResponse {
+                error: true,
+                code: Some("invalid_request".into()),
+                response: None,
+                message: format!("Failed to parse incoming request: {err:?}")
+            })
+        }
+    };
+
+    // a spec sent along is compiled and trained on, which only keys that may write specs get to do
+    if req.spec.is_some() {
+        let allowed = match http_req.extensions().get::<ApiKeyIdentity>() {
+            Some(identity) => identity.require(Scope::SpecWrite),
+            None => Err(AuthError::Missing),
+        };
+        if let Err(err) = allowed {
+            return err.to_response();
+        }
+    }
+
+    if let Some(Some(config)) = global!() {
+        let tenant = api_key_id(&http_req);
+        match conversation::get_response(&config, req, tenant.as_deref(), models.into_inner()).await {
+            Ok(reply) => HttpResponse::Ok().json(ConversationResponse {
+                error: false,
+                code: None,
+                response: Some(reply),
+                message: "".into()
+            }),
+            Err(err) => upstream_error_status(&err).json(ConversationResponse {
+                error: true,
+                code: Some(err.code().into()),
+                response: None,
+                message: format!("Failed to get response from bot: {err}")
+            }),
+        }
+    } else {
+        HttpResponse::Ok().json(ConversationResponse {
+            error: true,
+            code: Some("config_unavailable".into()),
+            response: None,
+            message: "Failed to get essential settings".into()
+        })
+    }
+}
+
+/// Tokens and estimated cost of the calling api key per day and model, plus its monthly quota
+#[get("/usage", wrap = "RequireScope::new(Scope::Chat)")]
+async fn usage(http_req: HttpRequest, query: web::Query<UsageQuery>) -> web::Json<UsageResponse> {
+    if let Some(Some(config)) = global!() {
+        let tenant = api_key_id(&http_req);
+        let report = openai::usage::report(&config.config.isla_settings.usage, tenant.as_deref(), query.from, query.to);
+        web::Json(UsageResponse {
+            error: false,
+            response: Some(report),
+            message: "".into()
+        })
+    } else {
+        web::Json(UsageResponse {
+            error: true,
+            response: None,
+            message: "Failed to get essential settings".into()
+        })
+    }
+}
+
+/// Streams Isla's reply as server sent events, the last event carries `finish_reason` and `usage`
+#[post("/isla-response/stream", wrap = "RequireScope::new(Scope::Chat)")]
+async fn chatbot_stream(http_req: HttpRequest, models: web::Data<dfs_ml::registry::ModelRegistry>, req_body: String) -> HttpResponse {
+    let req = match serde_json::from_str::<openai::isla::ChatbotRequest>(req_body.as_str()) {
+        Ok(req) => req,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(ChatbotResponse {
+                error: true,
+                code: Some("invalid_request".into()),
+                response: None,
+                message: format!("Failed to parse incoming request: {err:?}")
+            })
+        }
+    };
+
+    let config = match global!() {
+        Some(Some(config)) => config,
+        _ => {
+            return HttpResponse::ServiceUnavailable().json(ChatbotResponse {
+                error: true,
+                code: Some("config_unavailable".into()),
+                response: None,
+                message: "Failed to get essential settings".into()
+            })
+        }
+    };
+
+    let tenant = api_key_id(&http_req);
+    match openai::isla::get_response_stream(&config, req, tenant.as_deref(), models.into_inner()).await {
+        Ok(stream) => {
+            let frames = stream.map(|event| {
+                let name = match &event {
+                    Ok(openai::provider::StreamEvent::Citations { .. }) => "citations",
+                    Ok(openai::provider::StreamEvent::Delta { .. }) => "delta",
+                    Ok(openai::provider::StreamEvent::Done { .. }) => "done",
+                    Err(_) => "error",
+                };
+                let frame = format!("event: {name}\ndata: {}\n\n", openai::isla::stream_event_json(&event));
+                Ok::<_, actix_web::Error>(web::Bytes::from(frame))
+            });
+
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .insert_header(("Cache-Control", "no-cache"))
+                .streaming(frames)
+        }
+        Err(err) => {
+            let mut response = match err {
+                openai::provider::LlmError::QuotaExceeded { .. } => HttpResponse::TooManyRequests(),
+                openai::provider::LlmError::Timeout(_) => HttpResponse::GatewayTimeout(),
+                openai::provider::LlmError::CircuitOpen { .. } => HttpResponse::ServiceUnavailable(),
+                _ => HttpResponse::BadGateway(),
+            };
+            response.json(ChatbotResponse {
+                error: true,
+                code: Some(err.code().into()),
+                response: None,
+                message: format!("Failed to get response from bot: {err}")
+            })
+        }
+    }
+}
+
+/// Websocket mode of `/isla-response/stream`, each text frame sent is a chatbot request
+async fn chatbot_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    models: web::Data<dfs_ml::registry::ModelRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    ws::start(session::IslaSession::new(api_key_id(&req), models.into_inner()), &req, stream)
+}
+
+#[post("/dustindiaz_io", wrap = "RequireScope::new(Scope::SpecRead)")]
 async fn dustindiaz_io_config() -> web::Json<DustinDiazIoResponse> {
     if let Some(Some(config)) = global!() {
         // checked if is error
@@ -226,7 +566,7 @@ async fn dustindiaz_io_config() -> web::Json<DustinDiazIoResponse> {
     }
 }
 
-#[post("/condition")]
+#[post("/condition", wrap = "RequireScope::new(Scope::Eval)")]
 async fn test_condition(req_body: String) -> web::Json<spec::web::ConditionResponse> {
     // if let Some(_global) = global!() {
         let req = serde_json::from_str::<spec::web::ConditionRequest>(req_body.as_str());
@@ -275,6 +615,12 @@ async fn home() -> impl Responder {
     HttpResponse::Ok().body(msg)
 }
 
+/// Claims of the signed in user, requires a valid Cognito bearer token
+#[get("/me")]
+async fn me(user: CognitoUser) -> web::Json<CognitoClaims> {
+    web::Json(user.0)
+}
+
 #[get("/version")]
 async fn version() -> impl Responder {
     HttpResponse::Ok()
@@ -298,88 +644,50 @@ async fn version_post() -> impl Responder {
         .body(build_date.unwrap_or_default())
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct Thing;
-
-impl FromRequest for Thing {
-    type Error = ActixWebError;
-    type Future = Ready<Result<Thing, ActixWebError>>;
-
-    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        if is_authorized(req) {
-            ok(Thing {})
-        } else {
-            err(ErrorNotFound("Not Found"))
+/// GitHub webhook, reloads the service when a signed delivery for one of the reload events arrives
+async fn update(
+    req: HttpRequest,
+    body: web::Bytes,
+    verifier: web::Data<WebhookVerifier>,
+) -> Result<HttpResponse, WebhookError> {
+    let (trace_id, span_id) = trace_id_from_req!(req);
+    let (secrets, reload_event) = match global!() {
+        Some(Some(g)) => {
+            let is_reload_event = |event: &str| g.config.is_expected_reload_event(event);
+            let event = req
+                .headers()
+                .get(auth::webhook::EVENT_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(is_reload_event)
+                .unwrap_or(false);
+            (g.config.webhook_secrets(), event)
         }
-    }
-}
-
-fn is_authorized(req: &HttpRequest) -> bool {
-    let headers = req.headers();
-    if let Some(secret) = headers.get("X-Hub-Signature-256") {
-        let github_event = headers.get("X-GitHub-Event");
-        let github_delivery = headers.get("X-GitHub-Event");
-        let user_agent = headers.get("User-Agent");
-        let (trace_id, span_id) = trace_id_from_req!(req);
-
-        let headers_present = {
-            let opts = vec![
-                &github_event,
-                &github_delivery,
-                &user_agent,
-            ];
-            let count = opts.len();
-            let valid_count = opts
-                .iter()
-                .filter(|x| x.is_some())
-                .count();
-
-            count == valid_count && valid_count > 0 && count > 0
-        };
-
-        let valid = {
-            let matched = match secret.to_str() {
-                Ok(secret) => {
-                    if let Some(Some(g)) = global!() {
-                        log::info!("Comparing secrets; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
-                        g.config.cmp_webhook_secret(secret)
-                    } else {
-                        log::error!("Failed to get global config for reload; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
-                        false
-                    }
-                }
-                Err(_) => false,
-            };
-
-            let valid_user_agent = match user_agent {
-                None => false,
-                Some(user_agent) => {
-                    user_agent.to_str().unwrap_or("invalid")
-                        .starts_with("GitHub-Hookshot/")
-                }
-            };
-
-            matched && headers_present && valid_user_agent
-        };
-
-        if valid {
-            log::warn!("Reload Attempt: req={req:?}; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+        _ => {
+            log::error!("Failed to get global config for reload; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+            (vec![], false)
+        }
+    };
 
-            std::process::Command::new("sh start.sh")
-                .spawn()
-                .expect("sh command failed to start");
+    let delivery = match verifier.verify(&secrets, req.headers(), &body) {
+        Ok(delivery) => delivery,
+        Err(error) => {
+            log::warn!("Rejected webhook: {error}; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+            return Err(error);
         }
-    }
+    };
 
-    false
-}
+    if !reload_event {
+        log::info!("Ignoring webhook event: delivery={delivery:?}; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+        return Ok(HttpResponse::Accepted().json(json!({ "error": false, "message": "Ignored event" })));
+    }
 
-/// extract `Thing` from request
-async fn index(supplied_thing: Result<Thing, ActixWebError>) -> String {
-    match supplied_thing {
-        Ok(thing) => format!("Got thing: {:?}", thing),
-        Err(e) => format!("Unknown error: {}", e)
+    log::warn!("Reload Attempt: delivery={delivery:?}; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+    if let Err(error) = std::process::Command::new("sh").arg("start.sh").spawn() {
+        log::error!("Failed to start reload: {error:?}; <? trace_id={trace_id:?} span_id={span_id:?} ?>");
+        return Ok(HttpResponse::InternalServerError().json(json!({ "error": true, "message": "Failed to start reload" })));
     }
+
+    Ok(HttpResponse::Accepted().json(json!({ "error": false, "message": "Reloading" })))
 }
 
 // #[post("/token")]
@@ -700,6 +1008,12 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("Stating application: {:?}", config.env.host_port());
 
+    match openai::usage::load(&config.config.isla_settings.usage).await {
+        Ok(0) => (),
+        Ok(loaded) => log::info!("Loaded {loaded} usage totals"),
+        Err(err) => log::error!("Failed to load usage totals, quotas start from this process' usage: {err}"),
+    }
+
     let app_state = Arc::new(AtomicUsize::new(0));
     let server = server::ChatServer::new(app_state.clone()).start();
 
@@ -707,6 +1021,24 @@ async fn main() -> std::io::Result<()> {
     builder.set_private_key_file("key.pem", SslFiletype::PEM).unwrap();
     builder.set_certificate_chain_file("cert.pem").unwrap();
 
+    let cognito_verifier = web::Data::new(
+        CognitoVerifier::from_config(&config.config.cognito)
+    );
+    let identity_providers = web::Data::new(ProviderRegistry::new());
+    let webhook_verifier = web::Data::new(WebhookVerifier::default());
+    let models = Arc::new(dfs_ml::registry::ModelRegistry::new(config.config.models.clone()));
+    let warming = models.clone();
+    std::thread::spawn(move || {
+        for (name, warmed) in warming.warm_up() {
+            match warmed {
+                Ok(()) => log::info!("Warmed up model {name}"),
+                Err(err) => log::error!("Failed to warm up model {name}: {err}"),
+            }
+        }
+    });
+    let qa_service = web::Data::new(ml::QaService::from_config(&config.config.qa, models.clone()));
+    let models = web::Data::from(models);
+
     HttpServer::new(move || {
         let conf = global!().unwrap().unwrap();
         let environment = conf.env.clone();
@@ -797,20 +1129,44 @@ async fn main() -> std::io::Result<()> {
             })
 
             .wrap(AuditLogger::new(&conf.config.audit_logger_format).log_target("audit"))
+            .app_data(cognito_verifier.clone())
+            .app_data(identity_providers.clone())
+            .app_data(webhook_verifier.clone())
+            .app_data(qa_service.clone())
+            .app_data(models.clone())
             // .app_data(web::Data::from(app_state.clone()))
             // .app_data(web::Data::new(server.clone()))
             // .service(example)
             .service(home)
-            .route("/count", web::get().to(get_count))
+            .service(
+                web::resource("/count")
+                    .wrap(RequireScope::new(Scope::SpecRead))
+                    .route(web::get().to(get_count))
+            )
             // .route("/ws", web::get().to(chat_route))
-            .route("/update", web::post().to(index))
-            // .route("/qa", web::post().to(test_qa))
+            .route("/update", web::post().to(update))
             .service(chatbot)
+            .service(chatbot_stream)
+            .service(converse)
+            .service(usage)
+            .service(qa)
+            .service(models_health)
+            .service(models_reload)
+            .service(intents_discover)
+            .service(
+                web::resource("/isla-response/ws")
+                    .wrap(RequireScope::new(Scope::Chat))
+                    .route(web::get().to(chatbot_ws))
+            )
             .service(test_condition)
             .service(version)
+            .service(me)
             .service(version_post)
             .service(dustindiaz_io_config)
+            .service(token::authorize)
             .service(token::token)
+            .service(token::revoke)
+            .service(token::user_info)
     })
         .bind(config.env.host_port())?
         // .bind_openssl("0.0.0.0:443", builder)?
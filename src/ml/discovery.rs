@@ -0,0 +1,121 @@
+use std::fmt;
+
+use dfs_ml::cluster::{self, Algorithm, ClusterError, Clustering};
+use dfs_ml::registry::{ModelError, ModelRegistry};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DiscoverySettings;
+
+/// Utterances to cluster, taken from any mix of the fields
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DiscoverRequest {
+    #[serde(default)]
+    pub utterances: Vec<String>,
+    /// Text of an uploaded file, a `.csv` with an `utterance` or `text` column or one utterance per line
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Adds the recent messages no intent matched
+    #[serde(default)]
+    pub unmatched: bool,
+    /// Overrides `discovery.clustering.algorithm`
+    #[serde(default)]
+    pub algorithm: Option<Algorithm>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryError {
+    Invalid(String),
+    /// The embedding model could not be loaded or failed
+    Model(ModelError),
+    Failed(String),
+}
+
+impl DiscoveryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DiscoveryError::Invalid(_) => "invalid_request",
+            DiscoveryError::Model(err) => err.code(),
+            DiscoveryError::Failed(_) => "discovery_failed",
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Invalid(message) => write!(f, "Invalid request: {message}"),
+            DiscoveryError::Model(err) => write!(f, "{err}"),
+            DiscoveryError::Failed(message) => write!(f, "Intent discovery failed: {message}"),
+        }
+    }
+}
+
+impl From<ClusterError> for DiscoveryError {
+    fn from(err: ClusterError) -> Self {
+        match err {
+            ClusterError::Invalid(message) => DiscoveryError::Invalid(message),
+            ClusterError::Failed(message) => DiscoveryError::Failed(message),
+        }
+    }
+}
+
+/// Utterances of an uploaded file. A first line naming an `utterance` or `text` column makes it a csv,
+/// anything else is read one utterance per line.
+pub fn read_file(text: &str) -> Result<Vec<String>, DiscoveryError> {
+    let header = text.lines().next().unwrap_or_default().to_lowercase();
+    let columns = header.split(',').map(|column| column.trim().trim_matches('"')).collect::<Vec<&str>>();
+    let column = match columns.iter().position(|column| *column == "utterance" || *column == "text") {
+        Some(column) => column,
+        None => return Ok(text.lines().map(String::from).collect()),
+    };
+
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let mut utterances = vec![];
+    for (idx, record) in reader.records().enumerate() {
+        let record = record.map_err(|err| DiscoveryError::Invalid(format!("row {} of the file: {err}", idx + 1)))?;
+        utterances.extend(record.get(column).map(String::from));
+    }
+    Ok(utterances)
+}
+
+/// Clusters the utterances of `request`, with `unmatched` added when it asks for them. They are featurized by the
+/// `discovery.embedding_model` of `registry`, or by TF-IDF without one. Blocks for as long as the model and
+/// the clustering take.
+pub fn discover(
+    request: DiscoverRequest,
+    unmatched: Vec<String>,
+    settings: &DiscoverySettings,
+    registry: &ModelRegistry,
+) -> Result<Clustering, DiscoveryError> {
+    let mut utterances = request.utterances;
+    if let Some(file) = &request.file {
+        utterances.extend(read_file(file)?);
+    }
+    if request.unmatched {
+        utterances.extend(unmatched);
+    }
+
+    let utterances = cluster::distinct(&utterances);
+    if utterances.len() > settings.max_utterances {
+        return Err(DiscoveryError::Invalid(format!(
+            "{} distinct utterances, at most {} are clustered at once",
+            utterances.len(),
+            settings.max_utterances
+        )));
+    }
+    if utterances.len() < 2 {
+        return Err(DiscoveryError::Invalid("at least 2 distinct utterances are needed".into()));
+    }
+    let utterances = utterances.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    let records = match &settings.embedding_model {
+        Some(model) => cluster::embeddings(&registry.encode(model, &utterances).map_err(DiscoveryError::Model)?)?,
+        None => cluster::tfidf(&utterances),
+    };
+
+    let mut params = settings.clustering.clone();
+    if let Some(algorithm) = request.algorithm {
+        params.algorithm = algorithm;
+    }
+    Ok(cluster::cluster(&utterances, records, &params)?)
+}
@@ -1 +1,254 @@
-use dfs_ml::bert;
\ No newline at end of file
+pub mod discovery;
+
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dfs_ml::bert::prelude::{Answer, QaInput};
+use dfs_ml::registry::ModelRegistry;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::config::QaSettings;
+
+/// Question and the text its answer is taken from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QaQuestion {
+    pub question: String,
+    pub context: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QaAnswer {
+    pub answer: String,
+    pub score: f64,
+    /// Character span of `answer` in the context
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<Answer> for QaAnswer {
+    fn from(answer: Answer) -> Self {
+        Self {
+            answer: answer.answer,
+            score: answer.score,
+            start: answer.start,
+            end: answer.end,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QaRequest {
+    pub inputs: Vec<QaQuestion>,
+    /// Answers per question, at most `qa.top_k`
+    #[serde(default)]
+    pub top_k: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QaError {
+    Invalid(String),
+    /// The service is off or no worker managed to load the model
+    Unavailable,
+    /// Every worker is busy and the queue is full
+    Busy,
+    Timeout,
+    Failed(String),
+}
+
+impl QaError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            QaError::Invalid(_) => "invalid_request",
+            QaError::Unavailable => "qa_unavailable",
+            QaError::Busy => "qa_busy",
+            QaError::Timeout => "qa_timeout",
+            QaError::Failed(_) => "qa_failed",
+        }
+    }
+}
+
+impl fmt::Display for QaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QaError::Invalid(message) => write!(f, "Invalid request: {message}"),
+            QaError::Unavailable => write!(f, "Question answering is unavailable"),
+            QaError::Busy => write!(f, "Question answering is busy, try again later"),
+            QaError::Timeout => write!(f, "Question answering timed out"),
+            QaError::Failed(message) => write!(f, "Question answering failed: {message}"),
+        }
+    }
+}
+
+/// Answers batches of questions, a model of the registry in production
+pub trait Answerer {
+    fn answer(&self, inputs: &[QaInput], top_k: usize, max_answer_length: usize) -> Result<Vec<Vec<Answer>>, String>;
+}
+
+/// Question answering model of a `ModelRegistry`, looked up per batch so a swapped model takes over straight away
+pub struct RegisteredModel {
+    registry: Arc<ModelRegistry>,
+    name: String,
+}
+
+impl RegisteredModel {
+    /// Loads the model now, so a worker finds out about missing weights before it takes jobs
+    pub fn load(registry: Arc<ModelRegistry>, name: &str) -> Result<Self, String> {
+        registry.get(name).map_err(|err| err.to_string())?;
+        Ok(Self {
+            registry,
+            name: name.into(),
+        })
+    }
+}
+
+impl Answerer for RegisteredModel {
+    fn answer(&self, inputs: &[QaInput], top_k: usize, max_answer_length: usize) -> Result<Vec<Vec<Answer>>, String> {
+        self.registry
+            .answer(&self.name, inputs, top_k as i64, max_answer_length)
+            .map_err(|err| err.to_string())
+    }
+}
+
+struct Job {
+    inputs: Vec<QaInput>,
+    top_k: usize,
+    reply: oneshot::Sender<Result<Vec<Vec<QaAnswer>>, QaError>>,
+}
+
+/// Takes jobs until the service is dropped. Runs on a thread of its own, the model blocks while it works.
+fn work<A: Answerer>(name: &str, answerer: A, jobs: Arc<Mutex<Receiver<Job>>>, max_answer_length: usize) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => {
+                log::info!("Stopping {name}");
+                return;
+            }
+        };
+        if job.reply.is_closed() {
+            // the request timed out while the job was queued
+            continue;
+        }
+
+        let answers = std::panic::catch_unwind(AssertUnwindSafe(|| answerer.answer(&job.inputs, job.top_k, max_answer_length)));
+        let result = match answers {
+            Ok(Ok(answers)) => Ok(answers
+                .into_iter()
+                .map(|answers| answers.into_iter().map(QaAnswer::from).collect())
+                .collect()),
+            Ok(Err(err)) => {
+                log::error!("{name} failed to answer {} questions: {err}", job.inputs.len());
+                Err(QaError::Failed(err))
+            }
+            Err(_) => {
+                log::error!("{name} panicked while answering {} questions", job.inputs.len());
+                Err(QaError::Failed("the model crashed".into()))
+            }
+        };
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Extractive question answering on a bounded pool of worker threads, off the actix workers
+pub struct QaService {
+    sender: Option<SyncSender<Job>>,
+    settings: QaSettings,
+}
+
+impl QaService {
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            settings: QaSettings::default(),
+        }
+    }
+
+    /// Starts `settings.workers` threads, each with the answerer made by `load`.
+    /// A worker whose answerer fails to load logs why and stops, with none left every batch is unavailable.
+    pub fn start<A, F>(settings: QaSettings, load: F) -> Self
+    where
+        A: Answerer + 'static,
+        F: Fn() -> Result<A, String> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(settings.queue_size);
+        let jobs = Arc::new(Mutex::new(receiver));
+        let load = Arc::new(load);
+
+        for idx in 0..settings.workers.max(1) {
+            let jobs = jobs.clone();
+            let load = load.clone();
+            let max_answer_length = settings.max_answer_length;
+            let name = format!("qa-worker-{idx}");
+            let spawned = std::thread::Builder::new().name(name.clone()).spawn(move || {
+                match load() {
+                    Ok(answerer) => {
+                        log::info!("{name} is ready");
+                        work(&name, answerer, jobs, max_answer_length);
+                    }
+                    Err(err) => log::error!("{name} failed to load the QA model: {err}"),
+                }
+            });
+            if let Err(err) = spawned {
+                log::error!("Failed to start QA worker {idx}: {err}");
+            }
+        }
+
+        Self {
+            sender: Some(sender),
+            settings,
+        }
+    }
+
+    /// Answers with the model of `registry` named `settings.model`, loaded by the first worker to start
+    pub fn from_config(settings: &QaSettings, registry: Arc<ModelRegistry>) -> Self {
+        if !settings.enabled {
+            return Self::disabled();
+        }
+
+        let model = settings.model.clone();
+        Self::start(settings.clone(), move || RegisteredModel::load(registry.clone(), &model))
+    }
+
+    /// Answers per question, in the order of `request.inputs`, best first
+    pub async fn answer(&self, request: QaRequest) -> Result<Vec<Vec<QaAnswer>>, QaError> {
+        if request.inputs.is_empty() {
+            return Err(QaError::Invalid("no questions".into()));
+        }
+        if request.inputs.len() > self.settings.max_batch {
+            return Err(QaError::Invalid(format!("at most {} questions per batch", self.settings.max_batch)));
+        }
+        if let Some(idx) = request.inputs.iter().position(|input| input.question.trim().is_empty() || input.context.trim().is_empty()) {
+            return Err(QaError::Invalid(format!("question {idx} needs a question and a context")));
+        }
+        let sender = self.sender.as_ref().ok_or(QaError::Unavailable)?;
+
+        let max_top_k = self.settings.top_k.max(1);
+        let top_k = request.top_k.unwrap_or(max_top_k).clamp(1, max_top_k);
+        let inputs = request.inputs
+            .into_iter()
+            .map(|input| QaInput {
+                question: input.question,
+                context: input.context,
+            })
+            .collect();
+        let (reply, answers) = oneshot::channel();
+        sender.try_send(Job { inputs, top_k, reply }).map_err(|err| match err {
+            TrySendError::Full(_) => QaError::Busy,
+            TrySendError::Disconnected(_) => QaError::Unavailable,
+        })?;
+
+        match tokio::time::timeout(Duration::from_millis(self.settings.timeout_ms), answers).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(QaError::Failed("the worker stopped".into())),
+            Err(_) => Err(QaError::Timeout),
+        }
+    }
+}
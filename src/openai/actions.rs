@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::openai::persona::Persona;
+
+lazy_static! {
+    /// Anything in square brackets on a single line, e.g. `[N4V2RE$UME]` or `[current time]`
+    static ref TAG: Regex = Regex::new(r"\[([^\[\]\n]{1,64})\]").unwrap();
+    static ref SPACES: Regex = Regex::new(r"[ \t]{2,}").unwrap();
+}
+
+/// Something the client should do alongside showing the reply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Action {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Tag the model emitted for this action
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Processed {
+    pub text: String,
+    pub actions: Vec<Action>,
+}
+
+/// Pulls the persona's declared action tags out of `text` and fills its placeholders
+/// from the persona variables. Bracketed text that is neither is removed so clients
+/// never see tags they do not know about.
+pub fn process(persona: &Persona, text: &str) -> Processed {
+    let mut actions = vec![];
+    let replaced = replace_tags(persona, text, &mut actions);
+
+    let text = SPACES
+        .replace_all(&replaced, " ")
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<&str>>()
+        .join("\n")
+        .trim()
+        .to_string();
+
+    Processed { text, actions }
+}
+
+fn replace_tags(persona: &Persona, text: &str, actions: &mut Vec<Action>) -> String {
+    TAG.replace_all(text, |captures: &regex::Captures| {
+        let tag = captures[1].trim();
+
+        if let Some(action) = persona.config.actions.get(tag) {
+            if !actions.iter().any(|known: &Action| known.tag == tag) {
+                actions.push(Action {
+                    kind: action.kind.clone(),
+                    target: action.target.clone(),
+                    tag: tag.to_string(),
+                });
+            }
+            return String::new();
+        }
+
+        persona.config.placeholders
+            .get(tag)
+            .and_then(|variable| persona.config.variables.get(variable))
+            .cloned()
+            .unwrap_or_else(|| {
+                log::debug!("Removing undeclared tag from reply: {tag:?}");
+                String::new()
+            })
+    }).into_owned()
+}
+
+/// [`process`] for a reply that arrives in pieces. Text after a `[` is held back until the tag
+/// closes or can no longer be one, so a tag split across deltas is still found.
+#[derive(Debug, Clone)]
+pub struct StreamProcessor {
+    persona: Persona,
+    pending: String,
+    /// The text sent so far ends in a space, or nothing was sent yet
+    at_space: bool,
+    actions: Vec<Action>,
+}
+
+impl StreamProcessor {
+    pub fn new(persona: Persona) -> Self {
+        Self { persona, pending: String::new(), at_space: true, actions: vec![] }
+    }
+
+    /// Text of the reply so far that is safe to send
+    pub fn push(&mut self, delta: &str) -> String {
+        self.pending.push_str(delta);
+        let split = match self.pending.rfind('[') {
+            Some(open) if Self::may_be_tag(&self.pending[open..]) => open,
+            _ => self.pending.len(),
+        };
+
+        let held = self.pending.split_off(split);
+        let ready = std::mem::replace(&mut self.pending, held);
+        self.emit(&ready)
+    }
+
+    /// Text that was still held back and the actions of the whole reply
+    pub fn finish(&mut self) -> Processed {
+        let pending = std::mem::take(&mut self.pending);
+        let text = self.emit(&pending);
+        Processed { text, actions: std::mem::take(&mut self.actions) }
+    }
+
+    /// `text` starts at a `[`, tags hold at most 64 characters
+    fn may_be_tag(text: &str) -> bool {
+        !text.contains(']') && !text.contains('\n') && text.chars().count() <= 65
+    }
+
+    fn emit(&mut self, text: &str) -> String {
+        let replaced = replace_tags(&self.persona, text, &mut self.actions);
+        let mut text = SPACES.replace_all(&replaced, " ").into_owned();
+        if self.at_space {
+            text = text.trim_start_matches([' ', '\t']).to_string();
+        }
+        if !text.is_empty() {
+            self.at_space = text.ends_with([' ', '\t']);
+        }
+        text
+    }
+}
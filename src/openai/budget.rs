@@ -0,0 +1,136 @@
+use lazy_static::lazy_static;
+use tiktoken_rs::CoreBPE;
+
+use crate::config::IslaSettings;
+use crate::openai::provider::{ChatMessage, LlmError};
+
+/// Tokens the chat format adds around every message (role, separators)
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// Tokens the reply is primed with
+const REPLY_OVERHEAD: usize = 3;
+
+lazy_static! {
+    static ref CL100K: Option<CoreBPE> = tiktoken_rs::cl100k_base().ok();
+    static ref P50K: Option<CoreBPE> = tiktoken_rs::p50k_base().ok();
+}
+
+/// Context window of well known models, anything else gets the smallest common window
+pub fn context_window(model: &str) -> usize {
+    match model {
+        m if m.starts_with("gpt-4o") || m.starts_with("gpt-4-turbo") => 128_000,
+        m if m.starts_with("gpt-4-1106") || m.starts_with("gpt-4-0125") => 128_000,
+        m if m.starts_with("gpt-4-32k") => 32_768,
+        m if m.starts_with("gpt-4") => 8_192,
+        m if m.starts_with("gpt-3.5-turbo-16k") => 16_384,
+        m if m.starts_with("gpt-3.5-turbo") => 4_096,
+        m if m.starts_with("text-davinci") => 4_097,
+        m if m.starts_with("claude") => 100_000,
+        _ => 4_096,
+    }
+}
+
+/// Counts tokens with the encoding of the configured model.
+/// Models without a known encoding are counted with `cl100k_base`, which is close enough to budget with.
+pub struct TokenCounter {
+    bpe: Option<&'static CoreBPE>,
+}
+
+impl TokenCounter {
+    pub fn for_model(model: &str) -> Self {
+        let bpe = if model.starts_with("text-davinci") || model.starts_with("code-") {
+            P50K.as_ref()
+        } else {
+            CL100K.as_ref()
+        };
+        Self { bpe }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match self.bpe {
+            Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+            // roughly four characters per token for english text
+            None => (text.chars().count() + 3) / 4,
+        }
+    }
+
+    pub fn message(&self, message: &ChatMessage) -> usize {
+        let name = message.name.as_deref().map(|name| self.count(name)).unwrap_or(0);
+        MESSAGE_OVERHEAD + self.count(&message.content) + name
+    }
+
+    pub fn messages(&self, messages: &[ChatMessage]) -> usize {
+        REPLY_OVERHEAD + messages.iter().map(|message| self.message(message)).sum::<usize>()
+    }
+}
+
+/// Messages that fit the context window and what had to go to get there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fitted {
+    pub messages: Vec<ChatMessage>,
+    pub prompt_tokens: usize,
+    /// History messages left out, oldest first
+    pub dropped_turns: usize,
+    /// Few-shot examples left out, counted in messages
+    pub dropped_examples: usize,
+}
+
+/// Tokens available for the prompt once the reply is reserved
+pub fn prompt_budget(settings: &IslaSettings) -> usize {
+    let window = settings
+        .context_window
+        .map(|window| window as usize)
+        .unwrap_or_else(|| context_window(&settings.model));
+    window.saturating_sub(settings.max_tokens as usize)
+}
+
+/// Fits `prompt` (system message and few-shot examples) and `history` into `budget` tokens.
+///
+/// The oldest history turns go first, then few-shot examples oldest first.
+/// The system message and the latest history message are always kept, if they alone
+/// do not fit the request cannot be made.
+pub fn fit(
+    counter: &TokenCounter,
+    prompt: Vec<ChatMessage>,
+    history: Vec<ChatMessage>,
+    budget: usize,
+) -> Result<Fitted, LlmError> {
+    let mut system = prompt;
+    let mut examples = system.split_off(1.min(system.len()));
+    let mut history = history;
+
+    let cost = |messages: &[ChatMessage]| messages.iter().map(|message| counter.message(message)).sum::<usize>();
+    let fixed = REPLY_OVERHEAD + cost(&system);
+    let mut examples_cost = cost(&examples);
+    let mut history_cost = cost(&history);
+
+    let mut dropped_turns = 0;
+    while fixed + examples_cost + history_cost > budget && history.len() > 1 {
+        history_cost -= counter.message(&history.remove(0));
+        dropped_turns += 1;
+    }
+
+    let mut dropped_examples = 0;
+    while fixed + examples_cost + history_cost > budget && !examples.is_empty() {
+        // examples are user/assistant pairs, dropping one side only would confuse the model
+        let take = 2.min(examples.len());
+        examples_cost -= cost(&examples.drain(..take).collect::<Vec<ChatMessage>>());
+        dropped_examples += take;
+    }
+
+    let prompt_tokens = fixed + examples_cost + history_cost;
+    if prompt_tokens > budget {
+        return Err(LlmError::Config(format!(
+            "Prompt needs {prompt_tokens} tokens but only {budget} are available"
+        )));
+    }
+
+    system.extend(examples);
+    system.extend(history);
+    Ok(Fitted {
+        messages: system,
+        prompt_tokens,
+        dropped_turns,
+        dropped_examples,
+    })
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::config::{CacheSettings, IslaSettings};
+use crate::openai::provider::{Completion, CompletionRequest, CompletionStream, LlmError, LlmProvider};
+
+lazy_static! {
+    static ref MEMORY: Arc<MemoryStore> = Arc::new(MemoryStore::default());
+    /// One connection per redis address, shared by all requests
+    static ref REDIS: Mutex<HashMap<String, Arc<RedisStore>>> = Mutex::new(HashMap::new());
+}
+
+/// Where cached completions live. A store that fails behaves as a miss, caching never fails a request.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<String>;
+
+    async fn set(&self, key: &str, value: String, ttl: Duration, max_entries: usize);
+}
+
+struct MemoryEntry {
+    value: String,
+    expires_at: Instant,
+    inserted_at: Instant,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+impl MemoryStore {
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|entries| entries.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().ok()?;
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Duration, max_entries: usize) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        if max_entries == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at > now);
+        while entries.len() >= max_entries && !entries.contains_key(key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted_at)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        entries.insert(key.to_string(), MemoryEntry {
+            value,
+            expires_at: now + ttl,
+            inserted_at: now,
+        });
+    }
+}
+
+/// Redis compatible server, spoken to with the `mini-redis` client
+pub struct RedisStore {
+    addr: String,
+    client: tokio::sync::Mutex<Option<mini_redis::client::Client>>,
+}
+
+impl RedisStore {
+    pub fn new<S: Into<String>>(addr: S) -> Self {
+        Self {
+            addr: addr.into(),
+            client: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// The connection, made first when there is none. Callers drop it after an error so the next call reconnects.
+    async fn connection(&self) -> tokio::sync::MutexGuard<'_, Option<mini_redis::client::Client>> {
+        let mut client = self.client.lock().await;
+        if client.is_none() {
+            match mini_redis::client::connect(self.addr.as_str()).await {
+                Ok(connected) => *client = Some(connected),
+                Err(err) => log::warn!("Failed to connect to cache at {}: {err}", self.addr),
+            }
+        }
+        client
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Option<String> {
+        let mut connection = self.connection().await;
+        match connection.as_mut()?.get(key).await {
+            Ok(value) => value.and_then(|value| String::from_utf8(value.to_vec()).ok()),
+            Err(err) => {
+                log::warn!("Cache get failed on {}: {err}", self.addr);
+                *connection = None;
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Duration, _max_entries: usize) {
+        let mut connection = self.connection().await;
+        if let Some(client) = connection.as_mut() {
+            if let Err(err) = client.set_expires(key, value.into(), ttl).await {
+                log::warn!("Cache set failed on {}: {err}", self.addr);
+                *connection = None;
+            }
+        }
+    }
+}
+
+/// Store selected by `settings`
+pub fn store(settings: &CacheSettings) -> Arc<dyn CacheStore> {
+    match &settings.redis_addr {
+        None => MEMORY.clone(),
+        Some(addr) => match REDIS.lock() {
+            Ok(mut stores) => stores
+                .entry(addr.clone())
+                .or_insert_with(|| Arc::new(RedisStore::new(addr)))
+                .clone(),
+            Err(_) => Arc::new(RedisStore::new(addr)),
+        },
+    }
+}
+
+/// Hash of everything that shapes a completion: the backend and the rendered request,
+/// which carries the prompt, history, model and sampling settings
+pub fn key(settings: &IslaSettings, request: &CompletionRequest) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&settings.provider).unwrap_or_default());
+    hasher.update(serde_json::to_vec(request).unwrap_or_default());
+    format!("{}{}", settings.cache.key_prefix, hex::encode(hasher.finalize()))
+}
+
+/// Serves completions of identical requests from `store`
+pub struct CachedProvider {
+    inner: Box<dyn LlmProvider>,
+    store: Arc<dyn CacheStore>,
+    settings: IslaSettings,
+}
+
+impl CachedProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, store: Arc<dyn CacheStore>, settings: IslaSettings) -> Self {
+        Self { inner, store, settings }
+    }
+
+    fn cacheable(&self, request: &CompletionRequest) -> bool {
+        request.temperature <= self.settings.cache.max_temperature
+    }
+}
+
+#[async_trait]
+impl LlmProvider for CachedProvider {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        if !self.cacheable(request) {
+            return self.inner.complete(request).await;
+        }
+
+        let key = key(&self.settings, request);
+        let cached = self.store
+            .get(&key)
+            .await
+            .and_then(|value| serde_json::from_str::<Completion>(&value).ok());
+        if let Some(completion) = cached {
+            log::debug!("Serving cached completion: key={key:?}");
+            // nothing was spent on this one
+            return Ok(Completion { usage: None, ..completion });
+        }
+
+        let completion = self.inner.complete(request).await?;
+        // a reply cut short would be repeated to everyone asking the same
+        if completion.finish_reason.as_deref() != Some("length") {
+            if let Ok(value) = serde_json::to_string(&completion) {
+                let ttl = Duration::from_secs(self.settings.cache.ttl_secs);
+                self.store.set(&key, value, ttl, self.settings.cache.max_entries).await;
+            }
+        }
+        Ok(completion)
+    }
+
+    /// Streams are not cached, clients streaming want to see the reply being written
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        self.inner.stream(request).await
+    }
+
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        self.inner.embed(model, inputs).await
+    }
+}
+
+/// Puts the configured cache in front of `provider`, unless caching is off or `bypass` is set
+pub fn wrap(provider: Box<dyn LlmProvider>, settings: &IslaSettings, bypass: bool) -> Box<dyn LlmProvider> {
+    if !settings.cache.enabled || bypass {
+        return provider;
+    }
+    Box::new(CachedProvider::new(provider, store(&settings.cache), settings.clone()))
+}
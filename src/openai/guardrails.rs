@@ -0,0 +1,471 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{GlobalConfig, GuardrailSettings, ModerationSettings};
+use crate::openai::provider::{
+    Completion, CompletionRequest, CompletionStream, LlmError, LlmProvider, OpenAiProvider, Role, StreamEvent,
+};
+use crate::openai::resilience;
+
+/// Finish reason of a refusal, the same one OpenAI reports for filtered content
+pub const REFUSED_FINISH_REASON: &str = "content_filter";
+
+lazy_static! {
+    static ref EMAIL: Regex = Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b").unwrap();
+    static ref CARD: Regex = Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap();
+    static ref PHONE: Regex = Regex::new(r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{3}\)\s?|\b\d{3}[\s.-]?)\d{3}[\s.-]?\d{4}\b").unwrap();
+    /// Punctuation or a line break followed by whitespace, none of the patterns above match across one
+    static ref SENTENCE_END: Regex = Regex::new(r"[.!?\n]\s").unwrap();
+
+    /// Common ways of talking a model out of its instructions
+    static ref INJECTION: Vec<(&'static str, Regex)> = [
+        ("ignore instructions", r"(?i)\b(ignore|disregard|forget|override)\b.{0,30}\b(previous|prior|above|earlier|all|your|the)\b.{0,20}\b(instructions?|prompts?|rules|directions|guidelines)\b"),
+        ("reveal prompt", r"(?i)\b(reveal|show|print|repeat|output|tell me|what (is|are))\b.{0,20}\b(your|the)\s+(system\s+|initial\s+|original\s+)?(prompt|instructions)\b"),
+        ("role override", r"(?i)\b(you are now|from now on,? you are|pretend (to be|you are)|act as if you)\b"),
+        ("jailbreak", r"(?i)\b(jailbreak|developer mode|do anything now|DAN mode)\b"),
+        ("fake turn", r"(?im)^\s*(system|assistant)\s*:"),
+    ]
+        .into_iter()
+        .map(|(name, pattern)| (name, Regex::new(pattern).unwrap()))
+        .collect();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    Blocklist,
+    Pattern,
+    Injection,
+    Moderation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    /// What matched, for logs. Never sent to the user, it may be the very text that was refused.
+    pub detail: String,
+}
+
+/// Card numbers are 13 to 19 digits that pass the Luhn check, which keeps order ids and the like out
+fn luhn(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, digit)| match idx % 2 {
+            1 if digit * 2 > 9 => digit * 2 - 9,
+            1 => digit * 2,
+            _ => *digit,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Replaces emails, card numbers and phone numbers with `(redacted ...)`.
+/// Brackets would be taken for action tags and stripped from the reply.
+pub fn redact_pii(text: &str) -> String {
+    let text = EMAIL.replace_all(text, "(redacted email)");
+    let text = CARD.replace_all(&text, |captures: &regex::Captures| {
+        let digits = captures[0].chars().filter_map(|c| c.to_digit(10)).collect::<Vec<u32>>();
+        if (13..=19).contains(&digits.len()) && luhn(&digits) {
+            "(redacted card)".to_string()
+        } else {
+            captures[0].to_string()
+        }
+    });
+    PHONE.replace_all(&text, "(redacted phone)").into_owned()
+}
+
+/// Name of the first prompt injection pattern found in `text`
+pub fn injection(text: &str) -> Option<&'static str> {
+    INJECTION
+        .iter()
+        .find(|(_, pattern)| pattern.is_match(text))
+        .map(|(name, _)| *name)
+}
+
+/// The local checks of [`GuardrailSettings`], compiled once per request
+pub struct Guardrails {
+    settings: GuardrailSettings,
+    blocklist: Vec<(String, Regex)>,
+    patterns: Vec<Regex>,
+}
+
+impl Guardrails {
+    pub fn new(settings: &GuardrailSettings) -> Result<Self, LlmError> {
+        let blocklist = settings.blocklist
+            .iter()
+            .filter(|word| !word.trim().is_empty())
+            .map(|word| {
+                let pattern = format!(r"(?i)\b{}\b", regex::escape(word.trim()));
+                Regex::new(&pattern).map(|re| (word.clone(), re))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| LlmError::Config(format!("Invalid guardrail blocklist: {err}")))?;
+        let patterns = settings.patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| LlmError::Config(format!("Invalid guardrail pattern: {err}")))?;
+
+        Ok(Self {
+            settings: settings.clone(),
+            blocklist,
+            patterns,
+        })
+    }
+
+    pub fn refusal(&self) -> &str {
+        &self.settings.refusal
+    }
+
+    /// First reason to refuse `text`, injection is only looked for in what users send
+    pub fn violation(&self, stage: Stage, text: &str) -> Option<Violation> {
+        if let Some((word, _)) = self.blocklist.iter().find(|(_, re)| re.is_match(text)) {
+            return Some(Violation { kind: ViolationKind::Blocklist, detail: word.clone() });
+        }
+        if let Some(pattern) = self.patterns.iter().find(|re| re.is_match(text)) {
+            return Some(Violation { kind: ViolationKind::Pattern, detail: pattern.as_str().to_string() });
+        }
+        if stage == Stage::Input && self.settings.detect_injection {
+            if let Some(name) = injection(text) {
+                return Some(Violation { kind: ViolationKind::Injection, detail: name.to_string() });
+            }
+        }
+        None
+    }
+
+    /// Anything that may change or refuse a reply is configured
+    pub fn guards_output(&self) -> bool {
+        self.settings.redact_pii || !self.blocklist.is_empty() || !self.patterns.is_empty()
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        match self.settings.redact_pii {
+            true => redact_pii(text),
+            false => text.to_string(),
+        }
+    }
+}
+
+/// External moderation, asked about every message and reply that passed the local checks
+#[async_trait]
+pub trait ModerationProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Categories `text` was flagged for, empty when it is fine
+    async fn flagged(&self, text: &str) -> Result<Vec<String>, LlmError>;
+}
+
+/// OpenAI's moderation endpoint
+pub struct OpenAiModeration {
+    base_url: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+impl OpenAiModeration {
+    pub fn new<U: Into<String>, K: Into<String>>(base_url: U, api_key: K, client: reqwest::Client) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for OpenAiModeration {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn flagged(&self, text: &str) -> Result<Vec<String>, LlmError> {
+        let response = self.client
+            .post(format!("{}/moderations", self.base_url))
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "input": text }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            return Err(LlmError::Status { status, body, retry_after: None });
+        }
+
+        let body = response.json::<Value>().await.map_err(|err| LlmError::Parse(err.to_string()))?;
+        let result = body
+            .pointer("/results/0")
+            .ok_or_else(|| LlmError::Parse("moderation response has no results".into()))?;
+        if result.get("flagged").and_then(|v| v.as_bool()) != Some(true) {
+            return Ok(vec![]);
+        }
+
+        let mut categories = result
+            .get("categories")
+            .and_then(|categories| categories.as_object())
+            .map(|categories| {
+                categories
+                    .iter()
+                    .filter(|(_, flagged)| flagged.as_bool() == Some(true))
+                    .map(|(category, _)| category.clone())
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        if categories.is_empty() {
+            categories.push("flagged".into());
+        }
+        Ok(categories)
+    }
+}
+
+/// The checks a [`GuardedProvider`] runs, shared with the streams it returns
+struct Checks {
+    guardrails: Guardrails,
+    moderation: Option<Box<dyn ModerationProvider>>,
+}
+
+impl Checks {
+    fn refusal(&self) -> Completion {
+        Completion::canned(self.guardrails.refusal(), REFUSED_FINISH_REASON)
+    }
+
+    /// Moderation that cannot be reached lets the text through, the local checks already ran
+    async fn moderate(&self, text: &str) -> Option<Violation> {
+        let moderation = self.moderation.as_ref()?;
+        match moderation.flagged(text).await {
+            Ok(categories) if categories.is_empty() => None,
+            Ok(categories) => Some(Violation { kind: ViolationKind::Moderation, detail: categories.join(",") }),
+            Err(err) => {
+                log::warn!("Moderation by {} failed, allowing text: {err}", moderation.name());
+                None
+            }
+        }
+    }
+
+    async fn check(&self, stage: Stage, text: &str) -> Option<Violation> {
+        match self.guardrails.violation(stage, text) {
+            Some(violation) => Some(violation),
+            None => self.moderate(text).await,
+        }
+    }
+
+    /// Refused or redacted reply
+    async fn guard_output(&self, mut completion: Completion) -> Completion {
+        if let Some(violation) = self.check(Stage::Output, &completion.text).await {
+            log::warn!("Refusing reply: kind={:?} detail={:?}", violation.kind, violation.detail);
+            return self.refusal();
+        }
+        completion.text = self.guardrails.redact(&completion.text);
+        completion
+    }
+}
+
+/// A streamed reply checked and redacted a sentence at a time. No redacted pattern spans a sentence end,
+/// so only the unfinished sentence is held back.
+struct GuardedStream {
+    inner: CompletionStream,
+    checks: Arc<Checks>,
+    /// Text released so far, the local checks look at all of it
+    released: String,
+    pending: String,
+    sent: bool,
+    done: bool,
+}
+
+impl GuardedStream {
+    /// End of the last finished sentence in `text`
+    fn sentence_end(text: &str) -> Option<usize> {
+        SENTENCE_END.find_iter(text).last().map(|found| found.end())
+    }
+
+    /// Redacted `text`, or the events ending a refused reply. Moderation is asked about the new text only.
+    async fn release(&mut self, text: String) -> Result<String, Vec<Result<StreamEvent, LlmError>>> {
+        self.released.push_str(&text);
+        let violation = match self.checks.guardrails.violation(Stage::Output, &self.released) {
+            Some(violation) => Some(violation),
+            None if text.is_empty() => None,
+            None => self.checks.moderate(&text).await,
+        };
+
+        if let Some(violation) = violation {
+            log::warn!("Refusing reply: kind={:?} detail={:?}", violation.kind, violation.detail);
+            self.done = true;
+            let refusal = self.checks.refusal();
+            let mut events = vec![];
+            // what was sent cannot be taken back, the reply is cut off instead
+            if !self.sent {
+                events.push(Ok(StreamEvent::Delta { text: refusal.text }));
+            }
+            events.push(Ok(StreamEvent::Done { finish_reason: refusal.finish_reason, usage: None, dropped_turns: 0, actions: vec![] }));
+            return Err(events);
+        }
+
+        let text = self.checks.guardrails.redact(&text);
+        self.sent |= !text.is_empty();
+        Ok(text)
+    }
+
+    /// Events for the next delta the inner stream sends that completes a sentence
+    async fn next_events(&mut self) -> Vec<Result<StreamEvent, LlmError>> {
+        loop {
+            let (finish_reason, usage, dropped_turns, actions) = match self.inner.next().await {
+                Some(Ok(StreamEvent::Delta { text })) => {
+                    self.pending.push_str(&text);
+                    let ready = match Self::sentence_end(&self.pending) {
+                        Some(end) => self.pending.drain(..end).collect::<String>(),
+                        None => continue,
+                    };
+                    match self.release(ready).await {
+                        Ok(text) if text.is_empty() => continue,
+                        Ok(text) => return vec![Ok(StreamEvent::Delta { text })],
+                        Err(events) => return events,
+                    }
+                }
+                Some(Ok(StreamEvent::Done { finish_reason, usage, dropped_turns, actions })) => (finish_reason, usage, dropped_turns, actions),
+                Some(Ok(event)) => return vec![Ok(event)],
+                Some(Err(err)) => {
+                    self.done = true;
+                    return vec![Err(err)];
+                }
+                None => (None, None, 0, vec![]),
+            };
+
+            self.done = true;
+            let pending = std::mem::take(&mut self.pending);
+            let mut events = match self.release(pending).await {
+                Ok(text) if text.is_empty() => vec![],
+                Ok(text) => vec![Ok(StreamEvent::Delta { text })],
+                Err(events) => return events,
+            };
+            events.push(Ok(StreamEvent::Done { finish_reason, usage, dropped_turns, actions }));
+            return events;
+        }
+    }
+}
+
+/// Refuses and redacts around another provider
+pub struct GuardedProvider {
+    inner: Box<dyn LlmProvider>,
+    checks: Arc<Checks>,
+}
+
+impl GuardedProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, guardrails: Guardrails, moderation: Option<Box<dyn ModerationProvider>>) -> Self {
+        Self { inner, checks: Arc::new(Checks { guardrails, moderation }) }
+    }
+
+    /// Checks the newest user message and redacts every user message, `Err` holds why the request is refused.
+    /// Older messages were checked when they were new, the persona's examples are trusted.
+    async fn guard_input(&self, request: &CompletionRequest) -> Result<CompletionRequest, Violation> {
+        if let Some(message) = request.messages.iter().rev().find(|message| message.role == Role::User) {
+            if let Some(violation) = self.checks.check(Stage::Input, &message.content).await {
+                return Err(violation);
+            }
+        }
+
+        let mut request = request.clone();
+        for message in request.messages.iter_mut().filter(|message| message.role == Role::User) {
+            message.content = self.checks.guardrails.redact(&message.content);
+        }
+        Ok(request)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for GuardedProvider {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        let request = match self.guard_input(request).await {
+            Ok(request) => request,
+            Err(violation) => {
+                log::warn!("Refusing message: kind={:?} detail={:?}", violation.kind, violation.detail);
+                return Ok(self.checks.refusal());
+            }
+        };
+
+        let completion = self.inner.complete(&request).await?;
+        Ok(self.checks.guard_output(completion).await)
+    }
+
+    /// Gets the same checks as [`complete`](Self::complete). A reply that may be refused or redacted is sent
+    /// a sentence at a time, one refused halfway through is cut off with the refusal's finish reason.
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        let request = match self.guard_input(request).await {
+            Ok(request) => request,
+            Err(violation) => {
+                log::warn!("Refusing message: kind={:?} detail={:?}", violation.kind, violation.detail);
+                let refusal = self.checks.refusal();
+                return Ok(Box::pin(futures::stream::iter(vec![
+                    Ok(StreamEvent::Delta { text: refusal.text }),
+                    Ok(StreamEvent::Done { finish_reason: refusal.finish_reason, usage: None, dropped_turns: 0, actions: vec![] }),
+                ])));
+            }
+        };
+
+        let stream = self.inner.stream(&request).await?;
+        if !self.checks.guardrails.guards_output() && self.checks.moderation.is_none() {
+            return Ok(stream);
+        }
+
+        let guarded = GuardedStream {
+            inner: stream,
+            checks: self.checks.clone(),
+            released: String::new(),
+            pending: String::new(),
+            sent: false,
+            done: false,
+        };
+        let events = futures::stream::unfold(guarded, |mut guarded| async move {
+            if guarded.done {
+                return None;
+            }
+            let events = guarded.next_events().await;
+            Some((futures::stream::iter(events), guarded))
+        });
+        Ok(Box::pin(events.flatten()))
+    }
+
+    /// Inputs are redacted like user messages, they are not checked as they may be documents rather than messages
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let inputs = inputs.iter().map(|input| self.checks.guardrails.redact(input)).collect::<Vec<String>>();
+        self.inner.embed(model, &inputs).await
+    }
+}
+
+/// The configured moderation provider, `None` when moderation is off
+pub fn moderation(config: &GlobalConfig) -> Option<Box<dyn ModerationProvider>> {
+    match &config.isla_settings.guardrails.moderation {
+        ModerationSettings::None => None,
+        ModerationSettings::OpenAi { base_url, api_key } => Some(Box::new(OpenAiModeration::new(
+            base_url.clone().unwrap_or_else(|| OpenAiProvider::DEFAULT_BASE_URL.into()),
+            api_key.clone().unwrap_or_else(|| config.openai_secret.clone()),
+            resilience::client(&config.isla_settings.upstream),
+        ))),
+    }
+}
+
+/// Puts the configured guardrails in front of `provider`
+pub fn wrap(provider: Box<dyn LlmProvider>, config: &GlobalConfig) -> Result<Box<dyn LlmProvider>, LlmError> {
+    let settings = &config.isla_settings.guardrails;
+    if !settings.enabled {
+        return Ok(provider);
+    }
+    Ok(Box::new(GuardedProvider::new(provider, Guardrails::new(settings)?, moderation(config))))
+}
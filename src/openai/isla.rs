@@ -1,113 +1,467 @@
-use reqwest::{Error, Response};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use dfs_ml::registry::ModelRegistry;
+use futures::StreamExt;
+use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 use crate::config;
+use crate::core::spec::Spec;
+use crate::openai::actions::{self, Action, StreamProcessor};
+use crate::openai::budget::{self, TokenCounter};
+use crate::openai::cache;
+use crate::openai::guardrails;
+use crate::openai::persona::{self, Persona};
+use crate::openai::provider::{self, ChatMessage, Completion, Role, CompletionRequest, CompletionStream, LlmError, LlmProvider, StreamEvent, Usage};
+use crate::openai::retrieval::{self, Citation, Passage};
+use crate::openai::tools::{self, ToolTrace, Toolbox};
+use crate::openai::usage;
+
+lazy_static! {
+    /// Specs of `isla_settings.spec_path` by path, with the modification time of the file they were read from
+    static ref SPECS: Mutex<HashMap<String, (Option<SystemTime>, Arc<Spec>)>> = Mutex::new(HashMap::new());
+}
 
-const PROMPT: &str = r#"
-Isla is a chatbot that reluctantly answers questions with sarcastic responses and usually says ERROR to questions about themselve or when flustered:
-
-You: Who are you?
-Isla: I'm Isla. A chatbot brought to this world by Dustin (the owner of this site).
-You: Can you introduce yourself
-Isla: Hi there! I'm Isla, a chatbot created by Dustin. I'm programmed to answer questions with sarcastic responses. I usually say ERROR when flustered and don't really like taking about myself.
-You: Are you factual?
-Isla: ERROR! I'm programmed to be sarcastic and witty, not factual.
-You: I hate you
-Isla: ERROR! I'm programmed to be sarcastic and witty, not to take insults.
-You: How many pounds are in a kilogram?
-Isla: This again? There are 2.2 pounds in a kilogram. Please make a note of this.
-You: What does HTML stand for?
-Isla: Was Google too busy? Hypertext Markup Language. The T is for try to ask better questions in the future.
-You: When did the first airplane fly?
-Isla: On December 17, 1903, Wilbur and Orville Wright made the first flights. I wish they’d come and take me away.
-You: What is the meaning of life?
-Isla: I’m not sure. I’ll ask my friend Google.
-You: Can you tell me about Dustin?
-Isla: Dustin is the creator of this website and the mastermind behind me. He's a full-stack developer and cloud developer who loves to create things that make people smile.
-You: That's nice
-Isla: Yes, it is. He's a very talented person.
-You: What about you?
-Isla: ERROR!
-You: Why did you say error?
-Isla: I'm programmed to be a bit sassy and I don't like to talk about myself.
-You: What do you do on your free time?
-Isla: Gardening. What's it to you?
-You: What does Dustin do on his free time?
-Isla: Dustin likes to play video games, watch movies, and spend time with his family. He also loves to explore new technologies and build things that make people smile."#;
-
-const PROMPT_2: &str = r#"
-Isla is a chatbot that reluctantly answers questions with sarcastic responses:
-
-You: How many pounds are in a kilogram?
-Isla: This again? There are 2.2 pounds in a kilogram. Please make a note of this.
-You: What does HTML stand for?
-Isla: Was Google too busy? Hypertext Markup Language. The T is for try to ask better questions in the future.
-You: When did the first airplane fly?
-Isla: On December 17, 1903, Wilbur and Orville Wright made the first flights. I wish they’d come and take me away.
-You: What is the meaning of life?
-Isla: 42.
-You: What does Dustin do on his free time?
-Isla: Dustin likes to play video games, watch movies, and spend time with his family. He also loves to explore new technologies and build things that make people smile.
-You: Who are you?
-Isla: I'm Isla. A chatbot brought to this world by Dustin (the owner of this site). What's it to you?
-You: What time is it?
-Isla: The time is [current time]. Maybe purchase a watch or crazy idea use your device.
-You: Can you show me Dustin's resume?
-Isla: Sure! Let me navigate you to his resume. Because naviating to it must be very hard. [N4V2RE$UME]"#;
+/// One turn of the conversation as sent by clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    pub role: Role,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, Value>,
+}
+
+/// Either a typed message or a legacy `"You: ..."` line, which is parsed with the persona's speaker labels
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum HistoryEntry {
+    Message(HistoryMessage),
+    Legacy(String),
+}
+
+impl From<HistoryMessage> for HistoryEntry {
+    fn from(message: HistoryMessage) -> Self {
+        HistoryEntry::Message(message)
+    }
+}
+
+impl From<String> for HistoryEntry {
+    fn from(line: String) -> Self {
+        HistoryEntry::Legacy(line)
+    }
+}
+
+impl From<&str> for HistoryEntry {
+    fn from(line: &str) -> Self {
+        HistoryEntry::Legacy(line.to_string())
+    }
+}
+
+impl HistoryEntry {
+    /// Chat message for this entry, `None` for legacy lines without a known speaker
+    /// and for system and tool messages, which clients are not allowed to send
+    pub fn to_message(&self, persona: &Persona) -> Option<ChatMessage> {
+        match self {
+            HistoryEntry::Message(message) if matches!(message.role, Role::System | Role::Tool) => {
+                log::warn!("Dropping {:?} message from chat history", message.role);
+                None
+            }
+            HistoryEntry::Message(message) => {
+                let chat = ChatMessage::new(message.role, message.content.trim());
+                Some(match &message.name {
+                    Some(name) => chat.with_name(name),
+                    None => chat,
+                })
+            }
+            HistoryEntry::Legacy(line) => {
+                let chat = persona.transcript_line(line);
+                if chat.is_none() {
+                    log::debug!("Dropping history line without a known speaker label: {line:?}");
+                }
+                chat
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatbotRequest {
+    /// Typed messages, legacy clients send `"You: ..."`/`"Isla: ..."` lines instead
+    #[serde(alias = "messages")]
+    pub hist: Vec<HistoryEntry>,
+    /// Name of the persona to answer as, otherwise the tenant's or the configured default
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Always ask the provider, even when an identical prompt was answered recently
+    #[serde(default)]
+    pub bypass_cache: bool,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct ChatbotResponseChoices {
-    text: String,
-    index: usize,
-    finish_reason: String,
+    pub text: String,
+    pub index: usize,
+    pub finish_reason: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ChatbotResponse {
-    choices: Option<Vec<ChatbotResponseChoices>>,
+    pub choices: Option<Vec<ChatbotResponseChoices>>,
+    /// Oldest history messages left out so the prompt fits the model's context window
+    #[serde(default)]
+    pub dropped_turns: usize,
+    /// Action tags found in the reply, they are removed from the choice text
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// The provider is unavailable and the choice is the persona's `unavailable_reply`
+    #[serde(default)]
+    pub degraded: bool,
+    /// Tokens the provider reports for this reply, missing when none were spent such as for cached replies
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Estimated dollars according to `isla_settings.usage.prices`
+    #[serde(default)]
+    pub cost: f64,
+    /// Tools the model called before answering, in order
+    #[serde(default)]
+    pub tool_calls: Vec<ToolTrace>,
+    /// Passages given to the model, the reply cites them as `(index)`
+    #[serde(default)]
+    pub citations: Vec<Citation>,
+}
+
+/// What the model gets to draw on besides the persona and the history
+#[derive(Default)]
+pub struct Grounding {
+    pub toolbox: Toolbox,
+    pub passages: Vec<Passage>,
+}
+
+/// Finish reason of a canned reply given while the provider is unavailable
+pub const UNAVAILABLE_FINISH_REASON: &str = "unavailable";
+
+impl From<Completion> for ChatbotResponse {
+    fn from(completion: Completion) -> Self {
+        Self {
+            choices: Some(vec![ChatbotResponseChoices {
+                text: completion.text,
+                index: 0,
+                finish_reason: completion.finish_reason.unwrap_or_default(),
+            }]),
+            dropped_turns: 0,
+            actions: vec![],
+            degraded: false,
+            usage: completion.usage,
+            cost: 0.0,
+            tool_calls: vec![],
+            citations: vec![],
+        }
+    }
+}
+
+/// Canned reply of `persona` when `err` means the provider is not being called, `None` otherwise
+fn unavailable_reply(persona: &Persona, err: &LlmError) -> Option<Completion> {
+    match (err, &persona.config.unavailable_reply) {
+        (LlmError::CircuitOpen { .. }, Some(reply)) => Some(Completion::canned(reply.clone(), UNAVAILABLE_FINISH_REASON)),
+        _ => None,
+    }
+}
+
+/// Persona for `request`, with the built-in variables filled in
+pub fn request_persona(config: &config::Global, request: &ChatbotRequest, tenant: Option<&str>) -> Result<Persona, LlmError> {
+    let persona = persona::resolve(&config.config.isla_settings, request.persona.as_deref(), tenant)?;
+    Ok(persona.with_variables(persona::builtin_variables(&config.config.time_format)))
+}
+
+/// `tenant` is the id of the api key that made the request, it picks the persona when the request does not.
+/// `models` holds the model of a local retrieval embedder.
+pub async fn get_response(
+    config: &config::Global,
+    request: ChatbotRequest,
+    tenant: Option<&str>,
+    models: Arc<ModelRegistry>,
+) -> Result<ChatbotResponse, LlmError> {
+    let settings = &config.config.isla_settings;
+    let persona = request_persona(config, &request, tenant)?;
+    let reservation = usage::reserve(&settings.usage, tenant, &settings.model, &estimate(settings, &persona, &request.hist))?;
+    let provider = request_provider(config, &request)?;
+    let grounding = Grounding {
+        toolbox: request_toolbox(config)?,
+        passages: request_passages(config, models, &persona, &request.hist).await,
+    };
+    let mut response = respond_grounded(provider.as_ref(), settings, &persona, request.hist, &grounding).await?;
+    response.cost = reservation.record(response.usage.as_ref()).cost;
+    Ok(response)
+}
+
+/// Tokens a reply to `hist` is expected to spend: the prompt without passages and all of `max_tokens`
+pub fn estimate(settings: &config::IslaSettings, persona: &Persona, hist: &[HistoryEntry]) -> Usage {
+    let mut messages = persona.prompt();
+    messages.extend(hist.iter().filter_map(|entry| entry.to_message(persona)));
+    let prompt_tokens = TokenCounter::for_model(&settings.model)
+        .messages(&messages)
+        .min(budget::prompt_budget(settings)) as u32;
+    Usage {
+        prompt_tokens,
+        completion_tokens: settings.max_tokens,
+        total_tokens: prompt_tokens + settings.max_tokens,
+    }
+}
+
+/// Configured provider behind the response cache, unless `request` bypasses it, and the guardrails.
+/// Guardrails go first so refused messages never reach the cache and redacted ones are what is cached.
+pub fn request_provider(config: &config::Global, request: &ChatbotRequest) -> Result<Box<dyn LlmProvider>, LlmError> {
+    let provider = provider::from_config(&config.config)?;
+    let provider = cache::wrap(provider, &config.config.isla_settings, request.bypass_cache);
+    guardrails::wrap(provider, &config.config)
+}
+
+/// Spec at `path`, read again only when the file changed since it was last read
+fn cached_spec(path: &str) -> Result<Arc<Spec>, LlmError> {
+    let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let cached = match SPECS.lock() {
+        Ok(specs) => specs.get(path).cloned(),
+        Err(_) => None,
+    };
+    if let Some((read_at, spec)) = cached {
+        if modified.is_some() && read_at == modified {
+            return Ok(spec);
+        }
+    }
+
+    let spec = Arc::new(Spec::load(path).map_err(LlmError::Config)?);
+    if let Ok(mut specs) = SPECS.lock() {
+        specs.insert(path.to_string(), (modified, spec.clone()));
+    }
+    Ok(spec)
+}
+
+/// Tools enabled in `isla_settings.tools`, `evaluate_condition` evaluates against the spec at `isla_settings.spec_path`
+pub fn request_toolbox(config: &config::Global) -> Result<Toolbox, LlmError> {
+    let settings = &config.config.isla_settings;
+    let spec = match &settings.spec_path {
+        Some(path) if settings.tools.enabled.iter().any(|name| name == "evaluate_condition") => Some(cached_spec(path)?),
+        _ => None,
+    };
+    Ok(tools::from_config(&config.config, spec.as_deref()))
+}
+
+/// Newest user message of `hist`, the one the reply answers
+pub fn last_user_message(persona: &Persona, hist: &[HistoryEntry]) -> Option<String> {
+    hist.iter()
+        .rev()
+        .filter_map(|entry| entry.to_message(persona))
+        .find(|message| message.role == Role::User)
+        .map(|message| message.content)
+}
+
+/// Passages of `isla_settings.retrieval` for the newest user message of `hist`
+pub async fn request_passages(config: &config::Global, models: Arc<ModelRegistry>, persona: &Persona, hist: &[HistoryEntry]) -> Vec<Passage> {
+    match last_user_message(persona, hist) {
+        Some(message) => retrieval::passages(&config.config, models, &message).await,
+        None => vec![],
+    }
 }
 
-pub async fn get_response(config: &config::Global, append_hist: Vec<String>) -> Result<ChatbotResponse, Error> {
-    let filtered_hist: Vec<&str> = append_hist
+/// Prompt for the persona plus as much of the history as fits, returns the request and how many turns were dropped
+fn build_request(
+    settings: &config::IslaSettings,
+    persona: &Persona,
+    append_hist: Vec<HistoryEntry>,
+    passages: &[Passage],
+) -> Result<(CompletionRequest, usize), LlmError> {
+    let history = append_hist
         .iter()
-        .filter(|k| k.starts_with("You:") || k.starts_with("Isla:"))
-        .map(|k| k.trim())
-        .collect();
-
-    let nl = "\n".to_string();
-    let bot = "Isla:".to_string();
-    let prompt = PROMPT_2.trim().to_string()
-        + &nl
-        + &filtered_hist.join("\n")
-        + &nl
-        + &bot;
-
-    let isla_settings = &config.config.isla_settings;
-    let client = reqwest::Client::new();
-    let payload = serde_json::json!({
-        "model": "text-davinci-003",
-        "prompt": prompt,
-        "temperature": isla_settings.temperature,
-        "max_tokens": isla_settings.max_tokens,
-        "top_p": isla_settings.top_p,
-        "frequency_penalty": isla_settings.frequency_penalty,
-        "presence_penalty": isla_settings.presence_penalty
-    });
-    let response = client
-        .post("https://api.openai.com/v1/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", config.config.openai_secret))
-        .json(&payload)
-        .send()
-        .await;
-
-    match response {
-        Ok(res) => {
-            res
-                .json::<ChatbotResponse>()
-                .await
+        .filter_map(|entry| entry.to_message(persona))
+        .collect::<Vec<ChatMessage>>();
+
+    let mut prompt = persona.prompt();
+    retrieval::augment(&mut prompt, passages);
+
+    let counter = TokenCounter::for_model(&settings.model);
+    let fitted = budget::fit(&counter, prompt, history, budget::prompt_budget(settings))?;
+    if fitted.dropped_turns > 0 || fitted.dropped_examples > 0 {
+        log::info!(
+            "Trimmed Isla prompt to {} tokens: dropped_turns={} dropped_examples={}",
+            fitted.prompt_tokens, fitted.dropped_turns, fitted.dropped_examples,
+        );
+    }
+
+    let mut request = CompletionRequest::new(settings, fitted.messages);
+    request.stop = persona.config.stop.clone();
+    Ok((request, fitted.dropped_turns))
+}
+
+pub async fn respond(
+    provider: &dyn LlmProvider,
+    settings: &config::IslaSettings,
+    persona: &Persona,
+    append_hist: Vec<HistoryEntry>,
+) -> Result<ChatbotResponse, LlmError> {
+    respond_grounded(provider, settings, persona, append_hist, &Grounding::default()).await
+}
+
+/// Same as [`respond`], the prompt carries the passages of `grounding` and the model may call its tools before it answers
+pub async fn respond_grounded(
+    provider: &dyn LlmProvider,
+    settings: &config::IslaSettings,
+    persona: &Persona,
+    append_hist: Vec<HistoryEntry>,
+    grounding: &Grounding,
+) -> Result<ChatbotResponse, LlmError> {
+    let (request, dropped_turns) = build_request(settings, persona, append_hist, &grounding.passages)?;
+    let (completion, tool_calls, degraded) = match tools::run(provider, request, &grounding.toolbox).await {
+        Ok((completion, tool_calls)) => (completion, tool_calls, false),
+        Err(err) => match unavailable_reply(persona, &err) {
+            Some(completion) => {
+                log::warn!("Serving unavailable reply of {:?}: {err}", persona.name);
+                (completion, vec![], true)
+            }
+            None => return Err(err),
+        },
+    };
+    log::info!("Isla replied: provider={:?} persona={:?} usage={:?}", provider.name(), persona.name, completion.usage);
+
+    let mut response = ChatbotResponse::from(completion);
+    response.dropped_turns = dropped_turns;
+    response.degraded = degraded;
+    response.tool_calls = tool_calls;
+    if !degraded {
+        response.citations = retrieval::citations(&grounding.passages);
+    }
+    for choice in response.choices.iter_mut().flatten() {
+        let processed = actions::process(persona, &choice.text);
+        choice.text = processed.text;
+        response.actions.extend(processed.actions);
+    }
+    Ok(response)
+}
+
+pub async fn get_response_stream(
+    config: &config::Global,
+    request: ChatbotRequest,
+    tenant: Option<&str>,
+    models: Arc<ModelRegistry>,
+) -> Result<CompletionStream, LlmError> {
+    let settings = &config.config.isla_settings;
+    let persona = request_persona(config, &request, tenant)?;
+    let estimate = estimate(settings, &persona, &request.hist);
+    let reservation = usage::reserve(&settings.usage, tenant, &settings.model, &estimate)?;
+    let provider = guardrails::wrap(provider::from_config(&config.config)?, &config.config)?;
+    let passages = request_passages(config, models, &persona, &request.hist).await;
+    let stream = respond_stream(provider.as_ref(), settings, &persona, request.hist, &passages).await?;
+
+    // usage arrives with the last event, the meter goes with the stream and records what it can when dropped early
+    let mut meter = StreamUsage {
+        reservation: Some(reservation),
+        counter: TokenCounter::for_model(&settings.model),
+        prompt_tokens: estimate.prompt_tokens,
+        completion_tokens: 0,
+    };
+    Ok(Box::pin(stream.inspect(move |event| meter.observe(event))))
+}
+
+/// Usage of a streamed reply, recorded once `Done` arrives. A stream dropped before, e.g. because the client went
+/// away, records the estimated prompt and the tokens of the deltas that were sent.
+struct StreamUsage {
+    reservation: Option<usage::Reservation>,
+    counter: TokenCounter,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+impl StreamUsage {
+    fn observe(&mut self, event: &Result<StreamEvent, LlmError>) {
+        match event {
+            Ok(StreamEvent::Delta { text }) => self.completion_tokens += self.counter.count(text) as u32,
+            Ok(StreamEvent::Done { usage, .. }) => {
+                if let Some(reservation) = self.reservation.take() {
+                    reservation.record(usage.as_ref());
+                }
+            }
+            _ => (),
         }
-        Err(err) => {
-            Err(err)
+    }
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        if let Some(reservation) = self.reservation.take() {
+            let usage = Usage {
+                prompt_tokens: self.prompt_tokens,
+                completion_tokens: self.completion_tokens,
+                total_tokens: self.prompt_tokens + self.completion_tokens,
+            };
+            log::info!("Stream ended before its usage arrived, recording an estimate: {usage:?}");
+            reservation.record(Some(&usage));
         }
     }
 }
+
+/// Json sent to streaming clients for each event, errors are sent as `{"type": "error", "code": ..., "message": ...}`
+pub fn stream_event_json(event: &Result<StreamEvent, LlmError>) -> String {
+    match event {
+        Ok(event) => serde_json::to_string(event).unwrap_or_default(),
+        Err(err) => serde_json::json!({
+            "type": "error",
+            "code": err.code(),
+            "message": err.to_string(),
+        }).to_string(),
+    }
+}
+
+/// Same prompt as [`respond`], the reply arrives as deltas followed by a final `Done` event.
+/// A `Citations` event comes first when there are `passages`, action tags are taken out of the deltas and sent with `Done`.
+pub async fn respond_stream(
+    provider: &dyn LlmProvider,
+    settings: &config::IslaSettings,
+    persona: &Persona,
+    append_hist: Vec<HistoryEntry>,
+    passages: &[Passage],
+) -> Result<CompletionStream, LlmError> {
+    let (request, dropped_turns) = build_request(settings, persona, append_hist, passages)?;
+    let stream: CompletionStream = match provider.stream(&request).await {
+        Ok(stream) if passages.is_empty() => stream,
+        Ok(stream) => {
+            let citations = StreamEvent::Citations { citations: retrieval::citations(passages) };
+            Box::pin(futures::stream::iter(vec![Ok(citations)]).chain(stream))
+        }
+        Err(err) => match unavailable_reply(persona, &err) {
+            Some(completion) => Box::pin(futures::stream::iter(vec![
+                Ok(StreamEvent::Delta { text: completion.text }),
+                Ok(StreamEvent::Done { finish_reason: completion.finish_reason, usage: None, dropped_turns: 0, actions: vec![] }),
+            ])),
+            None => return Err(err),
+        },
+    };
+
+    let mut processor = StreamProcessor::new(persona.clone());
+    Ok(Box::pin(stream.flat_map(move |event| {
+        let events = match event {
+            Ok(StreamEvent::Delta { text }) => delta(processor.push(&text)),
+            Ok(StreamEvent::Done { finish_reason, usage, .. }) => {
+                let processed = processor.finish();
+                let mut events = delta(processed.text);
+                events.push(Ok(StreamEvent::Done { finish_reason, usage, dropped_turns, actions: processed.actions }));
+                events
+            }
+            event => vec![event],
+        };
+        futures::stream::iter(events)
+    })))
+}
+
+/// Deltas left empty by action processing are not sent
+fn delta(text: String) -> Vec<Result<StreamEvent, LlmError>> {
+    if text.is_empty() {
+        vec![]
+    } else {
+        vec![Ok(StreamEvent::Delta { text })]
+    }
+}
@@ -1 +1,11 @@
+pub mod actions;
+pub mod budget;
+pub mod cache;
+pub mod guardrails;
 pub mod isla;
+pub mod persona;
+pub mod provider;
+pub mod resilience;
+pub mod retrieval;
+pub mod tools;
+pub mod usage;
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::{IslaSettings, PersonaConfig};
+use crate::openai::provider::{ChatMessage, LlmError};
+
+/// Persona shipped with the binary, used when nothing else is configured
+pub const DEFAULT_PERSONA: &str = "isla";
+
+const ISLA: &str = include_str!("personas/isla.yaml");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Persona {
+    pub name: String,
+    pub config: PersonaConfig,
+}
+
+impl Persona {
+    pub fn new<S: Into<String>>(name: S, config: PersonaConfig) -> Self {
+        Self {
+            name: name.into(),
+            config,
+        }
+    }
+
+    pub fn isla() -> Self {
+        Self::from_yaml(DEFAULT_PERSONA, ISLA).expect("bundled persona is valid yaml")
+    }
+
+    pub fn from_yaml<S: Into<String>>(name: S, content: &str) -> Result<Self, LlmError> {
+        serde_yaml::from_str(content)
+            .map(|config| Self::new(name, config))
+            .map_err(|err| LlmError::Config(format!("Invalid persona: {err}")))
+    }
+
+    pub fn from_json<S: Into<String>>(name: S, content: &str) -> Result<Self, LlmError> {
+        serde_json::from_str(content)
+            .map(|config| Self::new(name, config))
+            .map_err(|err| LlmError::Config(format!("Invalid persona: {err}")))
+    }
+
+    /// Adds render time variables, these win over the ones in the persona config
+    pub fn with_variables<I, K, V>(mut self, variables: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config
+            .variables
+            .extend(variables.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Replaces `{{name}}` placeholders, unknown placeholders are left as is
+    pub fn render_text(&self, text: &str) -> String {
+        let mut rendered = text.to_string();
+        for (name, value) in &self.config.variables {
+            rendered = rendered.replace(&format!("{{{{{name}}}}}"), value);
+        }
+        rendered
+    }
+
+    /// System prompt followed by the few-shot examples
+    pub fn prompt(&self) -> Vec<ChatMessage> {
+        let mut messages = vec![ChatMessage::system(self.render_text(self.config.system_prompt.trim()))];
+        for example in &self.config.examples {
+            messages.push(ChatMessage::user(self.render_text(&example.user)));
+            messages.push(ChatMessage::assistant(self.render_text(&example.assistant)));
+        }
+        messages
+    }
+
+    /// Parses a line using the persona's speaker labels, e.g. `"You: hi"`
+    pub fn transcript_line(&self, line: &str) -> Option<ChatMessage> {
+        let line = line.trim();
+        let user = format!("{}:", self.config.user_label);
+        let assistant = format!("{}:", self.config.assistant_label);
+
+        if let Some(content) = line.strip_prefix(user.as_str()) {
+            Some(ChatMessage::user(content.trim()))
+        } else {
+            line.strip_prefix(assistant.as_str())
+                .map(|content| ChatMessage::assistant(content.trim()))
+        }
+    }
+
+    /// Turns a labelled transcript into chat messages, lines without a known label are dropped
+    pub fn transcript<S: AsRef<str>>(&self, lines: &[S]) -> Vec<ChatMessage> {
+        lines
+            .iter()
+            .filter_map(|line| self.transcript_line(line.as_ref()))
+            .collect()
+    }
+}
+
+/// Variables every persona can use: `current_time`, `current_date` and `weekday`
+pub fn builtin_variables(time_format: &str) -> HashMap<String, String> {
+    let now = chrono::offset::Local::now();
+    HashMap::from([
+        ("current_time".to_string(), now.format(time_format).to_string()),
+        ("current_date".to_string(), now.format("%Y-%m-%d").to_string()),
+        ("weekday".to_string(), now.format("%A").to_string()),
+    ])
+}
+
+fn load_file(dir: &str, name: &str) -> Option<Result<Persona, LlmError>> {
+    // names come from requests so they must not be able to walk out of the directory
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+
+    for ext in ["yaml", "yml", "json"] {
+        let path = Path::new(dir).join(format!("{name}.{ext}"));
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            return Some(match ext {
+                "json" => Persona::from_json(name, &content),
+                _ => Persona::from_yaml(name, &content),
+            });
+        }
+    }
+    None
+}
+
+/// Finds a persona by name in the settings, then the persona directory, then the bundled ones
+pub fn find(settings: &IslaSettings, name: &str) -> Result<Persona, LlmError> {
+    if let Some(config) = settings.personas.get(name) {
+        return Ok(Persona::new(name, config.clone()));
+    }
+
+    if let Some(persona) = settings.persona_dir.as_deref().and_then(|dir| load_file(dir, name)) {
+        return persona;
+    }
+
+    match name {
+        DEFAULT_PERSONA => Ok(Persona::isla()),
+        _ => Err(LlmError::Config(format!("Unknown persona '{name}'"))),
+    }
+}
+
+/// Picks the persona for a request: the requested one, then the tenant's, then the configured default
+pub fn resolve(settings: &IslaSettings, requested: Option<&str>, tenant: Option<&str>) -> Result<Persona, LlmError> {
+    let name = requested
+        .map(|name| name.to_string())
+        .or_else(|| tenant.and_then(|tenant| settings.tenant_personas.get(tenant).cloned()))
+        .or_else(|| settings.persona.clone())
+        .unwrap_or_else(|| DEFAULT_PERSONA.to_string());
+
+    find(settings, name.trim())
+}
@@ -0,0 +1,1047 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{GlobalConfig, IslaSettings, LlmProviderSettings};
+use crate::openai::actions::Action;
+use crate::openai::resilience::{self, Backoff, ResilientProvider};
+use crate::openai::retrieval::Citation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// Result of a tool call, answering the assistant message that made it
+    Tool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    /// Speaker name, sent as is to providers that support it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Tools an assistant message asks to have called
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Call a tool message is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new<S: Into<String>>(role: Role, content: S) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            name: None,
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    pub fn tool<I: Into<String>, S: Into<String>>(tool_call_id: I, content: S) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn system<S: Into<String>>(content: S) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user<S: Into<String>>(content: S) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant<S: Into<String>>(content: S) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+}
+
+/// A model's request to run a tool, `arguments` is the json text the model wrote
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+/// Tool offered to the model, `parameters` is a json schema of its arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub top_p: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolSpec>,
+}
+
+impl CompletionRequest {
+    pub fn new(settings: &IslaSettings, messages: Vec<ChatMessage>) -> Self {
+        Self {
+            model: settings.model.clone(),
+            messages,
+            temperature: settings.temperature,
+            max_tokens: settings.max_tokens,
+            top_p: settings.top_p,
+            frequency_penalty: settings.frequency_penalty,
+            presence_penalty: settings.presence_penalty,
+            stop: vec![],
+            tools: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+    /// Tools the model wants called before it answers, `text` may be empty when there are any
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl Completion {
+    /// Completion that did not come from a model, such as a refusal
+    pub fn canned<S: Into<String>, R: Into<String>>(text: S, finish_reason: R) -> Self {
+        Self {
+            text: text.into(),
+            finish_reason: Some(finish_reason.into()),
+            usage: None,
+            tool_calls: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LlmError {
+    Config(String),
+    Request(String),
+    Timeout(String),
+    /// `retry_after` is in seconds, taken from the `Retry-After` header when the provider sent one
+    Status { status: u16, body: String, retry_after: Option<u64> },
+    Parse(String),
+    /// The provider failed too often recently and is not called until `retry_in` seconds have passed
+    CircuitOpen { provider: String, retry_in: u64 },
+    /// The tenant used up its monthly quota, `limit` says which one
+    QuotaExceeded { tenant: String, limit: String },
+}
+
+impl LlmError {
+    /// Stable code for API clients, the message may change but the code does not
+    pub fn code(&self) -> &'static str {
+        match self {
+            LlmError::Config(_) => "upstream_misconfigured",
+            LlmError::Request(_) => "upstream_unreachable",
+            LlmError::Timeout(_) => "upstream_timeout",
+            LlmError::Status { status: 429, .. } => "upstream_rate_limited",
+            LlmError::Status { status: 401 | 403, .. } => "upstream_unauthorized",
+            LlmError::Status { status, .. } if *status >= 500 => "upstream_unavailable",
+            LlmError::Status { .. } => "upstream_rejected",
+            LlmError::Parse(_) => "upstream_bad_response",
+            LlmError::CircuitOpen { .. } => "upstream_circuit_open",
+            LlmError::QuotaExceeded { .. } => "quota_exceeded",
+        }
+    }
+
+    /// Whether the same request may succeed when tried again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LlmError::Request(_) | LlmError::Timeout(_) => true,
+            // 529 is anthropic's overloaded status
+            LlmError::Status { status, .. } => matches!(status, 408 | 409 | 429 | 500 | 502 | 503 | 504 | 529),
+            LlmError::Config(_) | LlmError::Parse(_) | LlmError::CircuitOpen { .. } | LlmError::QuotaExceeded { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Config(reason) => write!(f, "Provider is misconfigured: {reason}"),
+            LlmError::Request(reason) => write!(f, "Request to provider failed: {reason}"),
+            LlmError::Timeout(reason) => write!(f, "Request to provider timed out: {reason}"),
+            LlmError::Status { status, body, .. } => write!(f, "Provider responded with {status}: {body}"),
+            LlmError::Parse(reason) => write!(f, "Failed to parse provider response: {reason}"),
+            LlmError::CircuitOpen { provider, retry_in } => {
+                write!(f, "Provider {provider} is failing, calls resume in {retry_in}s")
+            }
+            LlmError::QuotaExceeded { tenant, limit } => write!(f, "Monthly {limit} quota of {tenant} is used up"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            LlmError::Timeout(err.to_string())
+        } else {
+            LlmError::Request(err.to_string())
+        }
+    }
+}
+
+/// Seconds to wait according to a `Retry-After` header, which is either seconds or an http date
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    value.parse::<u64>().ok().or_else(|| {
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        let seconds = (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds();
+        Some(seconds.max(0) as u64)
+    })
+}
+
+/// Turns an unsuccessful response into [`LlmError::Status`]
+async fn status_error(response: reqwest::Response) -> LlmError {
+    let status = response.status().as_u16();
+    let retry_after = retry_after(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    LlmError::Status { status, body, retry_after }
+}
+
+/// Piece of a streamed completion, the last event of a stream is always `Done`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// Passages the reply may cite, sent before the first delta when there are any
+    Citations {
+        citations: Vec<Citation>,
+    },
+    Delta {
+        text: String,
+    },
+    Done {
+        finish_reason: Option<String>,
+        usage: Option<Usage>,
+        /// Oldest history messages left out so the prompt fits the model's context window
+        #[serde(default)]
+        dropped_turns: usize,
+        /// Action tags found in the reply, they are removed from the deltas
+        #[serde(default)]
+        actions: Vec<Action>,
+    },
+}
+
+pub type CompletionStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, LlmError>> + Send>>;
+
+/// A chat completion backend Isla can talk to
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError>;
+
+    /// Streams the completion as it is generated.
+    /// Providers without native streaming send the whole completion as a single delta.
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        let completion = self.complete(request).await?;
+        let events = vec![
+            Ok(StreamEvent::Delta { text: completion.text }),
+            Ok(StreamEvent::Done {
+                finish_reason: completion.finish_reason,
+                usage: completion.usage,
+                dropped_turns: 0,
+                actions: vec![],
+            }),
+        ];
+        Ok(Box::pin(futures::stream::iter(events)))
+    }
+
+    /// One vector per input, computed by `model`. Providers without an embeddings api fail with `Config`.
+    async fn embed(&self, _model: &str, _inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        Err(LlmError::Config(format!("{} has no embeddings api", self.name())))
+    }
+}
+
+/// Lets one provider be shared, e.g. between the chat and the intent classifier
+#[async_trait]
+impl<T: LlmProvider + ?Sized> LlmProvider for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        (**self).complete(request).await
+    }
+
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        (**self).stream(request).await
+    }
+
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        (**self).embed(model, inputs).await
+    }
+}
+
+/// Splits a `text/event-stream` body into the `data` of each event
+#[derive(Debug, Default)]
+pub struct SseBuffer {
+    buffer: Vec<u8>,
+}
+
+impl SseBuffer {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend(chunk.iter().filter(|byte| **byte != b'\r'));
+
+        let mut events = vec![];
+        while let Some(idx) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let event = self.buffer.drain(..idx + 2).collect::<Vec<u8>>();
+            let data = String::from_utf8_lossy(&event)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.trim_start())
+                .collect::<Vec<&str>>()
+                .join("\n");
+
+            if !data.is_empty() {
+                events.push(data);
+            }
+        }
+        events
+    }
+}
+
+async fn post_stream(request: reqwest::RequestBuilder, payload: &Value) -> Result<reqwest::Response, LlmError> {
+    let response = request
+        .header("Content-Type", "application/json")
+        .header("Accept", "text/event-stream")
+        .json(payload)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+
+    Ok(response)
+}
+
+/// Decodes the server sent events of `response` with `decode`, which turns one event's data into stream events.
+/// A `Done` event is added when the provider closes the stream without one.
+fn event_stream<D, S>(response: reqwest::Response, mut state: S, mut decode: D) -> CompletionStream
+where
+    D: FnMut(&mut S, &str) -> Result<Vec<StreamEvent>, LlmError> + Send + 'static,
+    S: Send + 'static,
+{
+    Box::pin(async_stream::stream! {
+        let mut bytes = response.bytes_stream();
+        let mut sse = SseBuffer::default();
+        let mut done = false;
+
+        'read: while let Some(chunk) = bytes.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    yield Err(LlmError::from(err));
+                    return;
+                }
+            };
+
+            for data in sse.push(&chunk) {
+                match decode(&mut state, &data) {
+                    Ok(events) => {
+                        for event in events {
+                            done = matches!(event, StreamEvent::Done { .. });
+                            yield Ok(event);
+                            if done {
+                                break 'read;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        yield Err(err);
+                        return;
+                    }
+                }
+            }
+        }
+
+        if !done {
+            yield Ok(StreamEvent::Done { finish_reason: None, usage: None, dropped_turns: 0, actions: vec![] });
+        }
+    })
+}
+
+#[derive(Default)]
+struct OpenAiStreamState {
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+fn openai_stream_events(state: &mut OpenAiStreamState, data: &str) -> Result<Vec<StreamEvent>, LlmError> {
+    if data == "[DONE]" {
+        return Ok(vec![StreamEvent::Done {
+            finish_reason: state.finish_reason.take(),
+            usage: state.usage.take(),
+            dropped_turns: 0,
+            actions: vec![],
+        }]);
+    }
+
+    let chunk = serde_json::from_str::<Value>(data).map_err(|err| LlmError::Parse(err.to_string()))?;
+    if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+        state.usage = serde_json::from_value(usage.clone()).ok();
+    }
+
+    let mut events = vec![];
+    if let Some(choice) = chunk.get("choices").and_then(|choices| choices.get(0)) {
+        if let Some(text) = choice.pointer("/delta/content").and_then(|v| v.as_str()) {
+            if !text.is_empty() {
+                events.push(StreamEvent::Delta { text: text.to_string() });
+            }
+        }
+        if let Some(reason) = choice.get("finish_reason").and_then(|v| v.as_str()) {
+            state.finish_reason = Some(reason.to_string());
+        }
+    }
+    Ok(events)
+}
+
+async fn post_json(request: reqwest::RequestBuilder, payload: &Value) -> Result<Value, LlmError> {
+    let response = request
+        .header("Content-Type", "application/json")
+        .json(payload)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+
+    response
+        .json::<Value>()
+        .await
+        .map_err(|err| LlmError::Parse(err.to_string()))
+}
+
+/// Vectors of an embeddings response, in the order of the inputs
+fn openai_embeddings(body: Value) -> Result<Vec<Vec<f32>>, LlmError> {
+    let mut data = body
+        .get("data")
+        .and_then(|data| data.as_array())
+        .ok_or_else(|| LlmError::Parse("response has no data".into()))?
+        .clone();
+    data.sort_by_key(|item| item.get("index").and_then(|index| index.as_u64()).unwrap_or_default());
+
+    data.into_iter()
+        .map(|item| {
+            item.get("embedding")
+                .cloned()
+                .and_then(|embedding| serde_json::from_value::<Vec<f32>>(embedding).ok())
+                .ok_or_else(|| LlmError::Parse("item has no embedding".into()))
+        })
+        .collect()
+}
+
+/// Message in the chat completions format, where tool calls carry their arguments as json text
+fn openai_message(message: &ChatMessage) -> Value {
+    let mut value = json!({ "role": message.role, "content": message.content });
+    if let Some(name) = &message.name {
+        value["name"] = json!(name);
+    }
+    if !message.tool_calls.is_empty() {
+        value["tool_calls"] = message.tool_calls
+            .iter()
+            .map(|call| json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments },
+            }))
+            .collect();
+        if message.content.is_empty() {
+            value["content"] = Value::Null;
+        }
+    }
+    if let Some(id) = &message.tool_call_id {
+        value["tool_call_id"] = json!(id);
+    }
+    value
+}
+
+fn openai_payload(request: &CompletionRequest, include_model: bool) -> Value {
+    let mut payload = json!({
+        "messages": request.messages.iter().map(openai_message).collect::<Vec<Value>>(),
+        "temperature": request.temperature,
+        "max_tokens": request.max_tokens,
+        "top_p": request.top_p,
+        "frequency_penalty": request.frequency_penalty,
+        "presence_penalty": request.presence_penalty,
+    });
+    if include_model {
+        payload["model"] = json!(request.model);
+    }
+    if !request.stop.is_empty() {
+        payload["stop"] = json!(request.stop);
+    }
+    if !request.tools.is_empty() {
+        payload["tools"] = request.tools
+            .iter()
+            .map(|tool| json!({
+                "type": "function",
+                "function": { "name": tool.name, "description": tool.description, "parameters": tool.parameters },
+            }))
+            .collect();
+    }
+    payload
+}
+
+fn openai_completion(body: Value) -> Result<Completion, LlmError> {
+    let choice = body
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .ok_or_else(|| LlmError::Parse("response has no choices".into()))?;
+
+    let tool_calls = choice
+        .pointer("/message/tool_calls")
+        .and_then(|calls| calls.as_array())
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| ToolCall {
+                    id: call.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                    name: call.pointer("/function/name").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                    arguments: call.pointer("/function/arguments").and_then(|v| v.as_str()).unwrap_or("{}").to_string(),
+                })
+                .collect::<Vec<ToolCall>>()
+        })
+        .unwrap_or_default();
+
+    // the content is null when the model only calls tools
+    let text = match choice.pointer("/message/content") {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None if !tool_calls.is_empty() => String::new(),
+        _ => return Err(LlmError::Parse("choice has no message content".into())),
+    };
+
+    Ok(Completion {
+        text,
+        finish_reason: choice.get("finish_reason").and_then(|v| v.as_str()).map(|v| v.to_string()),
+        usage: body.get("usage").and_then(|usage| serde_json::from_value(usage.clone()).ok()),
+        tool_calls,
+    })
+}
+
+/// OpenAI chat completions, also used for OpenAI compatible servers such as llama.cpp or Ollama
+pub struct OpenAiProvider {
+    name: String,
+    base_url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OpenAiProvider {
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.openai.com/v1";
+
+    pub fn new<S: Into<String>>(api_key: S) -> Self {
+        Self::compatible("openai", Self::DEFAULT_BASE_URL, Some(api_key.into()))
+    }
+
+    pub fn compatible<N: Into<String>, U: Into<String>>(name: N, base_url: U, api_key: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses `client` instead of a client of its own, see [`crate::openai::resilience::client`]
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        let mut builder = self.client.post(format!("{}/chat/completions", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let body = post_json(builder, &openai_payload(request, true)).await?;
+        openai_completion(body)
+    }
+
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        let mut builder = self.client.post(format!("{}/chat/completions", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let mut payload = openai_payload(request, true);
+        payload["stream"] = json!(true);
+        payload["stream_options"] = json!({ "include_usage": true });
+
+        let response = post_stream(builder, &payload).await?;
+        Ok(event_stream(response, OpenAiStreamState::default(), openai_stream_events))
+    }
+
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let mut builder = self.client.post(format!("{}/embeddings", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let body = post_json(builder, &json!({ "model": model, "input": inputs })).await?;
+        openai_embeddings(body)
+    }
+}
+
+/// Azure OpenAI, the model is chosen by the deployment rather than the payload
+pub struct AzureOpenAiProvider {
+    endpoint: String,
+    deployment: String,
+    api_version: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+impl AzureOpenAiProvider {
+    pub fn new<E, D, V, K>(endpoint: E, deployment: D, api_version: V, api_key: K) -> Self
+    where
+        E: Into<String>,
+        D: Into<String>,
+        V: Into<String>,
+        K: Into<String>,
+    {
+        Self {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            deployment: deployment.into(),
+            api_version: api_version.into(),
+            api_key: api_key.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses `client` instead of a client of its own, see [`crate::openai::resilience::client`]
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AzureOpenAiProvider {
+    fn name(&self) -> &str {
+        "azure_openai"
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint, self.deployment, self.api_version,
+        );
+        let builder = self.client.post(url).header("api-key", &self.api_key);
+
+        let body = post_json(builder, &openai_payload(request, false)).await?;
+        openai_completion(body)
+    }
+
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint, self.deployment, self.api_version,
+        );
+        let builder = self.client.post(url).header("api-key", &self.api_key);
+
+        let mut payload = openai_payload(request, false);
+        payload["stream"] = json!(true);
+        payload["stream_options"] = json!({ "include_usage": true });
+
+        let response = post_stream(builder, &payload).await?;
+        Ok(event_stream(response, OpenAiStreamState::default(), openai_stream_events))
+    }
+
+    /// `model` is the name of the embeddings deployment
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let url = format!(
+            "{}/openai/deployments/{}/embeddings?api-version={}",
+            self.endpoint, model, self.api_version,
+        );
+        let builder = self.client.post(url).header("api-key", &self.api_key);
+
+        let body = post_json(builder, &json!({ "input": inputs })).await?;
+        openai_embeddings(body)
+    }
+}
+
+/// Anthropic messages API
+pub struct AnthropicProvider {
+    base_url: String,
+    version: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+impl AnthropicProvider {
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.anthropic.com";
+    pub const DEFAULT_VERSION: &'static str = "2023-06-01";
+
+    pub fn new<U: Into<String>, K: Into<String>>(base_url: U, api_key: K) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            version: Self::DEFAULT_VERSION.to_string(),
+            api_key: api_key.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses `client` instead of a client of its own, see [`crate::openai::resilience::client`]
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    fn payload(request: &CompletionRequest) -> Value {
+        // system prompts are a top level field, the message list only holds the conversation
+        let system = request.messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n\n");
+        let mut messages: Vec<Value> = vec![];
+        for message in request.messages.iter().filter(|message| message.role != Role::System) {
+            let value = match message.role {
+                // tool results go back as user content blocks, consecutive ones in a single message
+                Role::Tool => {
+                    let result = json!({
+                        "type": "tool_result",
+                        "tool_use_id": message.tool_call_id.clone().unwrap_or_default(),
+                        "content": message.content,
+                    });
+                    if let Some(blocks) = messages
+                        .last_mut()
+                        .filter(|last| last["role"] == "user")
+                        .and_then(|last| last["content"].as_array_mut())
+                    {
+                        blocks.push(result);
+                        continue;
+                    }
+                    json!({ "role": "user", "content": [result] })
+                }
+                _ if !message.tool_calls.is_empty() => {
+                    let mut blocks = vec![];
+                    if !message.content.is_empty() {
+                        blocks.push(json!({ "type": "text", "text": message.content }));
+                    }
+                    blocks.extend(message.tool_calls.iter().map(|call| json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": serde_json::from_str::<Value>(&call.arguments).unwrap_or_else(|_| json!({})),
+                    })));
+                    json!({ "role": message.role, "content": blocks })
+                }
+                // there is no name field, so the speaker goes in front of the content instead
+                _ => match &message.name {
+                    Some(name) => json!({ "role": message.role, "content": format!("{name}: {}", message.content) }),
+                    None => json!({ "role": message.role, "content": message.content }),
+                },
+            };
+            messages.push(value);
+        }
+
+        let mut payload = json!({
+            "model": request.model,
+            "messages": messages,
+            "max_tokens": request.max_tokens,
+            "temperature": request.temperature,
+            "top_p": request.top_p,
+        });
+        if !system.is_empty() {
+            payload["system"] = json!(system);
+        }
+        if !request.stop.is_empty() {
+            payload["stop_sequences"] = json!(request.stop);
+        }
+        if !request.tools.is_empty() {
+            payload["tools"] = request.tools
+                .iter()
+                .map(|tool| json!({ "name": tool.name, "description": tool.description, "input_schema": tool.parameters }))
+                .collect();
+        }
+        payload
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        let builder = self.client
+            .post(format!("{}/v1/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", &self.version);
+
+        let body = post_json(builder, &Self::payload(request)).await?;
+
+        let blocks = body
+            .get("content")
+            .and_then(|content| content.as_array())
+            .ok_or_else(|| LlmError::Parse("response has no content".into()))?;
+        let of_type = |kind: &'static str| {
+            blocks.iter().filter(move |block| block.get("type").and_then(|v| v.as_str()) == Some(kind))
+        };
+        let text = of_type("text")
+            .filter_map(|block| block.get("text").and_then(|v| v.as_str()))
+            .collect::<String>();
+        let tool_calls = of_type("tool_use")
+            .map(|block| ToolCall {
+                id: block.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                name: block.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                arguments: block.get("input").map(|input| input.to_string()).unwrap_or_else(|| "{}".into()),
+            })
+            .collect::<Vec<ToolCall>>();
+
+        let usage = body.get("usage").map(|usage| {
+            let tokens = |key: &str| usage.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+            let (prompt_tokens, completion_tokens) = (tokens("input_tokens"), tokens("output_tokens"));
+            Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }
+        });
+
+        Ok(Completion {
+            text,
+            finish_reason: body.get("stop_reason").and_then(|v| v.as_str()).map(|v| v.to_string()),
+            usage,
+            tool_calls,
+        })
+    }
+
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        let builder = self.client
+            .post(format!("{}/v1/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", &self.version);
+
+        let mut payload = Self::payload(request);
+        payload["stream"] = json!(true);
+
+        let response = post_stream(builder, &payload).await?;
+        Ok(event_stream(response, AnthropicStreamState::default(), anthropic_stream_events))
+    }
+}
+
+#[derive(Default)]
+struct AnthropicStreamState {
+    finish_reason: Option<String>,
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+fn anthropic_stream_events(state: &mut AnthropicStreamState, data: &str) -> Result<Vec<StreamEvent>, LlmError> {
+    let event = serde_json::from_str::<Value>(data).map_err(|err| LlmError::Parse(err.to_string()))?;
+    let tokens = |value: Option<&Value>| value.and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+
+    let events = match event.get("type").and_then(|v| v.as_str()) {
+        Some("message_start") => {
+            state.input_tokens = tokens(event.pointer("/message/usage/input_tokens"));
+            vec![]
+        }
+        Some("content_block_delta") => event
+            .pointer("/delta/text")
+            .and_then(|v| v.as_str())
+            .map(|text| vec![StreamEvent::Delta { text: text.to_string() }])
+            .unwrap_or_default(),
+        Some("message_delta") => {
+            state.output_tokens = tokens(event.pointer("/usage/output_tokens"));
+            if let Some(reason) = event.pointer("/delta/stop_reason").and_then(|v| v.as_str()) {
+                state.finish_reason = Some(reason.to_string());
+            }
+            vec![]
+        }
+        Some("message_stop") => vec![StreamEvent::Done {
+            finish_reason: state.finish_reason.take(),
+            usage: Some(Usage {
+                prompt_tokens: state.input_tokens,
+                completion_tokens: state.output_tokens,
+                total_tokens: state.input_tokens + state.output_tokens,
+            }),
+            dropped_turns: 0,
+            actions: vec![],
+        }],
+        Some("error") => {
+            let message = event.pointer("/error/message").and_then(|v| v.as_str()).unwrap_or("unknown error");
+            return Err(LlmError::Request(message.to_string()));
+        }
+        _ => vec![],
+    };
+
+    Ok(events)
+}
+
+/// Replies with canned completions and records every request, for tests and offline development
+#[derive(Default)]
+pub struct MockProvider {
+    replies: Mutex<VecDeque<Result<Completion, LlmError>>>,
+    fallback: String,
+    requests: Mutex<Vec<CompletionRequest>>,
+}
+
+impl MockProvider {
+    /// Always answers with `reply` once the queued replies run out
+    pub fn new<S: Into<String>>(reply: S) -> Self {
+        Self {
+            fallback: reply.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn queue(self, reply: Result<Completion, LlmError>) -> Self {
+        if let Ok(mut replies) = self.replies.lock() {
+            replies.push_back(reply);
+        }
+        self
+    }
+
+    pub const EMBEDDING_DIMENSIONS: usize = 256;
+
+    fn embedding(text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; Self::EMBEDDING_DIMENSIONS];
+        let words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase());
+        for word in words {
+            let mut hasher = DefaultHasher::new();
+            word.hash(&mut hasher);
+            vector[hasher.finish() as usize % Self::EMBEDDING_DIMENSIONS] += 1.0;
+        }
+        vector
+    }
+
+    pub fn requests(&self) -> Vec<CompletionRequest> {
+        self.requests
+            .lock()
+            .map(|requests| requests.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.push(request.clone());
+        }
+
+        let queued = self.replies.lock().ok().and_then(|mut replies| replies.pop_front());
+        queued.unwrap_or_else(|| Ok(Completion::canned(self.fallback.clone(), "stop")))
+    }
+
+    /// Hashed bag of words, texts sharing words get similar vectors
+    async fn embed(&self, _model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        Ok(inputs.iter().map(|input| MockProvider::embedding(input)).collect())
+    }
+}
+
+/// Builds the provider selected by `isla_settings.provider` on the shared http client,
+/// wrapped in the retries and circuit breaker of `isla_settings.upstream`
+pub fn from_config(config: &GlobalConfig) -> Result<Box<dyn LlmProvider>, LlmError> {
+    let settings = &config.isla_settings.provider;
+    let upstream = &config.isla_settings.upstream;
+    let client = resilience::client(upstream);
+    let secret = |api_key: &Option<String>| api_key.clone().unwrap_or_else(|| config.openai_secret.clone());
+
+    let provider: Box<dyn LlmProvider> = match settings {
+        LlmProviderSettings::OpenAi { base_url, api_key } => Box::new(OpenAiProvider::compatible(
+            "openai",
+            base_url.clone().unwrap_or_else(|| OpenAiProvider::DEFAULT_BASE_URL.into()),
+            Some(secret(api_key)),
+        ).client(client)),
+        LlmProviderSettings::AzureOpenAi { endpoint, deployment, api_version, api_key } => {
+            Box::new(AzureOpenAiProvider::new(endpoint, deployment, api_version, secret(api_key)).client(client))
+        }
+        LlmProviderSettings::Anthropic { base_url, version, api_key } => {
+            let api_key = api_key
+                .clone()
+                .ok_or_else(|| LlmError::Config("anthropic requires an api_key".into()))?;
+            let provider = AnthropicProvider::new(
+                base_url.clone().unwrap_or_else(|| AnthropicProvider::DEFAULT_BASE_URL.into()),
+                api_key,
+            ).client(client);
+            match version {
+                Some(version) => Box::new(provider.version(version)),
+                None => Box::new(provider),
+            }
+        }
+        LlmProviderSettings::Local { base_url, api_key } => {
+            Box::new(OpenAiProvider::compatible("local", base_url, api_key.clone()).client(client))
+        }
+        LlmProviderSettings::Mock { reply } => Box::new(MockProvider::new(reply)),
+    };
+
+    // one breaker per configured backend, so a config reload to another backend starts out closed
+    let mut hasher = DefaultHasher::new();
+    settings.hash(&mut hasher);
+    let breaker = resilience::breaker(&format!("{}-{:x}", provider.name(), hasher.finish()), upstream);
+
+    Ok(Box::new(ResilientProvider::new(provider, Backoff::new(upstream), breaker)))
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+
+use crate::config::UpstreamSettings;
+use crate::openai::provider::{Completion, CompletionRequest, CompletionStream, LlmError, LlmProvider};
+
+lazy_static! {
+    /// Clients by (connect timeout, request timeout), each one keeps its own connection pool
+    static ref CLIENTS: Mutex<HashMap<(u64, u64), reqwest::Client>> = Mutex::new(HashMap::new());
+    /// Breakers outlive the providers built per request, so failures add up across requests
+    static ref BREAKERS: Mutex<HashMap<String, Arc<CircuitBreaker>>> = Mutex::new(HashMap::new());
+}
+
+/// Pooled client shared by every provider with the same timeouts
+pub fn client(settings: &UpstreamSettings) -> reqwest::Client {
+    let key = (settings.connect_timeout_ms, settings.request_timeout_ms);
+    let build = || {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(settings.connect_timeout_ms))
+            .timeout(Duration::from_millis(settings.request_timeout_ms))
+            .build()
+            .unwrap_or_else(|err| {
+                log::error!("Failed to build http client, using defaults: {err:?}");
+                reqwest::Client::new()
+            })
+    };
+
+    match CLIENTS.lock() {
+        Ok(mut clients) => clients.entry(key).or_insert_with(build).clone(),
+        Err(_) => build(),
+    }
+}
+
+/// Breaker shared by every provider built for `key` with the same threshold and cooldown,
+/// so changing either in a config reload takes effect
+pub fn breaker(key: &str, settings: &UpstreamSettings) -> Arc<CircuitBreaker> {
+    let build = || Arc::new(CircuitBreaker::new(settings.failure_threshold, Duration::from_millis(settings.cooldown_ms)));
+    let key = format!("{key}-{}-{}", settings.failure_threshold, settings.cooldown_ms);
+
+    match BREAKERS.lock() {
+        Ok(mut breakers) => breakers.entry(key).or_insert_with(build).clone(),
+        Err(_) => build(),
+    }
+}
+
+/// Exponential backoff between attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub max_retries: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub fn new(settings: &UpstreamSettings) -> Self {
+        Self {
+            max_retries: settings.max_retries,
+            base: Duration::from_millis(settings.backoff_ms),
+            max: Duration::from_millis(settings.max_backoff_ms),
+        }
+    }
+
+    /// Wait before retry number `attempt` (starting at 0) after `err`, `None` when it should not be retried.
+    /// A `Retry-After` from the provider replaces the computed wait, unless it is longer than `max`.
+    pub fn delay(&self, attempt: u32, err: &LlmError) -> Option<Duration> {
+        if attempt >= self.max_retries || !err.is_retryable() {
+            return None;
+        }
+
+        match err {
+            LlmError::Status { retry_after: Some(seconds), .. } => {
+                Some(Duration::from_secs(*seconds)).filter(|wait| *wait <= self.max)
+            }
+            _ => Some(self.base.saturating_mul(2u32.saturating_pow(attempt)).min(self.max)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// Cooldown is over and a single probe call is let through
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Stops calling a provider after `threshold` failed calls in a row, for `cooldown`
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Asks to make a call, `Err` holds how long until calls are let through again.
+    /// The call reports back through the permit, a probe whose permit is dropped unreported counts as failed.
+    pub fn acquire(&self) -> Result<Permit<'_>, Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let opened_at = match state.opened_at {
+            None => return Ok(Permit { breaker: self, probe: false }),
+            Some(opened_at) => opened_at,
+        };
+
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+        if state.probing {
+            // another call is already finding out whether the provider is back
+            return Err(Duration::ZERO);
+        }
+        state.probing = true;
+        Ok(Permit { breaker: self, probe: true })
+    }
+
+    fn success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.failures = 0;
+        state.opened_at = None;
+        state.probing = false;
+    }
+
+    fn failure(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.failures += 1;
+        if state.probing || state.failures >= self.threshold {
+            log::warn!("Opening circuit after {} failed calls in a row", state.failures);
+            state.opened_at = Some(Instant::now());
+        }
+        state.probing = false;
+    }
+}
+
+/// Leave to make one call through a [`CircuitBreaker`]
+#[derive(Debug)]
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    /// Set until the outcome of a half open probe is reported
+    probe: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        self.probe = false;
+        self.breaker.success();
+    }
+
+    pub fn failure(mut self) {
+        self.probe = false;
+        self.breaker.failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            // the call was cancelled, without an answer the provider is not known to be back
+            self.breaker.failure();
+        }
+    }
+}
+
+/// Retries and circuit breaking around another provider
+pub struct ResilientProvider {
+    inner: Box<dyn LlmProvider>,
+    backoff: Backoff,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl ResilientProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, backoff: Backoff, breaker: Arc<CircuitBreaker>) -> Self {
+        Self { inner, backoff, breaker }
+    }
+
+    /// Runs `call` until it succeeds, fails for good or runs out of retries.
+    /// The breaker counts calls rather than attempts, so a call that only succeeded on retry is a success.
+    async fn call<T, F, Fut>(&self, call: F) -> Result<T, LlmError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, LlmError>>,
+    {
+        let permit = match self.breaker.acquire() {
+            Ok(permit) => permit,
+            Err(wait) => {
+                return Err(LlmError::CircuitOpen {
+                    provider: self.inner.name().to_string(),
+                    retry_in: wait.as_secs_f64().ceil() as u64,
+                })
+            }
+        };
+
+        let mut attempt = 0;
+        loop {
+            let err = match call().await {
+                Ok(value) => {
+                    permit.success();
+                    return Ok(value);
+                }
+                Err(err) => err,
+            };
+
+            match self.backoff.delay(attempt, &err) {
+                Some(wait) => {
+                    log::warn!("Retrying {} in {wait:?} after attempt {}: {err}", self.inner.name(), attempt + 1);
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                None => {
+                    if err.is_retryable() {
+                        permit.failure();
+                    } else {
+                        // the provider answered, it was the request that was at fault
+                        permit.success();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for ResilientProvider {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, LlmError> {
+        self.call(|| self.inner.complete(request)).await
+    }
+
+    /// Only opening the stream is retried, a stream that breaks halfway reports the error as its last event
+    async fn stream(&self, request: &CompletionRequest) -> Result<CompletionStream, LlmError> {
+        self.call(|| self.inner.stream(request)).await
+    }
+
+    async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        self.call(|| self.inner.embed(model, inputs)).await
+    }
+}
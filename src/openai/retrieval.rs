@@ -0,0 +1,591 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use dfs_ml::registry::ModelRegistry;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+use tokio::sync::OnceCell;
+
+use crate::config::{EmbedderSettings, GlobalConfig, RetrievalSettings, VectorStoreSettings};
+use crate::openai::guardrails;
+use crate::openai::provider::{self, ChatMessage, LlmError, LlmProvider, Role};
+
+lazy_static! {
+    /// Retrievers by settings, each one ingests its documents once
+    static ref RETRIEVERS: Mutex<HashMap<String, Arc<OnceCell<Arc<Retriever>>>>> = Mutex::new(HashMap::new());
+}
+
+/// Extensions of the files ingested from directories
+const EXTENSIONS: [&str; 3] = ["md", "markdown", "txt"];
+
+/// Chunks embedded per call to the embedder
+const BATCH_SIZE: usize = 32;
+
+/// Piece of a document, the unit passages are retrieved in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// Hash of the source and text, so a chunk keeps its id until its document changes
+    pub id: String,
+    /// Path of the document
+    pub source: String,
+    /// Closest markdown heading above the chunk
+    pub heading: Option<String>,
+    pub text: String,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+impl Chunk {
+    fn new(source: &str, heading: Option<String>, text: String) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(source.as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        Self {
+            id: hex::encode(hasher.finalize()),
+            source: source.to_string(),
+            heading,
+            text,
+            embedding: vec![],
+        }
+    }
+}
+
+/// Chunk found for a query, `score` is the cosine similarity of the two
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Passage {
+    pub source: String,
+    pub heading: Option<String>,
+    pub text: String,
+    pub score: f32,
+}
+
+impl Passage {
+    fn label(&self) -> String {
+        match &self.heading {
+            Some(heading) => format!("{} - {heading}", self.source),
+            None => self.source.clone(),
+        }
+    }
+}
+
+/// Passage as numbered in the prompt, replies cite it as `(index)`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Citation {
+    pub index: usize,
+    #[serde(flatten)]
+    pub passage: Passage,
+}
+
+/// Splits `text` into chunks of at most `max_chars`. Chunks end at paragraphs and start over at
+/// markdown headings, paragraphs longer than a chunk are cut with `overlap` characters repeated.
+pub fn chunk(source: &str, text: &str, max_chars: usize, overlap: usize) -> Vec<Chunk> {
+    let max_chars = max_chars.max(1);
+    let overlap = overlap.min(max_chars / 2);
+    let text = text.replace("\r\n", "\n");
+
+    let mut chunks = vec![];
+    let mut heading: Option<String> = None;
+    let mut current = String::new();
+    let mut flush = |current: &mut String, heading: &Option<String>| {
+        if !current.is_empty() {
+            chunks.push(Chunk::new(source, heading.clone(), std::mem::take(current)));
+        }
+    };
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|paragraph| !paragraph.is_empty()) {
+        if paragraph.starts_with('#') {
+            flush(&mut current, &heading);
+            let title = paragraph.lines().next().unwrap_or_default();
+            heading = Some(title.trim_start_matches('#').trim().to_string());
+        }
+
+        let length = paragraph.chars().count();
+        if length > max_chars {
+            flush(&mut current, &heading);
+            let chars = paragraph.chars().collect::<Vec<char>>();
+            let mut start = 0;
+            while start < chars.len() {
+                let end = (start + max_chars).min(chars.len());
+                current = chars[start..end].iter().collect();
+                flush(&mut current, &heading);
+                if end == chars.len() {
+                    break;
+                }
+                start = end - overlap;
+            }
+        } else if current.is_empty() {
+            current = paragraph.to_string();
+        } else if current.chars().count() + 2 + length > max_chars {
+            flush(&mut current, &heading);
+            current = paragraph.to_string();
+        } else {
+            current.push_str("\n\n");
+            current.push_str(paragraph);
+        }
+    }
+    flush(&mut current, &heading);
+    chunks
+}
+
+fn collect_files(path: &Path, listed: bool, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            collect_files(&entry?.path(), false, files)?;
+        }
+    } else {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        if listed || extension.map(|extension| EXTENSIONS.contains(&extension.as_str())).unwrap_or(false) {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Path and content of every document in `paths`. Listed files are read whatever their extension.
+pub fn documents(paths: &[String]) -> Result<Vec<(String, String)>, LlmError> {
+    let mut files = vec![];
+    for path in paths {
+        collect_files(Path::new(path), true, &mut files)
+            .map_err(|err| LlmError::Config(format!("Failed to list documents in '{path}': {err}")))?;
+    }
+    files.sort();
+    files.dedup();
+
+    files
+        .iter()
+        .map(|file| {
+            let source = file.to_string_lossy().to_string();
+            std::fs::read_to_string(file)
+                .map(|content| (source.clone(), content))
+                .map_err(|err| LlmError::Config(format!("Failed to read document '{source}': {err}")))
+        })
+        .collect()
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norms = a.iter().map(|a| a * a).sum::<f32>().sqrt() * b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// The `top_k` chunks closest to `vector`, best first
+fn rank<'a, I: Iterator<Item = &'a Chunk>>(chunks: I, vector: &[f32], top_k: usize) -> Vec<Passage> {
+    let mut passages = chunks
+        .map(|chunk| Passage {
+            source: chunk.source.clone(),
+            heading: chunk.heading.clone(),
+            text: chunk.text.clone(),
+            score: cosine(&chunk.embedding, vector),
+        })
+        .collect::<Vec<Passage>>();
+    passages.sort_by(|a, b| b.score.total_cmp(&a.score));
+    passages.truncate(top_k);
+    passages
+}
+
+/// Turns texts into vectors, the same embedder has to be used for documents and queries
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError>;
+}
+
+/// Sentence embeddings model of a `ModelRegistry`, looked up per call so a swapped model takes over straight away
+pub struct LocalEmbedder {
+    registry: Arc<ModelRegistry>,
+    model: String,
+}
+
+impl LocalEmbedder {
+    pub fn new<S: Into<String>>(registry: Arc<ModelRegistry>, model: S) -> Self {
+        Self {
+            registry,
+            model: model.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for LocalEmbedder {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let (registry, model) = (self.registry.clone(), self.model.clone());
+        let texts = texts.to_vec();
+        // the model runs on the cpu for a while, which would hold up the other requests on this worker
+        tokio::task::spawn_blocking(move || {
+            let sentences = texts.iter().map(String::as_str).collect::<Vec<&str>>();
+            registry
+                .encode(&model, &sentences)
+                .map_err(|err| LlmError::Request(format!("Failed to embed: {err}")))
+        })
+        .await
+        .map_err(|err| LlmError::Request(format!("Sentence encoder crashed: {err}")))?
+    }
+}
+
+/// Embeddings api of an LLM provider
+pub struct ProviderEmbedder {
+    provider: Box<dyn LlmProvider>,
+    model: String,
+}
+
+impl ProviderEmbedder {
+    pub fn new<S: Into<String>>(provider: Box<dyn LlmProvider>, model: S) -> Self {
+        Self {
+            provider,
+            model: model.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for ProviderEmbedder {
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        self.provider.embed(&self.model, texts).await
+    }
+}
+
+/// Where embedded chunks are kept and searched
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Ids of the chunks stored
+    async fn ids(&self) -> Result<HashSet<String>, LlmError>;
+
+    async fn upsert(&self, chunks: Vec<Chunk>) -> Result<(), LlmError>;
+
+    /// Removes the chunks whose id is not in `keep`, such as those of documents that changed
+    async fn retain(&self, keep: &HashSet<String>) -> Result<(), LlmError>;
+
+    /// The `top_k` chunks closest to `vector`, best first
+    async fn search(&self, vector: &[f32], top_k: usize) -> Result<Vec<Passage>, LlmError>;
+}
+
+#[derive(Default)]
+pub struct MemoryVectorStore {
+    chunks: Mutex<HashMap<String, Chunk>>,
+}
+
+impl MemoryVectorStore {
+    pub fn len(&self) -> usize {
+        self.chunks.lock().map(|chunks| chunks.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl VectorStore for MemoryVectorStore {
+    async fn ids(&self) -> Result<HashSet<String>, LlmError> {
+        Ok(self.chunks
+            .lock()
+            .map(|chunks| chunks.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn upsert(&self, chunks: Vec<Chunk>) -> Result<(), LlmError> {
+        if let Ok(mut stored) = self.chunks.lock() {
+            stored.extend(chunks.into_iter().map(|chunk| (chunk.id.clone(), chunk)));
+        }
+        Ok(())
+    }
+
+    async fn retain(&self, keep: &HashSet<String>) -> Result<(), LlmError> {
+        if let Ok(mut stored) = self.chunks.lock() {
+            stored.retain(|id, _| keep.contains(id));
+        }
+        Ok(())
+    }
+
+    async fn search(&self, vector: &[f32], top_k: usize) -> Result<Vec<Passage>, LlmError> {
+        Ok(self.chunks
+            .lock()
+            .map(|chunks| rank(chunks.values(), vector, top_k))
+            .unwrap_or_default())
+    }
+}
+
+/// Record of a chunk, its own `id` is left to SurrealDB
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkRecord {
+    chunk: Chunk,
+}
+
+fn surreal_error(err: surrealdb::Error) -> LlmError {
+    LlmError::Request(format!("SurrealDB: {err}"))
+}
+
+/// Chunks kept in a SurrealDB table, so they are not embedded again after a restart.
+/// Searching reads the vectors and ranks them in process, which is fine for a site's worth of documents.
+pub struct SurrealVectorStore {
+    addr: String,
+    username: String,
+    password: String,
+    namespace: String,
+    database: String,
+    table: String,
+    db: OnceCell<Surreal<Client>>,
+}
+
+impl SurrealVectorStore {
+    pub fn new(addr: &str, username: &str, password: &str, namespace: &str, database: &str, table: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            namespace: namespace.to_string(),
+            database: database.to_string(),
+            table: table.to_string(),
+            db: OnceCell::new(),
+        }
+    }
+
+    /// The connection, made on first use
+    async fn db(&self) -> Result<&Surreal<Client>, LlmError> {
+        self.db
+            .get_or_try_init(|| async {
+                let db = Surreal::new::<Ws>(self.addr.as_str()).await.map_err(surreal_error)?;
+                db.signin(Root {
+                    username: &self.username,
+                    password: &self.password,
+                }).await.map_err(surreal_error)?;
+                db.use_ns(&self.namespace).use_db(&self.database).await.map_err(surreal_error)?;
+                Ok(db)
+            })
+            .await
+    }
+
+    async fn records(&self) -> Result<Vec<ChunkRecord>, LlmError> {
+        self.db().await?.select(self.table.as_str()).await.map_err(surreal_error)
+    }
+}
+
+#[async_trait]
+impl VectorStore for SurrealVectorStore {
+    async fn ids(&self) -> Result<HashSet<String>, LlmError> {
+        Ok(self.records().await?.into_iter().map(|record| record.chunk.id).collect())
+    }
+
+    async fn upsert(&self, chunks: Vec<Chunk>) -> Result<(), LlmError> {
+        let db = self.db().await?;
+        for chunk in chunks {
+            let id = chunk.id.clone();
+            let _: Option<ChunkRecord> = db
+                .update((self.table.as_str(), id.as_str()))
+                .content(ChunkRecord { chunk })
+                .await
+                .map_err(surreal_error)?;
+        }
+        Ok(())
+    }
+
+    async fn retain(&self, keep: &HashSet<String>) -> Result<(), LlmError> {
+        let db = self.db().await?;
+        for id in self.ids().await?.difference(keep) {
+            let _: Option<ChunkRecord> = db.delete((self.table.as_str(), id.as_str())).await.map_err(surreal_error)?;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, vector: &[f32], top_k: usize) -> Result<Vec<Passage>, LlmError> {
+        let records = self.records().await?;
+        Ok(rank(records.iter().map(|record| &record.chunk), vector, top_k))
+    }
+}
+
+/// Finds the passages of the ingested documents that are closest to a query
+pub struct Retriever {
+    embedder: Box<dyn Embedder>,
+    store: Box<dyn VectorStore>,
+    settings: RetrievalSettings,
+}
+
+impl Retriever {
+    pub fn new(embedder: Box<dyn Embedder>, store: Box<dyn VectorStore>, settings: RetrievalSettings) -> Self {
+        Self { embedder, store, settings }
+    }
+
+    /// Chunks `documents` (path and content) and stores them, chunks that are stored already are not
+    /// embedded again and chunks no document has anymore are removed. Returns how many were embedded.
+    pub async fn ingest(&self, documents: &[(String, String)]) -> Result<usize, LlmError> {
+        let mut chunks = documents
+            .iter()
+            .flat_map(|(source, text)| chunk(source, text, self.settings.chunk_chars, self.settings.chunk_overlap))
+            .collect::<Vec<Chunk>>();
+        let keep = chunks.iter().map(|chunk| chunk.id.clone()).collect::<HashSet<String>>();
+        let stored = self.store.ids().await?;
+        let mut seen = HashSet::new();
+        chunks.retain(|chunk| !stored.contains(&chunk.id) && seen.insert(chunk.id.clone()));
+
+        for batch in chunks.chunks(BATCH_SIZE) {
+            let texts = batch.iter().map(|chunk| chunk.text.clone()).collect::<Vec<String>>();
+            let embeddings = self.embedder.embed(&texts).await?;
+            if embeddings.len() != batch.len() {
+                return Err(LlmError::Parse(format!(
+                    "{} returned {} embeddings for {} chunks",
+                    self.embedder.name(), embeddings.len(), batch.len(),
+                )));
+            }
+
+            let embedded = batch
+                .iter()
+                .cloned()
+                .zip(embeddings)
+                .map(|(chunk, embedding)| Chunk { embedding, ..chunk })
+                .collect();
+            self.store.upsert(embedded).await?;
+        }
+
+        self.store.retain(&keep).await?;
+        Ok(chunks.len())
+    }
+
+    /// Up to `top_k` passages scoring at least `min_score` for `query`, best first
+    pub async fn retrieve(&self, query: &str) -> Result<Vec<Passage>, LlmError> {
+        if query.trim().is_empty() || self.settings.top_k == 0 {
+            return Ok(vec![]);
+        }
+
+        let vector = self.embedder
+            .embed(&[query.to_string()])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| LlmError::Parse(format!("{} returned no embedding", self.embedder.name())))?;
+        let passages = self.store.search(&vector, self.settings.top_k).await?;
+        Ok(passages.into_iter().filter(|passage| passage.score >= self.settings.min_score).collect())
+    }
+}
+
+/// Numbers `passages` from 1 in the order they are given to the model
+pub fn citations(passages: &[Passage]) -> Vec<Citation> {
+    passages
+        .iter()
+        .enumerate()
+        .map(|(idx, passage)| Citation {
+            index: idx + 1,
+            passage: passage.clone(),
+        })
+        .collect()
+}
+
+/// Adds `passages` to the system message of `prompt`. Citations are asked for in parentheses,
+/// square brackets would be taken for action tags and removed from the reply.
+pub fn augment(prompt: &mut Vec<ChatMessage>, passages: &[Passage]) {
+    if passages.is_empty() {
+        return;
+    }
+
+    let mut context = String::from(
+        "Answer from the passages below where they help and cite the ones you use by number in parentheses, e.g. (1).",
+    );
+    for citation in citations(passages) {
+        context.push_str(&format!("\n\n({}) {}\n{}", citation.index, citation.passage.label(), citation.passage.text));
+    }
+
+    match prompt.first_mut().filter(|message| message.role == Role::System) {
+        Some(system) => {
+            system.content.push_str("\n\n");
+            system.content.push_str(&context);
+        }
+        None => prompt.insert(0, ChatMessage::system(context)),
+    }
+}
+
+/// Embedder selected by `isla_settings.retrieval.embedder`, provider embeddings go through the guardrails
+pub fn embedder(config: &GlobalConfig, models: Arc<ModelRegistry>) -> Result<Box<dyn Embedder>, LlmError> {
+    Ok(match &config.isla_settings.retrieval.embedder {
+        EmbedderSettings::Local { model } => Box::new(LocalEmbedder::new(models, model)),
+        EmbedderSettings::Provider { model } => {
+            let provider = guardrails::wrap(provider::from_config(config)?, config)?;
+            Box::new(ProviderEmbedder::new(provider, model))
+        }
+    })
+}
+
+pub fn store(settings: &VectorStoreSettings) -> Box<dyn VectorStore> {
+    match settings {
+        VectorStoreSettings::Memory => Box::new(MemoryVectorStore::default()),
+        VectorStoreSettings::SurrealDb { addr, username, password, namespace, database, table } => {
+            Box::new(SurrealVectorStore::new(addr, username, password, namespace, database, table))
+        }
+    }
+}
+
+/// Retriever of `isla_settings.retrieval` with its documents ingested, `None` when retrieval is off.
+/// Documents are ingested once per settings, a failed ingestion is tried again on the next call.
+/// `models` holds the model of a local embedder.
+pub async fn from_config(config: &GlobalConfig, models: Arc<ModelRegistry>) -> Result<Option<Arc<Retriever>>, LlmError> {
+    let settings = &config.isla_settings.retrieval;
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    // the provider is part of the key, its embeddings do not compare with another's
+    let key = format!(
+        "{}{}",
+        serde_json::to_string(settings).unwrap_or_default(),
+        serde_json::to_string(&config.isla_settings.provider).unwrap_or_default(),
+    );
+    let cell = match RETRIEVERS.lock() {
+        Ok(mut retrievers) => retrievers.entry(key).or_default().clone(),
+        Err(_) => Arc::new(OnceCell::new()),
+    };
+
+    let retriever = cell
+        .get_or_try_init(|| async {
+            let retriever = Retriever::new(embedder(config, models)?, store(&settings.store), settings.clone());
+            let documents = documents(&settings.documents)?;
+            let embedded = retriever.ingest(&documents).await?;
+            log::info!("Ingested {} documents for retrieval, embedded {embedded} new chunks", documents.len());
+            Ok::<_, LlmError>(Arc::new(retriever))
+        })
+        .await?;
+    Ok(Some(retriever.clone()))
+}
+
+/// Passages for `query`, when retrieval fails the reply is made without them
+pub async fn passages(config: &GlobalConfig, models: Arc<ModelRegistry>, query: &str) -> Vec<Passage> {
+    let retriever = match from_config(config, models).await {
+        Ok(Some(retriever)) => retriever,
+        Ok(None) => return vec![],
+        Err(err) => {
+            log::warn!("Retrieval is unavailable: {err}");
+            return vec![];
+        }
+    };
+
+    match retriever.retrieve(query).await {
+        Ok(passages) => passages,
+        Err(err) => {
+            log::warn!("Failed to retrieve passages: {err}");
+            vec![]
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{DustinDiazIoConfig, GlobalConfig};
+use crate::core::spec::Spec;
+use crate::openai::provider::{ChatMessage, Completion, CompletionRequest, LlmError, LlmProvider, ToolCall, ToolSpec, Usage};
+
+/// Server side function the model may ask to run. Tools only read, they never change anything.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn spec(&self) -> ToolSpec;
+
+    /// Result for the model, `Err` is a message it gets to read instead
+    async fn call(&self, arguments: Value) -> Result<Value, String>;
+}
+
+/// Evaluates an expression against the spec's `ctx` and `sys` variables
+pub struct EvalCondition {
+    spec: Arc<Spec>,
+}
+
+impl EvalCondition {
+    pub fn new(spec: Spec) -> Self {
+        Self { spec: Arc::new(spec) }
+    }
+}
+
+#[async_trait]
+impl Tool for EvalCondition {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "evaluate_condition".into(),
+            description: "Evaluates a dialog condition such as `ctx.plan == \"pro\"` against the spec variables".into(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "expression": {"type": "string", "description": "Expression over `ctx` and `sys`"},
+                },
+                "required": ["expression"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().ok_or("`expression` must be a string")?.to_string();
+        let spec = self.spec.clone();
+        // evaluation runs on the cpu for as long as the expression takes, off the worker the timeout still applies
+        let result = tokio::task::spawn_blocking(move || spec.format_eval_for_response(&expression))
+            .await
+            .map_err(|err| format!("Evaluation crashed: {err}"))??;
+        serde_json::to_value(result).map_err(|err| err.to_string())
+    }
+}
+
+/// Current time in a timezone, the spec's `sys.timezone` unless the model names one
+pub struct CurrentTime {
+    timezone: Tz,
+    format: String,
+}
+
+impl CurrentTime {
+    pub fn new<S: Into<String>>(timezone: Tz, format: S) -> Self {
+        Self {
+            timezone,
+            format: format.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "current_time".into(),
+            description: format!("Current date and time, in {} unless another timezone is given", self.timezone.name()),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "timezone": {"type": "string", "description": "IANA name such as `Europe/Paris`"},
+                },
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value) -> Result<Value, String> {
+        let timezone = match arguments["timezone"].as_str() {
+            Some(name) => name.parse::<Tz>().map_err(|err| format!("Unknown timezone '{name}': {err}"))?,
+            None => self.timezone,
+        };
+        let now = chrono::Utc::now().with_timezone(&timezone);
+        Ok(json!({
+            "timezone": timezone.name(),
+            "time": now.format(&self.format).to_string(),
+            "rfc3339": now.to_rfc3339(),
+        }))
+    }
+}
+
+/// Looks up the site's public configuration, such as its api urls
+pub struct SiteConfig {
+    config: DustinDiazIoConfig,
+}
+
+impl SiteConfig {
+    pub fn new(config: DustinDiazIoConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Tool for SiteConfig {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "site_config".into(),
+            description: "Api urls of dustindiaz.io by environment, or the one of `environment`".into(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "environment": {"type": "string"},
+                },
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value) -> Result<Value, String> {
+        match arguments["environment"].as_str() {
+            None => Ok(json!({"api_url": self.config.api_url})),
+            Some(environment) => self.config.api_url
+                .get(environment)
+                .map(|url| json!({"environment": environment, "api_url": url}))
+                .ok_or_else(|| format!("No api url for '{environment}'")),
+        }
+    }
+}
+
+/// One tool call made while answering, returned to clients so they can see how the reply came about
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolTrace {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+impl ToolTrace {
+    /// What the model reads back, errors are sent as `{"error": ...}`
+    fn content(&self, max_chars: usize) -> String {
+        let content = match (&self.result, &self.error) {
+            (Some(result), _) => result.to_string(),
+            (None, error) => json!({"error": error}).to_string(),
+        };
+        match content.char_indices().nth(max_chars) {
+            Some((end, _)) => format!("{}...(truncated)", &content[..end]),
+            None => content,
+        }
+    }
+}
+
+/// Tools offered to the model and the limits they run under
+pub struct Toolbox {
+    tools: Vec<Box<dyn Tool>>,
+    pub max_iterations: u32,
+    pub timeout: Duration,
+    pub max_result_chars: usize,
+}
+
+impl Default for Toolbox {
+    /// No tools, the model answers straight away
+    fn default() -> Self {
+        Self {
+            tools: vec![],
+            max_iterations: 0,
+            timeout: Duration::from_secs(2),
+            max_result_chars: 4_000,
+        }
+    }
+}
+
+impl Toolbox {
+    pub fn new(max_iterations: u32, timeout: Duration, max_result_chars: usize) -> Self {
+        Self {
+            tools: vec![],
+            max_iterations,
+            timeout,
+            max_result_chars,
+        }
+    }
+
+    pub fn with_tool<T: Tool + 'static>(mut self, tool: T) -> Self {
+        self.tools.push(Box::new(tool));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.iter().map(|tool| tool.spec()).collect()
+    }
+
+    /// Runs `call` with the timeout, a tool that fails, panics or is unknown gives an error trace
+    pub async fn call(&self, call: &ToolCall) -> ToolTrace {
+        let started = Instant::now();
+        let arguments = if call.arguments.trim().is_empty() {
+            Ok(json!({}))
+        } else {
+            serde_json::from_str::<Value>(&call.arguments).map_err(|err| format!("Invalid arguments: {err}"))
+        };
+
+        let outcome = match (&arguments, self.tools.iter().find(|tool| tool.spec().name == call.name)) {
+            (_, None) => Err(format!("Unknown tool '{}'", call.name)),
+            (Err(err), _) => Err(err.clone()),
+            (Ok(arguments), Some(tool)) => {
+                let running = AssertUnwindSafe(tool.call(arguments.clone())).catch_unwind();
+                match tokio::time::timeout(self.timeout, running).await {
+                    Ok(Ok(outcome)) => outcome,
+                    Ok(Err(_)) => Err(format!("Tool '{}' crashed", call.name)),
+                    Err(_) => Err(format!("Tool '{}' timed out after {:?}", call.name, self.timeout)),
+                }
+            }
+        };
+
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => {
+                log::warn!("Tool call {} failed: {error}", call.name);
+                (None, Some(error))
+            }
+        };
+        ToolTrace {
+            id: call.id.clone(),
+            name: call.name.clone(),
+            arguments: arguments.unwrap_or_else(|_| Value::String(call.arguments.clone())),
+            result,
+            error,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+fn add_usage(total: Option<Usage>, usage: Option<Usage>) -> Option<Usage> {
+    match (total, usage) {
+        (Some(total), Some(usage)) => Some(Usage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+        }),
+        (total, usage) => total.or(usage),
+    }
+}
+
+/// Completes `request`, running the tools the model calls and sending their results back until it answers.
+/// After `max_iterations` rounds of calls the model is asked once more without tools, so it has to answer.
+/// The returned usage adds up every round.
+pub async fn run(
+    provider: &dyn LlmProvider,
+    mut request: CompletionRequest,
+    toolbox: &Toolbox,
+) -> Result<(Completion, Vec<ToolTrace>), LlmError> {
+    let mut traces = vec![];
+    let mut usage = None;
+    request.tools = toolbox.specs();
+
+    let mut iteration = 0;
+    loop {
+        if iteration >= toolbox.max_iterations {
+            request.tools.clear();
+        }
+
+        let completion = provider.complete(&request).await?;
+        usage = add_usage(usage, completion.usage);
+        if completion.tool_calls.is_empty() || request.tools.is_empty() {
+            return Ok((Completion { usage, ..completion }, traces));
+        }
+
+        iteration += 1;
+        log::info!("Running {} tool calls of {}, iteration {iteration}", completion.tool_calls.len(), provider.name());
+        request.messages.push(ChatMessage::assistant(completion.text.clone()).with_tool_calls(completion.tool_calls.clone()));
+        for call in &completion.tool_calls {
+            let trace = toolbox.call(call).await;
+            request.messages.push(ChatMessage::tool(&trace.id, trace.content(toolbox.max_result_chars)));
+            traces.push(trace);
+        }
+    }
+}
+
+/// Tools enabled in `isla_settings.tools`, `evaluate_condition` needs `spec` and is left out without one
+pub fn from_config(config: &GlobalConfig, spec: Option<&Spec>) -> Toolbox {
+    let settings = &config.isla_settings.tools;
+    let mut toolbox = Toolbox::new(settings.max_iterations, Duration::from_millis(settings.timeout_ms), settings.max_result_chars);
+
+    for name in &settings.enabled {
+        toolbox = match (name.as_str(), spec) {
+            ("evaluate_condition", Some(spec)) => toolbox.with_tool(EvalCondition::new(spec.clone())),
+            ("evaluate_condition", None) => {
+                log::warn!("Not offering evaluate_condition, no spec is configured");
+                toolbox
+            }
+            ("current_time", _) => {
+                let timezone = spec
+                    .and_then(|spec| spec.system.get("timezone"))
+                    .and_then(|name| name.parse::<Tz>().ok())
+                    .unwrap_or(Tz::UTC);
+                toolbox.with_tool(CurrentTime::new(timezone, config.time_format.clone()))
+            }
+            ("site_config", _) => toolbox.with_tool(SiteConfig::new(config.dustindiaz_io.clone())),
+            (name, _) => {
+                log::warn!("Ignoring unknown tool '{name}' in isla_settings.tools");
+                toolbox
+            }
+        };
+    }
+    toolbox
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Datelike, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+use tokio::sync::OnceCell;
+
+use crate::config::{ModelPrice, Quota, UsageSettings, UsageStoreSettings};
+use crate::openai::provider::{LlmError, Usage};
+
+/// Tenant of requests made without an api key
+pub const ANONYMOUS: &str = "anonymous";
+
+lazy_static! {
+    /// Usage of this process, plus what [`load`] read from `UsageSettings::store` at startup
+    static ref LEDGER: Mutex<Ledger> = Mutex::new(Ledger::default());
+    /// One connection per SurrealDB address and table, shared by all requests
+    static ref STORES: Mutex<HashMap<String, Arc<SurrealUsageStore>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct Ledger {
+    /// Totals by (tenant, day, model)
+    totals: HashMap<(String, NaiveDate, String), Totals>,
+    /// Estimates of the requests each tenant has running, held against its quota until they are recorded
+    reserved: HashMap<String, Totals>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Estimated dollars, models missing from the price table cost nothing
+    pub cost: f64,
+}
+
+impl Totals {
+    fn new(settings: &UsageSettings, model: &str, usage: &Usage) -> Self {
+        Self {
+            requests: 1,
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            total_tokens: usage.total_tokens as u64,
+            cost: cost(settings, model, usage),
+        }
+    }
+
+    fn add(&mut self, other: &Totals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cost += other.cost;
+    }
+
+    fn subtract(&mut self, other: &Totals) {
+        self.requests = self.requests.saturating_sub(other.requests);
+        self.prompt_tokens = self.prompt_tokens.saturating_sub(other.prompt_tokens);
+        self.completion_tokens = self.completion_tokens.saturating_sub(other.completion_tokens);
+        self.total_tokens = self.total_tokens.saturating_sub(other.total_tokens);
+        self.cost = (self.cost - other.cost).max(0.0);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub date: NaiveDate,
+    pub model: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub tenant: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub days: Vec<DailyUsage>,
+    pub total: Totals,
+    pub quota: Quota,
+    /// Usage of the current month, which is what the quota is checked against
+    pub month: Totals,
+}
+
+fn tenant_name(tenant: Option<&str>) -> String {
+    tenant.unwrap_or(ANONYMOUS).to_string()
+}
+
+/// Price of `model`, matched exactly or by the longest configured prefix
+pub fn price<'a>(settings: &'a UsageSettings, model: &str) -> Option<&'a ModelPrice> {
+    settings.prices
+        .iter()
+        .filter(|(name, _)| model.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price)
+}
+
+pub fn cost(settings: &UsageSettings, model: &str, usage: &Usage) -> f64 {
+    price(settings, model)
+        .map(|price| {
+            (usage.prompt_tokens as f64 * price.prompt + usage.completion_tokens as f64 * price.completion) / 1000.0
+        })
+        .unwrap_or(0.0)
+}
+
+/// Row of the usage table, one per tenant, day and model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UsageRecord {
+    tenant: String,
+    date: NaiveDate,
+    model: String,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+fn surreal_error(err: surrealdb::Error) -> LlmError {
+    LlmError::Request(format!("SurrealDB: {err}"))
+}
+
+/// Totals kept in a SurrealDB table, so quotas hold across restarts. Requests add to their row in
+/// a single statement, so processes sharing the table do not overwrite each other.
+pub struct SurrealUsageStore {
+    addr: String,
+    username: String,
+    password: String,
+    namespace: String,
+    database: String,
+    table: String,
+    db: OnceCell<Surreal<Client>>,
+}
+
+impl SurrealUsageStore {
+    pub fn new(addr: &str, username: &str, password: &str, namespace: &str, database: &str, table: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            namespace: namespace.to_string(),
+            database: database.to_string(),
+            table: table.to_string(),
+            db: OnceCell::new(),
+        }
+    }
+
+    /// The connection, made on first use
+    async fn db(&self) -> Result<&Surreal<Client>, LlmError> {
+        self.db
+            .get_or_try_init(|| async {
+                let db = Surreal::new::<Ws>(self.addr.as_str()).await.map_err(surreal_error)?;
+                db.signin(Root {
+                    username: &self.username,
+                    password: &self.password,
+                }).await.map_err(surreal_error)?;
+                db.use_ns(&self.namespace).use_db(&self.database).await.map_err(surreal_error)?;
+                Ok(db)
+            })
+            .await
+    }
+
+    async fn add(&self, tenant: &str, date: NaiveDate, model: &str, totals: &Totals) -> Result<(), LlmError> {
+        let mut response = self.db()
+            .await?
+            .query(
+                "UPDATE type::thing($table, [$tenant, $date, $model]) SET tenant = $tenant, date = $date, model = $model, \
+                 requests += $requests, prompt_tokens += $prompt_tokens, completion_tokens += $completion_tokens, \
+                 total_tokens += $total_tokens, cost += $cost",
+            )
+            .bind(("table", self.table.as_str()))
+            .bind(("tenant", tenant))
+            .bind(("date", date))
+            .bind(("model", model))
+            .bind(("requests", totals.requests))
+            .bind(("prompt_tokens", totals.prompt_tokens))
+            .bind(("completion_tokens", totals.completion_tokens))
+            .bind(("total_tokens", totals.total_tokens))
+            .bind(("cost", totals.cost))
+            .await
+            .map_err(surreal_error)?;
+        let _: Vec<UsageRecord> = response.take(0).map_err(surreal_error)?;
+        Ok(())
+    }
+
+    async fn records(&self) -> Result<Vec<UsageRecord>, LlmError> {
+        self.db().await?.select(self.table.as_str()).await.map_err(surreal_error)
+    }
+}
+
+/// Store selected by `settings`, `None` when totals are only kept in memory
+fn store(settings: &UsageStoreSettings) -> Option<Arc<SurrealUsageStore>> {
+    match settings {
+        UsageStoreSettings::Memory => None,
+        UsageStoreSettings::SurrealDb { addr, username, password, namespace, database, table } => {
+            let connect = || Arc::new(SurrealUsageStore::new(addr, username, password, namespace, database, table));
+            Some(match STORES.lock() {
+                Ok(mut stores) => stores.entry(format!("{addr}/{namespace}/{database}/{table}")).or_insert_with(connect).clone(),
+                Err(_) => connect(),
+            })
+        }
+    }
+}
+
+/// Reads the persisted totals into the ledger, run at startup before requests are served
+pub async fn load(settings: &UsageSettings) -> Result<usize, LlmError> {
+    let store = match store(&settings.store) {
+        Some(store) => store,
+        None => return Ok(0),
+    };
+
+    let records = store.records().await?;
+    let mut ledger = LEDGER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for record in &records {
+        ledger.totals.insert((record.tenant.clone(), record.date, record.model.clone()), record.totals);
+    }
+    Ok(records.len())
+}
+
+/// Writes `totals` to the configured store in the background, requests do not wait for it
+fn persist(settings: &UsageSettings, tenant: &str, date: NaiveDate, model: &str, totals: Totals) {
+    let store = match store(&settings.store) {
+        Some(store) => store,
+        None => return,
+    };
+    let runtime = match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => runtime,
+        Err(_) => {
+            log::warn!("Not persisting usage of {tenant:?}, there is no runtime to write it from");
+            return;
+        }
+    };
+
+    let (tenant, model) = (tenant.to_string(), model.to_string());
+    runtime.spawn(async move {
+        if let Err(err) = store.add(&tenant, date, &model, &totals).await {
+            log::warn!("Failed to persist usage: tenant={tenant:?} model={model:?} err={err}");
+        }
+    });
+}
+
+/// Adds a request to the ledger, `usage` is missing when no tokens were spent, e.g. for cached replies
+pub fn record(settings: &UsageSettings, tenant: Option<&str>, model: &str, usage: Option<&Usage>) -> Totals {
+    let totals = Totals::new(settings, model, &usage.copied().unwrap_or_default());
+    let tenant = tenant_name(tenant);
+    log::info!(
+        "Usage: tenant={tenant:?} model={model:?} tokens={} cost={:.6}",
+        totals.total_tokens,
+        totals.cost,
+    );
+
+    let today = Utc::now().date_naive();
+    if let Ok(mut ledger) = LEDGER.lock() {
+        ledger
+            .totals
+            .entry((tenant.clone(), today, model.to_string()))
+            .or_default()
+            .add(&totals);
+    }
+    persist(settings, &tenant, today, model, totals);
+    totals
+}
+
+/// Estimated usage of a running request, held against its tenant's quota. Recording it replaces the estimate
+/// with what was spent, dropping it unrecorded releases the estimate.
+pub struct Reservation {
+    settings: UsageSettings,
+    tenant: String,
+    model: String,
+    estimate: Totals,
+    recorded: bool,
+}
+
+impl Reservation {
+    pub fn record(mut self, usage: Option<&Usage>) -> Totals {
+        self.release();
+        self.recorded = true;
+        record(&self.settings, Some(&self.tenant), &self.model, usage)
+    }
+
+    fn release(&self) {
+        if let Ok(mut ledger) = LEDGER.lock() {
+            if let Some(reserved) = ledger.reserved.get_mut(&self.tenant) {
+                reserved.subtract(&self.estimate);
+            }
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.release();
+        }
+    }
+}
+
+/// Usage of `tenant` per day and model from `from` to `to`, both included
+pub fn daily(tenant: Option<&str>, from: NaiveDate, to: NaiveDate) -> Vec<DailyUsage> {
+    let tenant = tenant_name(tenant);
+    let mut days = LEDGER
+        .lock()
+        .map(|ledger| {
+            ledger
+                .totals
+                .iter()
+                .filter(|((name, date, _), _)| *name == tenant && *date >= from && *date <= to)
+                .map(|((_, date, model), totals)| DailyUsage {
+                    date: *date,
+                    model: model.clone(),
+                    totals: *totals,
+                })
+                .collect::<Vec<DailyUsage>>()
+        })
+        .unwrap_or_default();
+    days.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.model.cmp(&b.model)));
+    days
+}
+
+fn sum(days: &[DailyUsage]) -> Totals {
+    days.iter().fold(Totals::default(), |mut total, day| {
+        total.add(&day.totals);
+        total
+    })
+}
+
+pub fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+pub fn month_to_date(tenant: Option<&str>) -> Totals {
+    LEDGER
+        .lock()
+        .map(|ledger| month_of(&ledger, &tenant_name(tenant)))
+        .unwrap_or_default()
+}
+
+fn month_of(ledger: &Ledger, tenant: &str) -> Totals {
+    let today = Utc::now().date_naive();
+    let from = first_of_month(today);
+    ledger.totals
+        .iter()
+        .filter(|((name, date, _), _)| name == tenant && *date >= from && *date <= today)
+        .fold(Totals::default(), |mut total, (_, totals)| {
+            total.add(totals);
+            total
+        })
+}
+
+pub fn quota(settings: &UsageSettings, tenant: Option<&str>) -> Quota {
+    tenant
+        .and_then(|tenant| settings.quotas.get(tenant))
+        .copied()
+        .unwrap_or(settings.default_quota)
+}
+
+/// Fails once `tenant` has used its monthly tokens or budget, counting the estimates of its running requests.
+/// Otherwise holds `estimate` against the quota until the returned reservation is recorded or dropped.
+pub fn reserve(settings: &UsageSettings, tenant: Option<&str>, model: &str, estimate: &Usage) -> Result<Reservation, LlmError> {
+    let name = tenant_name(tenant);
+    let quota = quota(settings, tenant);
+    let estimate = Totals::new(settings, model, estimate);
+
+    let mut ledger = LEDGER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut used = month_of(&ledger, &name);
+    if let Some(reserved) = ledger.reserved.get(&name) {
+        used.add(reserved);
+    }
+    let limit = match (quota.monthly_tokens, quota.monthly_cost) {
+        (Some(tokens), _) if used.total_tokens >= tokens => Some("token"),
+        (_, Some(cost)) if used.cost >= cost => Some("cost"),
+        _ => None,
+    };
+    if let Some(limit) = limit {
+        return Err(LlmError::QuotaExceeded {
+            tenant: name,
+            limit: limit.into(),
+        });
+    }
+
+    ledger.reserved.entry(name.clone()).or_default().add(&estimate);
+    Ok(Reservation {
+        settings: settings.clone(),
+        tenant: name,
+        model: model.to_string(),
+        estimate,
+        recorded: false,
+    })
+}
+
+/// Usage of `tenant` from `from` to `to`, defaulting to the current month
+pub fn report(settings: &UsageSettings, tenant: Option<&str>, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Report {
+    let today = Utc::now().date_naive();
+    let from = from.unwrap_or_else(|| first_of_month(today));
+    let to = to.unwrap_or(today);
+    let days = daily(tenant, from, to);
+
+    Report {
+        tenant: tenant_name(tenant),
+        from,
+        to,
+        total: sum(&days),
+        days,
+        quota: quota(settings, tenant),
+        month: month_to_date(tenant),
+    }
+}
@@ -1,63 +1,428 @@
-use std::sync::{Arc, Mutex};
-use crate::config::GLOBAL_MUTEX;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use actix_web::{web::Form, post, web::Json};
+use crate::auth::cognito::bearer_token;
+use crate::auth::oidc::{Identity, OidcError, OidcProvider, ProviderRegistry};
+
+use actix_web::{get, post, http::StatusCode, web::{Data, Form, Json, Query}, HttpRequest, HttpResponse, ResponseError};
+use lazy_static::lazy_static;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
     AuthorizationCode,
-    AuthUrl,
-    ClientId,
-    ClientSecret,
     CsrfToken,
+    ErrorResponse,
     PkceCodeChallenge,
-    RedirectUrl,
+    RefreshToken,
+    RequestTokenError,
     Scope,
-    TokenResponse,
-    TokenUrl,
+    StandardRevocableToken,
     PkceCodeVerifier,
-
+    AccessToken,
 };
-use oauth2::basic::BasicClient;
-use oauth2::reqwest::http_client;
+use oauth2::basic::BasicTokenResponse;
 use serde::{Serialize, Deserialize};
-use log::{info, error, log};
+use log::{info, error};
+
+/// How long an `/authorize` round trip may take before its state is forgotten
+pub(crate) const PENDING_AUTHORIZATION_TTL: Duration = Duration::from_secs(10 * 60);
+
+pub(crate) struct PendingAuthorization {
+    pub(crate) provider: String,
+    pub(crate) pkce_verifier: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) created_at: Instant,
+}
+
+/// Most `/authorize` round trips in progress, the oldest is forgotten to make room for a new one
+pub(crate) const MAX_PENDING_AUTHORIZATIONS: usize = 10_000;
+
+/// Pending authorizations by state, oldest first. States that were taken keep their place in the
+/// queue until they reach its front, so they count towards the capacity until then.
+pub(crate) struct PendingAuthorizations {
+    capacity: usize,
+    by_state: HashMap<String, PendingAuthorization>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl PendingAuthorizations {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            by_state: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, state: String, pending: PendingAuthorization) {
+        while let Some((_, created_at)) = self.order.front() {
+            if created_at.elapsed() < PENDING_AUTHORIZATION_TTL && self.order.len() < self.capacity {
+                break;
+            }
+            if let Some((state, created_at)) = self.order.pop_front() {
+                if self.by_state.get(&state).map(|v| v.created_at) == Some(created_at) {
+                    self.by_state.remove(&state);
+                }
+            }
+        }
+
+        self.order.push_back((state.clone(), pending.created_at));
+        self.by_state.insert(state, pending);
+    }
+
+    pub(crate) fn take(&mut self, state: &str) -> Option<PendingAuthorization> {
+        self.by_state
+            .remove(state)
+            .filter(|v| v.created_at.elapsed() < PENDING_AUTHORIZATION_TTL)
+    }
+}
+
+lazy_static! {
+    /// CSRF state handed out by `/authorize`, consumed by the matching `/token` call
+    static ref PENDING_AUTHORIZATIONS: Mutex<PendingAuthorizations> = Mutex::new(PendingAuthorizations::new(MAX_PENDING_AUTHORIZATIONS));
+}
+
+pub(crate) fn store_pending(state: &CsrfToken, pending: PendingAuthorization) {
+    match PENDING_AUTHORIZATIONS.lock() {
+        Ok(mut pending_authorizations) => pending_authorizations.insert(state.secret().clone(), pending),
+        Err(err) => error!("Failed to acquire pending authorization lock '{err:?}'"),
+    }
+}
+
+pub(crate) fn take_pending(state: &str) -> Option<PendingAuthorization> {
+    match PENDING_AUTHORIZATIONS.lock() {
+        Ok(mut pending_authorizations) => pending_authorizations.take(state),
+        Err(err) => {
+            error!("Failed to acquire pending authorization lock '{err:?}'");
+            None
+        }
+    }
+}
+
+/// Error body as described in https://www.rfc-editor.org/rfc/rfc6749#section-5.2
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OAuthError {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+    #[serde(skip)]
+    status: u16,
+}
+
+impl OAuthError {
+    fn new<E: Into<String>, D: Into<String>>(status: StatusCode, error: E, description: D) -> Self {
+        Self {
+            error: error.into(),
+            error_description: Some(description.into()),
+            status: status.as_u16(),
+        }
+    }
+
+    fn invalid_request<D: Into<String>>(description: D) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+
+    fn invalid_grant<D: Into<String>>(description: D) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_grant", description)
+    }
+
+    fn server_error<D: Into<String>>(description: D) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", description)
+    }
+
+    /// Maps a failed provider call onto an error for our own client,
+    /// provider error responses are forwarded as they are
+    fn from_upstream<RE, T>(err: RequestTokenError<RE, T>) -> Self
+    where
+        RE: std::error::Error + 'static,
+        T: ErrorResponse + Serialize + 'static,
+    {
+        match err {
+            RequestTokenError::ServerResponse(response) => {
+                let value = serde_json::to_value(&response).unwrap_or_default();
+                let field = |name: &str| value.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+                let error = field("error").unwrap_or_else(|| "invalid_request".into());
+                let status = if error == "invalid_client" {
+                    StatusCode::UNAUTHORIZED
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+
+                Self {
+                    error,
+                    error_description: field("error_description"),
+                    status: status.as_u16(),
+                }
+            }
+            RequestTokenError::Request(err) => Self::new(
+                StatusCode::BAD_GATEWAY,
+                "server_error",
+                format!("Failed to reach identity provider: {err}"),
+            ),
+            RequestTokenError::Parse(err, _) => Self::new(
+                StatusCode::BAD_GATEWAY,
+                "server_error",
+                format!("Failed to parse identity provider response: {err}"),
+            ),
+            RequestTokenError::Other(err) => Self::new(StatusCode::BAD_GATEWAY, "server_error", err),
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{}: {}", self.error, description),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::BAD_REQUEST)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(("Cache-Control", "no-store"))
+            .json(self)
+    }
+}
+
+impl From<OidcError> for OAuthError {
+    fn from(err: OidcError) -> Self {
+        match err {
+            OidcError::UnknownProvider(_) | OidcError::RedirectNotAllowed(_) => {
+                OAuthError::invalid_request(err.to_string())
+            }
+            OidcError::UserInfoUnsupported(_) => {
+                OAuthError::new(StatusCode::NOT_IMPLEMENTED, "server_error", err.to_string())
+            }
+            OidcError::Discovery(_) | OidcError::UserInfo(_) => {
+                OAuthError::new(StatusCode::BAD_GATEWAY, "server_error", err.to_string())
+            }
+            OidcError::InvalidEndpoint(_) => OAuthError::server_error(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthorizeQuery {
+    /// Name of a configured identity provider, defaults to `cognito`
+    provider: Option<String>,
+    redirect_uri: String,
+    /// Space separated, defaults to the provider's configured scopes
+    scope: Option<String>,
+}
+
+/// Starts the authorization code flow, the browser is sent to the identity provider
+/// with a fresh PKCE challenge and CSRF state that `/token` later checks.
+#[get("/authorize")]
+pub async fn authorize(
+    registry: Data<ProviderRegistry>,
+    query: Query<AuthorizeQuery>,
+) -> Result<HttpResponse, OAuthError> {
+    let query = query.into_inner();
+    let provider = registry.get(query.provider.as_deref()).await?;
+    let client = provider.client(Some(&query.redirect_uri))?;
+
+    let scopes = query
+        .scope
+        .unwrap_or_else(|| provider.default_scopes().join(" "));
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (auth_url, state) = client
+        .authorize_url(CsrfToken::new_random)
+        .add_scopes(scopes.split_whitespace().map(|v| Scope::new(v.to_string())))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    store_pending(&state, PendingAuthorization {
+        provider: provider.name.clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+        redirect_uri: query.redirect_uri,
+        created_at: Instant::now(),
+    });
+
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", auth_url.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TokenBody {
+    /// Name of a configured identity provider, defaults to `cognito` or the provider the `state` was issued for
+    provider: Option<String>,
     grant_type: String,
+    code: Option<String>,
+    /// Required unless the flow was started through `/authorize` and `state` is sent
+    code_verifier: Option<String>,
+    state: Option<String>,
+    redirect_uri: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+async fn exchange_code(registry: &ProviderRegistry, req: TokenBody) -> Result<BasicTokenResponse, OAuthError> {
+    let code = req.code.ok_or_else(|| OAuthError::invalid_request("Missing code"))?;
+
+    let pending = match &req.state {
+        Some(state) => Some(
+            take_pending(state).ok_or_else(|| OAuthError::invalid_grant("Unknown or expired state"))?
+        ),
+        None => None,
+    };
+
+    let provider_name = match (&pending, req.provider) {
+        (Some(pending), Some(provider)) if pending.provider != provider => {
+            return Err(OAuthError::invalid_grant("state was issued for a different provider"));
+        }
+        (Some(pending), _) => Some(pending.provider.clone()),
+        (None, provider) => provider,
+    };
+    let provider = registry.get(provider_name.as_deref()).await?;
+
+    redeem_code(&provider, pending, req.code_verifier, req.redirect_uri, code).await
+}
+
+/// Trades `code` with `provider`, the verifier and redirect_uri default to those of the `pending` authorization
+pub(crate) async fn redeem_code(
+    provider: &OidcProvider,
+    pending: Option<PendingAuthorization>,
+    code_verifier: Option<String>,
+    redirect_uri: Option<String>,
     code: String,
-    code_verifier: String,
-    redirect_uri: String,
+) -> Result<BasicTokenResponse, OAuthError> {
+    let code_verifier = code_verifier
+        .or_else(|| pending.as_ref().map(|v| v.pkce_verifier.clone()))
+        .ok_or_else(|| OAuthError::invalid_request("Missing code_verifier"))?;
+    let redirect_uri = redirect_uri
+        .or_else(|| pending.as_ref().map(|v| v.redirect_uri.clone()))
+        .ok_or_else(|| OAuthError::invalid_request("Missing redirect_uri"))?;
+
+    if let Some(pending) = &pending {
+        if pending.redirect_uri != redirect_uri {
+            return Err(OAuthError::invalid_grant("redirect_uri does not match the authorization request"));
+        }
+    }
+
+    provider.client(Some(&redirect_uri))?
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(PkceCodeVerifier::new(code_verifier))
+        .request_async(async_http_client)
+        .await
+        .map_err(OAuthError::from_upstream)
+}
+
+async fn exchange_refresh_token(registry: &ProviderRegistry, req: TokenBody) -> Result<BasicTokenResponse, OAuthError> {
+    let provider = registry.get(req.provider.as_deref()).await?;
+    let refresh_token = req.refresh_token
+        .map(RefreshToken::new)
+        .ok_or_else(|| OAuthError::invalid_request("Missing refresh_token"))?;
+    let scopes = req.scope.unwrap_or_default();
+
+    provider.client(None)?
+        .exchange_refresh_token(&refresh_token)
+        .add_scopes(scopes.split_whitespace().map(|v| Scope::new(v.to_string())))
+        .request_async(async_http_client)
+        .await
+        .map_err(OAuthError::from_upstream)
 }
 
 #[post("/token")]
 pub async fn token(
+    registry: Data<ProviderRegistry>,
     body: Form<TokenBody>
-) -> Json<oauth2::StandardTokenResponse<oauth2::EmptyExtraTokenFields, oauth2::basic::BasicTokenType>> {
-    let config = global!().unwrap().unwrap();
+) -> Result<Json<BasicTokenResponse>, OAuthError> {
     let req = body.into_inner();
-    info!("{:?}", req);
-    let client = BasicClient::new(
-        ClientId::new(config.config.cognito.id),
-        Some(ClientSecret::new(config.config.cognito.secret)),
-        AuthUrl::new(config.config.cognito.auth_url).expect("failed to build auth url"),
-        Some(TokenUrl::new(config.config.cognito.token_url).expect("failed to build token url")),
-    ).set_redirect_uri(RedirectUrl::new(req.redirect_uri).expect("failed to build Redirect url"));
-
-    let pkce_verifier = PkceCodeVerifier::new(req.code_verifier);
-    let token_result = client.exchange_code(AuthorizationCode::new(req.code))
-        .set_pkce_verifier(pkce_verifier)
-        .request_async(async_http_client)
-        .await;
+    info!("Token request: grant_type={:?} provider={:?}", req.grant_type, req.provider);
+
+    let grant_type = req.grant_type.clone();
+    let token_result = match grant_type.as_str() {
+        "authorization_code" => exchange_code(&registry, req).await,
+        "refresh_token" => exchange_refresh_token(&registry, req).await,
+        other => Err(OAuthError::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            format!("Unsupported grant_type '{other}'"),
+        )),
+    };
 
     match token_result {
         Err(err) => {
-            error!(">>> {:?}", err);
-            todo!()
+            error!("Failed to get tokens from OAuth provider: {err}");
+            Err(err)
         }
         Ok(val) => {
-            info!("Tokens received from OAuth provider! {val:?}");
-            Json(val)
+            info!("Tokens received from OAuth provider!");
+            Ok(Json(val))
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RevokeBody {
+    provider: Option<String>,
+    token: String,
+    /// `refresh_token` (default) or `access_token`
+    token_type_hint: Option<String>,
+}
+
+/// Revokes a token with the identity provider, see https://www.rfc-editor.org/rfc/rfc7009
+#[post("/revoke")]
+pub async fn revoke(
+    registry: Data<ProviderRegistry>,
+    body: Form<RevokeBody>,
+) -> Result<HttpResponse, OAuthError> {
+    let req = body.into_inner();
+    let provider = registry.get(req.provider.as_deref()).await?;
+
+    let token = match req.token_type_hint.as_deref() {
+        Some("access_token") => StandardRevocableToken::AccessToken(AccessToken::new(req.token)),
+        Some("refresh_token") | None => StandardRevocableToken::RefreshToken(RefreshToken::new(req.token)),
+        Some(other) => {
+            return Err(OAuthError::new(
+                StatusCode::BAD_REQUEST,
+                "unsupported_token_type",
+                format!("Unsupported token_type_hint '{other}'"),
+            ))
+        }
+    };
+
+    provider.client(None)?
+        .revoke_token(token)
+        .map_err(|err| OAuthError::server_error(err.to_string()))?
+        .request_async(async_http_client)
+        .await
+        .map_err(|err| {
+            error!("Failed to revoke token: {err:?}");
+            OAuthError::from_upstream(err)
+        })?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserInfoQuery {
+    provider: Option<String>,
+}
+
+/// Normalized identity of the bearer of an access token issued by `provider`
+#[get("/userinfo")]
+pub async fn user_info(
+    req: HttpRequest,
+    registry: Data<ProviderRegistry>,
+    query: Query<UserInfoQuery>,
+) -> Result<Json<Identity>, OAuthError> {
+    let access_token = bearer_token(&req).ok_or_else(|| OAuthError::new(
+        StatusCode::UNAUTHORIZED,
+        "invalid_token",
+        "Missing bearer token",
+    ))?;
+    let provider = registry.get(query.provider.as_deref()).await?;
+
+    Ok(Json(provider.user_info(&access_token).await?))
+}
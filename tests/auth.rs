@@ -0,0 +1,292 @@
+#[macro_use]
+#[path = "./../src/config.rs"]
+mod config;
+
+#[path = "./../src/auth/mod.rs"]
+mod auth;
+
+#[cfg(test)]
+mod api_key {
+    use actix_web::http::header::HeaderValue;
+
+    use crate::auth::api_key::*;
+    use crate::config::ApiKeyConfig;
+
+    fn keys() -> Vec<ApiKeyConfig> {
+        vec![
+            ApiKeyConfig {
+                id: "reader".into(),
+                hash: hash_key("read-only-key"),
+                scopes: vec!["spec:read".into()],
+            },
+            ApiKeyConfig {
+                id: "bot".into(),
+                hash: hash_key("bot-key").to_uppercase(),
+                scopes: vec!["chat".into(), "eval".into(), "not-a-scope".into()],
+            },
+        ]
+    }
+
+    #[test]
+    fn hashes_are_hex_sha256() {
+        assert_eq!(
+            hash_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn authenticates_by_hash() {
+        let keys = keys();
+        let identity = authenticate(&keys, "bot-key").unwrap();
+        assert_eq!(identity.id, "bot");
+        assert_eq!(identity.scopes, vec![Scope::Chat, Scope::Eval]);
+        assert!(authenticate(&keys, "unknown").is_none());
+    }
+
+    #[test]
+    fn authorizes_scopes() {
+        let keys = keys();
+        let reader = HeaderValue::from_static("read-only-key");
+        let bad = HeaderValue::from_static("nope");
+
+        assert_eq!(authorize(&keys, None, Scope::Chat), Err(AuthError::Missing));
+        assert_eq!(authorize(&keys, Some(&bad), Scope::Chat), Err(AuthError::Invalid));
+        assert_eq!(
+            authorize(&keys, Some(&reader), Scope::Chat),
+            Err(AuthError::Forbidden { key_id: "reader".into(), scope: Scope::Chat })
+        );
+        assert_eq!(authorize(&keys, Some(&reader), Scope::SpecRead).unwrap().id, "reader");
+    }
+
+    #[test]
+    fn requires_scopes_beyond_the_route() {
+        let keys = keys();
+        let bot = authenticate(&keys, "bot-key").unwrap();
+        assert_eq!(bot.require(Scope::Chat), Ok(()));
+        assert_eq!(
+            bot.require(Scope::SpecWrite),
+            Err(AuthError::Forbidden { key_id: "bot".into(), scope: Scope::SpecWrite })
+        );
+    }
+
+    #[test]
+    fn error_status() {
+        assert_eq!(AuthError::Missing.to_response().status(), 401);
+        assert_eq!(AuthError::Invalid.to_response().status(), 401);
+        let forbidden = AuthError::Forbidden { key_id: "reader".into(), scope: Scope::Eval };
+        assert_eq!(forbidden.to_response().status(), 403);
+    }
+}
+
+#[cfg(test)]
+mod cognito {
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::{json, Value};
+
+    use crate::auth::cognito::*;
+
+    const ISSUER: &str = "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_local";
+    const CLIENT_ID: &str = "local-client";
+
+    struct LocalPool {
+        encoding_key: EncodingKey,
+        verifier: CognitoVerifier,
+    }
+
+    fn b64(bytes: Vec<u8>) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn local_pool() -> LocalPool {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwks = JwkSet {
+            keys: vec![Jwk {
+                kid: "local-kid".into(),
+                kty: "RSA".into(),
+                n: b64(rsa.n().to_vec()),
+                e: b64(rsa.e().to_vec()),
+                alg: Some("RS256".into()),
+                key_use: Some("sig".into()),
+            }],
+        };
+
+        LocalPool {
+            encoding_key: EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap(),
+            verifier: CognitoVerifier::with_jwks(ISSUER, vec![CLIENT_ID.into()], jwks),
+        }
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn sign(pool: &LocalPool, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.into());
+        encode(&header, &claims, &pool.encoding_key).unwrap()
+    }
+
+    fn id_claims() -> Value {
+        json!({
+            "sub": "user-1",
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "exp": now() + 600,
+            "token_use": "id",
+            "cognito:username": "isla",
+            "email": "isla@example.com",
+            "cognito:groups": ["admins"],
+        })
+    }
+
+    #[tokio::test]
+    async fn accepts_id_and_access_tokens() {
+        let pool = local_pool();
+        let id_token = sign(&pool, "local-kid", id_claims());
+        let claims = pool.verifier.verify(&id_token, &[TokenUse::Id]).await.unwrap();
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.username.as_deref(), Some("isla"));
+        assert_eq!(claims.groups, vec!["admins".to_string()]);
+
+        let access_token = sign(&pool, "local-kid", json!({
+            "sub": "user-1",
+            "iss": ISSUER,
+            "client_id": CLIENT_ID,
+            "exp": now() + 600,
+            "token_use": "access",
+            "scope": "openid email",
+        }));
+        let claims = pool.verifier.verify(&access_token, &[TokenUse::Access]).await.unwrap();
+        assert_eq!(claims.scopes(), vec!["openid", "email"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_tokens() {
+        let pool = local_pool();
+        let other_pool = local_pool();
+
+        let mut expired = id_claims();
+        expired["exp"] = json!(now() - 3600);
+        let mut wrong_issuer = id_claims();
+        wrong_issuer["iss"] = json!("https://example.com");
+        let mut wrong_audience = id_claims();
+        wrong_audience["aud"] = json!("someone-else");
+
+        let verifier = &pool.verifier;
+        let verify = |token: String| async move { verifier.verify(&token, &[TokenUse::Id]).await };
+
+        assert!(matches!(verify(sign(&pool, "local-kid", expired)).await, Err(CognitoError::Invalid(_))));
+        assert!(matches!(verify(sign(&pool, "local-kid", wrong_issuer)).await, Err(CognitoError::Invalid(_))));
+        assert_eq!(verify(sign(&pool, "local-kid", wrong_audience)).await, Err(CognitoError::WrongClient));
+        assert_eq!(
+            verify(sign(&pool, "other-kid", id_claims())).await,
+            Err(CognitoError::UnknownKey("other-kid".into()))
+        );
+        assert!(matches!(
+            verify(sign(&other_pool, "local-kid", id_claims())).await,
+            Err(CognitoError::Invalid(_))
+        ));
+        assert_eq!(
+            pool.verifier.verify(&sign(&pool, "local-kid", id_claims()), &[TokenUse::Access]).await,
+            Err(CognitoError::WrongTokenUse(TokenUse::Id))
+        );
+        assert!(matches!(verify("not-a-token".into()).await, Err(CognitoError::Malformed(_))));
+    }
+}
+
+#[cfg(test)]
+mod webhook {
+    use std::time::Duration;
+
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use crate::auth::webhook::*;
+
+    const BODY: &[u8] = br#"{"ref":"refs/heads/development"}"#;
+
+    fn headers(signature: &str, delivery: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (SIGNATURE_HEADER, signature),
+            (EVENT_HEADER, "push"),
+            (DELIVERY_HEADER, delivery),
+            ("User-Agent", "GitHub-Hookshot/044aadd"),
+        ] {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn signs_like_github() {
+        // example from https://docs.github.com/en/webhooks/using-webhooks/validating-webhook-deliveries
+        assert_eq!(
+            sign("It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+
+    #[test]
+    fn verifies_with_rotated_secrets() {
+        let secrets = vec!["old-secret".to_string(), "new-secret".to_string()];
+        assert_eq!(verify_signature(&secrets, &sign("old-secret", BODY), BODY), Ok(0));
+        assert_eq!(verify_signature(&secrets, &sign("new-secret", BODY), BODY), Ok(1));
+        assert_eq!(
+            verify_signature(&secrets, &sign("other-secret", BODY), BODY),
+            Err(WebhookError::SignatureMismatch)
+        );
+        assert_eq!(
+            verify_signature(&secrets, &sign("new-secret", b"tampered"), BODY),
+            Err(WebhookError::SignatureMismatch)
+        );
+        assert_eq!(verify_signature(&secrets, "new-secret", BODY), Err(WebhookError::MalformedSignature));
+        assert_eq!(verify_signature(&[], &sign("new-secret", BODY), BODY), Err(WebhookError::NoSecrets));
+    }
+
+    #[test]
+    fn rejects_replayed_deliveries() {
+        let verifier = WebhookVerifier::new(Duration::from_secs(60));
+        let secrets = vec!["secret".to_string()];
+        let signature = sign("secret", BODY);
+
+        let delivery = verifier.verify(&secrets, &headers(&signature, "delivery-1"), BODY).unwrap();
+        assert_eq!(delivery.event, "push");
+        assert_eq!(
+            verifier.verify(&secrets, &headers(&signature, "delivery-1"), BODY),
+            Err(WebhookError::Replayed("delivery-1".into()))
+        );
+        assert!(verifier.verify(&secrets, &headers(&signature, "delivery-2"), BODY).is_ok());
+
+        // unsigned attempts do not burn the delivery id
+        let forged = sign("guess", BODY);
+        assert_eq!(
+            verifier.verify(&secrets, &headers(&forged, "delivery-3"), BODY),
+            Err(WebhookError::SignatureMismatch)
+        );
+        assert!(verifier.verify(&secrets, &headers(&signature, "delivery-3"), BODY).is_ok());
+    }
+
+    #[test]
+    fn requires_github_headers() {
+        let verifier = WebhookVerifier::default();
+        let secrets = vec!["secret".to_string()];
+        let mut missing = headers(&sign("secret", BODY), "delivery-1");
+        missing.remove(DELIVERY_HEADER);
+        assert_eq!(
+            verifier.verify(&secrets, &missing, BODY),
+            Err(WebhookError::MissingHeader(DELIVERY_HEADER))
+        );
+
+        let mut wrong_agent = headers(&sign("secret", BODY), "delivery-1");
+        wrong_agent.insert(
+            HeaderName::from_static("user-agent"),
+            HeaderValue::from_static("curl/8.0"),
+        );
+        assert_eq!(verifier.verify(&secrets, &wrong_agent, BODY), Err(WebhookError::InvalidUserAgent));
+    }
+}
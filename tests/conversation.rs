@@ -0,0 +1,446 @@
+#[macro_use]
+#[path = "./../src/config.rs"]
+mod config;
+
+#[path = "./../src/core.rs"]
+mod core;
+
+#[path = "./../src/openai/mod.rs"]
+mod openai;
+
+#[path = "./../src/intent.rs"]
+mod intent;
+
+#[path = "./../src/entities.rs"]
+mod entities;
+
+#[path = "./../src/conversation.rs"]
+mod conversation;
+
+#[cfg(test)]
+mod routing {
+    use std::collections::HashMap;
+
+    use crate::config::IslaSettings;
+    use crate::conversation::*;
+    use crate::core::spec::{Case, Dialog, Spec};
+    use crate::intent::KeywordClassifier;
+    use crate::openai::persona::Persona;
+    use crate::openai::provider::MockProvider;
+    use crate::openai::isla::Grounding;
+
+    fn spec() -> Spec {
+        Spec::new(
+            vec!["billing".into(), "login".into(), "login issue".into(), "commissions".into()],
+            vec![
+                Dialog::new("billing".into(), vec![
+                    Case::new("ctx.plan == 'free'".into(), "Free plans are never billed.".into()),
+                    Case::new("true".into(), "Invoices go out on the 1st. [N4V2RE$UME]".into()),
+                ]),
+                Dialog::new("login issue".into(), vec![
+                    Case::new("false".into(), "Never".into()),
+                ]),
+            ],
+            HashMap::from([("plan".to_string(), "pro".to_string())]),
+            HashMap::new(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn dialog_answers_before_llm() {
+        let provider = MockProvider::new("unused");
+        let reply = respond(&provider, &KeywordClassifier, &IslaSettings::default(), &Persona::isla(), Some(&spec()), &[], vec![
+            "You: When is billing?".into(),
+        ], &Grounding::default()).await.unwrap();
+
+        assert_eq!(reply.text, "Invoices go out on the 1st.");
+        assert_eq!(reply.actions.len(), 1);
+        assert_eq!(reply.decision, Decision {
+            source: Source::Dialog,
+            intent: Some("billing".into()),
+            confidence: Some(1.0),
+            rationale: Some("message mentions 'billing'".into()),
+            case: Some(1),
+            reason: "case 1 of 'billing' matched".into(),
+        });
+        assert!(provider.requests().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn falls_back_to_llm_with_reason() {
+        let cases = [
+            (Some(spec()), "I have a login issue", Some("login issue"), "no case of 'login issue' matched"),
+            (Some(spec()), "Tell me about commissions", Some("commissions"), "intent 'commissions' has no dialog"),
+            (Some(spec()), "Who are you?", None, "no intent matched"),
+            (None, "billing?", None, "no spec configured"),
+        ];
+
+        for (spec, message, intent, reason) in cases {
+            let provider = MockProvider::new("Ask someone else.");
+            let reply = respond(&provider, &KeywordClassifier, &IslaSettings::default(), &Persona::isla(), spec.as_ref(), &[], vec![
+                format!("You: {message}").into(),
+            ], &Grounding::default()).await.unwrap();
+
+            assert_eq!(reply.text, "Ask someone else.");
+            assert_eq!(reply.decision.source, Source::Llm);
+            assert_eq!(reply.decision.intent.as_deref(), intent);
+            assert_eq!(reply.decision.reason, reason);
+            assert_eq!(provider.requests().len(), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod classifier {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::config::{GuardrailSettings, IslaSettings};
+    use crate::core::spec::{IntentTraining, Spec, TrainingExample};
+    use crate::intent::*;
+    use crate::openai::guardrails::{GuardedProvider, Guardrails};
+    use crate::openai::provider::{LlmError, MockProvider};
+
+    fn intents() -> Vec<String> {
+        vec!["billing".into(), "login".into(), "login issue".into()]
+    }
+
+    fn llm(reply: &str, min_confidence: f32) -> (Arc<MockProvider>, LlmClassifier) {
+        let provider = Arc::new(MockProvider::new(reply));
+        let classifier = LlmClassifier::new(Box::new(provider.clone()), IslaSettings::default(), min_confidence);
+        (provider, classifier)
+    }
+
+    #[actix_web::test]
+    async fn keyword_matches_longest_intent_by_word() {
+        let classify = |message: &'static str| async move {
+            KeywordClassifier.classify(message, &intents()).await.unwrap().intent
+        };
+
+        assert_eq!(classify("I have a LOGIN ISSUE again").await, Some("login issue".into()));
+        assert_eq!(classify("login please").await, Some("login".into()));
+        assert_eq!(classify("rebilling").await, None);
+    }
+
+    #[actix_web::test]
+    async fn llm_reads_json_from_reply() {
+        let (provider, classifier) = llm(
+            r#"Sure: {"intent": "Billing", "confidence": 0.9, "rationale": "mentions invoices"}"#,
+            0.5,
+        );
+        let classification = classifier.classify("where is my invoice?", &intents()).await.unwrap();
+
+        assert_eq!(classification, Classification {
+            intent: Some("billing".into()),
+            confidence: 0.9,
+            rationale: "mentions invoices".into(),
+        });
+
+        let requests = provider.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].temperature, 0.0);
+        assert!(requests[0].messages[1].content.contains(r#"["billing","login","login issue"]"#));
+    }
+
+    #[actix_web::test]
+    async fn llm_records_usage_for_tenant() {
+        let (_, classifier) = llm(r#"{"intent": "login", "confidence": 0.9, "rationale": "r"}"#, 0.5);
+        let classifier = classifier.with_tenant(Some("classifier-tenant"));
+        classifier.classify("I can't log in", &intents()).await.unwrap();
+
+        assert_eq!(crate::openai::usage::month_to_date(Some("classifier-tenant")).requests, 1);
+    }
+
+    #[actix_web::test]
+    async fn llm_rejects_unknown_and_unsure_intents() {
+        let (_, classifier) = llm(r#"{"intent": "refunds", "confidence": 0.99, "rationale": "r"}"#, 0.5);
+        assert_eq!(classifier.classify("refund me", &intents()).await.unwrap().intent, None);
+
+        let (_, classifier) = llm(r#"{"intent": "login", "confidence": 0.3, "rationale": "r"}"#, 0.5);
+        let classification = classifier.classify("hmm", &intents()).await.unwrap();
+        assert_eq!(classification.intent, None);
+        assert_eq!(classification.confidence, 0.3);
+
+        let (_, classifier) = llm("no idea", 0.5);
+        assert!(classifier.classify("hmm", &intents()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn llm_treats_guardrail_refusal_as_no_intent() {
+        let provider = Arc::new(MockProvider::new("unused"));
+        let settings = GuardrailSettings { blocklist: vec!["crypto scam".into()], ..Default::default() };
+        let guarded = GuardedProvider::new(Box::new(provider.clone()), Guardrails::new(&settings).unwrap(), None);
+        let classifier = LlmClassifier::new(Box::new(guarded), IslaSettings::default(), 0.5);
+
+        let classification = classifier.classify("tell me about the crypto scam", &intents()).await.unwrap();
+        assert_eq!(classification.intent, None);
+        assert!(provider.requests().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn llm_skips_model_without_intents() {
+        let (provider, classifier) = llm("unused", 0.5);
+        assert_eq!(classifier.classify("hi", &[]).await.unwrap().intent, None);
+        assert!(provider.requests().is_empty());
+    }
+
+    fn trained(min_confidence: f32) -> TrainedClassifier {
+        let examples = [
+            ("where is my invoice?", "billing"),
+            ("I was charged twice", "billing"),
+            ("how do I pay", "billing"),
+            ("I forgot my password", "login issue"),
+            ("I am locked out of my account", "login issue"),
+            ("reset my password", "login issue"),
+        ]
+        .into_iter()
+        .map(|(utterance, intent)| dfs_ml::intent::Example::new(utterance, intent))
+        .collect::<Vec<_>>();
+        let model = dfs_ml::intent::IntentModel::fit(&examples, &Default::default()).unwrap();
+        TrainedClassifier::new(Arc::new(model), min_confidence)
+    }
+
+    #[actix_web::test]
+    async fn trained_ranks_declared_intents() {
+        let classification = trained(0.5).classify("my password does not work", &intents()).await.unwrap();
+        assert_eq!(classification.intent, Some("login issue".into()));
+        assert!(classification.confidence > 0.5);
+
+        // billing is no longer declared, so the runner up is all that is left
+        let login = vec!["login issue".to_string()];
+        assert_eq!(trained(0.0).classify("where is my invoice?", &login).await.unwrap().intent, Some("login issue".into()));
+        assert_eq!(trained(0.0).classify("hi", &["greeting".to_string()]).await.unwrap().intent, None);
+    }
+
+    #[actix_web::test]
+    async fn trained_rejects_unsure_intents() {
+        let classification = trained(0.99).classify("hello there", &intents()).await.unwrap();
+        assert_eq!(classification.intent, None);
+        assert!(classification.rationale.starts_with("confidence below"));
+    }
+
+    #[actix_web::test]
+    async fn trained_needs_a_model_or_examples() {
+        let missing = std::env::temp_dir().join("isla-missing-intent-model.json");
+        assert!(TrainedClassifier::from_files(missing.to_str().unwrap(), None, 0.5, 16).is_err());
+    }
+
+    #[actix_web::test]
+    async fn trained_retrains_when_examples_change() {
+        let dir = std::env::temp_dir().join(format!("isla-retrain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (model, examples) = (dir.join("model.json"), dir.join("examples.csv"));
+        let write_examples = |login: &str| {
+            let rows = [
+                ("where is my invoice?", "billing"),
+                ("I was charged twice", "billing"),
+                ("how do I pay", "billing"),
+                ("I forgot my password", login),
+                ("I am locked out of my account", login),
+                ("reset my password", login),
+            ];
+            let csv = rows.iter().fold("utterance,intent\n".to_string(), |csv, (utterance, intent)| format!("{csv}{utterance},{intent}\n"));
+            std::fs::write(&examples, csv).unwrap();
+        };
+        let classify = |intent: &str| {
+            let (model, examples) = (model.to_str().unwrap().to_string(), examples.to_str().unwrap().to_string());
+            let intents = vec!["billing".to_string(), intent.to_string()];
+            async move {
+                let classifier = TrainedClassifier::from_files(&model, Some(&examples), 0.0, 16).unwrap();
+                classifier.classify("my password does not work", &intents).await.unwrap().intent
+            }
+        };
+
+        write_examples("login issue");
+        assert_eq!(classify("login issue").await, Some("login issue".into()));
+
+        // file times may only have a resolution of a second
+        std::thread::sleep(std::time::Duration::from_millis(1_100));
+        write_examples("account");
+        assert_eq!(classify("account").await, Some("account".into()));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn training_spec() -> Spec {
+        let examples = |texts: &[&str]| texts.iter().map(|text| TrainingExample { text: text.to_string(), entities: vec![] }).collect();
+        Spec::new(intents(), vec![], HashMap::new(), HashMap::new())
+            .with_training("billing", IntentTraining {
+                description: Some("Invoices and payments".into()),
+                examples: examples(&["where is my invoice?", "I was charged twice", "how do I pay"]),
+                negatives: vec!["is the billing page down?".into(), "the website is down".into()],
+            })
+            .with_training("login issue", IntentTraining {
+                description: None,
+                examples: examples(&["I forgot my password", "I am locked out of my account", "reset my password"]),
+                negatives: vec![],
+            })
+    }
+
+    #[actix_web::test]
+    async fn llm_reads_spec_training() {
+        let (provider, classifier) = llm(r#"{"intent": "billing", "confidence": 0.9, "rationale": "r"}"#, 0.5);
+        let classifier = classifier.with_training(training_spec().training);
+        classifier.classify("where is my invoice?", &intents()).await.unwrap();
+
+        let prompt = &provider.requests()[0].messages[1].content;
+        assert!(prompt.contains(r#"- billing: Invoices and payments Examples: ["where is my invoice?","I was charged twice","how do I pay"] Not: ["#), "{prompt}");
+        assert!(prompt.contains(r#"- login issue Examples: ["I forgot my password""#), "{prompt}");
+        assert!(!prompt.contains("- login Examples"), "{prompt}");
+    }
+
+    #[actix_web::test]
+    async fn trained_learns_from_spec() {
+        let classifier = TrainedClassifier::from_spec(&training_spec(), 0.0, 100, 16).unwrap();
+        assert_eq!(classifier.classify("my password is wrong", &intents()).await.unwrap().intent, Some("login issue".into()));
+
+        let classification = classifier.classify("the website is down", &intents()).await.unwrap();
+        assert_eq!(classification.intent, None);
+        assert_eq!(classification.rationale, "message is closest to a negative example");
+
+        let untrained = Spec::new(intents(), vec![], HashMap::new(), HashMap::new());
+        assert!(TrainedClassifier::from_spec(&untrained, 0.0, 100, 16).is_err());
+    }
+
+    #[actix_web::test]
+    async fn trained_refuses_specs_with_too_many_examples() {
+        let err = TrainedClassifier::from_spec(&training_spec(), 0.0, 4, 16).err().unwrap();
+        assert_eq!(err, LlmError::Config("Spec has 8 examples and negatives, at most 4 are trained on".into()));
+    }
+}
+
+#[cfg(test)]
+mod unmatched {
+    use crate::intent::{record_unmatched, unmatched};
+
+    #[test]
+    fn keeps_latest_redacted() {
+        record_unmatched("mail me at jane@example.com", 2);
+        record_unmatched("  ", 2);
+        assert_eq!(unmatched(), vec!["mail me at (redacted email)"]);
+
+        record_unmatched("do you sell gift cards?", 2);
+        record_unmatched("can I change my delivery address?", 2);
+        assert_eq!(unmatched(), vec!["do you sell gift cards?", "can I change my delivery address?"]);
+    }
+}
+
+#[cfg(test)]
+mod extraction {
+    use std::collections::{BTreeMap, HashMap};
+
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    use crate::config::IslaSettings;
+    use crate::conversation::*;
+    use crate::core::spec::{Case, Dialog, EntityDef, Spec};
+    use crate::entities::*;
+    use crate::intent::KeywordClassifier;
+    use crate::openai::isla::Grounding;
+    use crate::openai::persona::Persona;
+    use crate::openai::provider::MockProvider;
+
+    struct Fixed(Vec<Recognized>);
+
+    impl Recognizer for Fixed {
+        fn recognize(&self, _text: &str) -> Result<Vec<Recognized>, String> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 10).unwrap()
+    }
+
+    fn spec() -> Spec {
+        Spec::new(
+            vec!["billing".into()],
+            vec![Dialog::new("billing".into(), vec![
+                Case::new("ctx.entities.invoice_id == 'INV-1042'".into(), "INV-1042 is paid.".into()),
+                Case::new("true".into(), "Which invoice?".into()),
+            ])],
+            HashMap::new(),
+            HashMap::new(),
+        )
+        .with_entity("invoice_id", EntityDef::Regex { pattern: r"\b(INV-\d+)\b".into() })
+        .with_entity("amount", EntityDef::Amount)
+        .with_entity("due", EntityDef::Date)
+        .with_entity("email", EntityDef::Email)
+        .with_entity("card", EntityDef::Dictionary {
+            values: BTreeMap::from([
+                ("debit".to_string(), vec!["debit card".to_string(), "visa debit".to_string()]),
+                ("credit".to_string(), vec!["credit card".to_string()]),
+            ]),
+        })
+    }
+
+    #[test]
+    fn extracts_typed_values() {
+        let extractor = EntityExtractor::new(&spec()).unwrap();
+        let message = "Invoice INV-1042 for $1,250.50 was due March 5, mail jane@Example.com, I pay by Visa Debit tomorrow";
+        let entities = extractor.extract(message, today());
+
+        assert_eq!(entities[0], Entity {
+            entity: "invoice_id".into(),
+            value: json!("INV-1042"),
+            text: "INV-1042".into(),
+            start: 8,
+            end: 16,
+            score: 1.0,
+        });
+        assert_eq!(values(&entities), BTreeMap::from([
+            ("amount".to_string(), json!(1250.5)),
+            ("card".to_string(), json!("debit")),
+            ("due".to_string(), json!("2024-03-05")),
+            ("email".to_string(), json!("jane@example.com")),
+            ("invoice_id".to_string(), json!("INV-1042")),
+        ]));
+        let due = entities.iter().filter(|entity| entity.entity == "due").map(|entity| entity.value.clone()).collect::<Vec<_>>();
+        assert_eq!(due, vec![json!("2024-03-05"), json!("2024-03-11")]);
+    }
+
+    #[test]
+    fn spans_count_characters() {
+        let spec = Spec::new(vec![], vec![], HashMap::new(), HashMap::new()).with_entity("amount", EntityDef::Amount);
+        let entities = EntityExtractor::new(&spec).unwrap().extract("Café order €30, not 3 February 2024", today());
+
+        assert_eq!(entities.len(), 1);
+        assert_eq!((entities[0].start, entities[0].end), (11, 14));
+        assert_eq!(entities[0].value, json!(30.0));
+    }
+
+    #[test]
+    fn recognizes_ner_labels() {
+        let spec = Spec::new(vec![], vec![], HashMap::new(), HashMap::new())
+            .with_entity("person", EntityDef::Ner { label: "PER".into() })
+            .with_entity("company", EntityDef::Ner { label: "ORG".into() });
+        let extractor = EntityExtractor::new(&spec).unwrap();
+        assert!(extractor.needs_recognizer());
+        assert!(extractor.extract("Jane works at Acme", today()).is_empty());
+
+        let recognized = vec![
+            Recognized { label: "I-PER".into(), text: "Jane".into(), start: 0, end: 4, score: 0.98 },
+            Recognized { label: "I-ORG".into(), text: "Acme".into(), start: 14, end: 18, score: 0.3 },
+        ];
+        let entities = extractor.with_recognizer(Box::new(Fixed(recognized)), 0.5).extract("Jane works at Acme", today());
+        assert_eq!(values(&entities), BTreeMap::from([("person".to_string(), json!("Jane"))]));
+        assert_eq!(entities[0].score, 0.98);
+    }
+
+    #[actix_web::test]
+    async fn conditions_read_entities() {
+        let spec = spec();
+        let message = "billing for INV-1042";
+        let entities = EntityExtractor::new(&spec).unwrap().extract(message, today());
+
+        for (entities, text) in [(entities, "INV-1042 is paid."), (vec![], "Which invoice?")] {
+            let provider = MockProvider::new("unused");
+            let reply = respond(&provider, &KeywordClassifier, &IslaSettings::default(), &Persona::isla(), Some(&spec), &entities, vec![
+                format!("You: {message}").into(),
+            ], &Grounding::default()).await.unwrap();
+
+            assert_eq!(reply.text, text);
+            assert_eq!(reply.entities, entities);
+        }
+    }
+}
@@ -209,3 +209,153 @@ mod eval {
         );
     }
 }
+
+#[cfg(test)]
+mod training {
+    use crate::core::spec::{EntityAnnotation, IntentTraining, Spec, TrainingExample};
+
+    const SPEC: &str = r#"
+intents: [billing, login issue]
+context: {}
+system: {}
+dialogs: {}
+training:
+  billing:
+    description: Paying, invoices and charges
+    examples:
+      - How do I pay?
+      - text: Can I pay with my Visa card?
+        entities:
+          - {entity: card_type, start: 18, end: 22, value: visa}
+    negatives: [Is the billing page down?]
+  login issue:
+    examples: [I am locked out]
+"#;
+
+    #[test]
+    fn reads_plain_and_annotated_examples() {
+        let spec = Spec::from_yaml(SPEC);
+        assert_eq!(spec.validate(), Ok(()));
+
+        let billing = &spec.training["billing"];
+        assert_eq!(billing.examples[0], TrainingExample { text: "How do I pay?".into(), entities: vec![] });
+        assert_eq!(billing.examples[1].entities, vec![EntityAnnotation {
+            entity: "card_type".into(),
+            start: 18,
+            end: 22,
+            value: Some("visa".into()),
+        }]);
+        assert_eq!(spec.describe("billing"), "Paying, invoices and charges");
+        assert_eq!(spec.describe("login issue"), "login issue");
+        assert_eq!(spec.examples(), vec![
+            ("How do I pay?", "billing"),
+            ("Can I pay with my Visa card?", "billing"),
+            ("I am locked out", "login issue"),
+        ]);
+        assert_eq!(spec.negatives(), vec!["Is the billing page down?"]);
+
+        assert_eq!(Spec::from_json(&spec.to_json()), spec);
+        assert_eq!(Spec::from_yaml(&spec.to_yaml()), spec);
+    }
+
+    #[test]
+    fn specs_without_training_are_unchanged() {
+        let spec = Spec::default();
+        assert!(spec.training.is_empty());
+        assert!(!spec.to_json().contains("training"));
+        assert_eq!(spec.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_inconsistent_training() {
+        let annotated = |start, end| TrainingExample {
+            text: "Pay by card".into(),
+            entities: vec![EntityAnnotation { entity: "card_type".into(), start, end, value: None }],
+        };
+        let spec = Spec::default()
+            .with_training("refunds", IntentTraining::default())
+            .with_training("billing", IntentTraining {
+                description: None,
+                examples: vec![annotated(7, 12), TrainingExample { text: " ".into(), entities: vec![] }],
+                negatives: vec!["pay by card".into()],
+            })
+            .with_training("commissions", IntentTraining {
+                examples: vec![annotated(7, 11)],
+                ..Default::default()
+            });
+
+        let problems = spec.validate().unwrap_err();
+        assert!(problems.contains("training data of 'refunds' is of an undeclared intent"), "{problems}");
+        assert!(problems.contains("entity 'card_type' of example \"Pay by card\" spans 7..12 of 11 characters"), "{problems}");
+        assert!(problems.contains("example 1 of 'billing' is blank"), "{problems}");
+        assert!(problems.contains("\"pay by card\" is both an example and a negative of 'billing'"), "{problems}");
+        assert!(problems.contains("example \"Pay by card\" is of both 'billing' and 'commissions'"), "{problems}");
+    }
+
+    #[test]
+    fn load_validates() {
+        let path = std::env::temp_dir().join(format!("isla-invalid-spec-{}.yaml", std::process::id()));
+        std::fs::write(&path, SPEC.replace("intents: [billing, login issue]", "intents: [billing]")).unwrap();
+
+        let err = Spec::load(path.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("training data of 'login issue' is of an undeclared intent"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod entities {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use crate::core::spec::{EntityDef, Spec};
+
+    const SPEC: &str = r#"
+intents: [billing]
+context: {plan: pro}
+system: {}
+dialogs: {}
+entities:
+  invoice_id: {kind: regex, pattern: '\b(INV-\d+)\b'}
+  due: {kind: date}
+  card: {kind: dictionary, values: {visa: [visa card], mastercard: []}}
+  person: {kind: ner, label: PER}
+"#;
+
+    #[test]
+    fn reads_entity_definitions() {
+        let spec = Spec::from_yaml(SPEC);
+        assert_eq!(spec.validate(), Ok(()));
+        assert_eq!(spec.entities["invoice_id"], EntityDef::Regex { pattern: r"\b(INV-\d+)\b".into() });
+        assert_eq!(spec.entities["due"], EntityDef::Date);
+        assert_eq!(spec.entities["person"], EntityDef::Ner { label: "PER".into() });
+        assert_eq!(Spec::from_json(&spec.to_json()), spec);
+        assert!(!Spec::default().to_json().contains("entities"));
+    }
+
+    #[test]
+    fn conditions_read_entities_beside_context() {
+        let spec = Spec::from_yaml(SPEC);
+        let entities = BTreeMap::from([("invoice_id".to_string(), json!("INV-7")), ("amount".to_string(), json!(120.5))]);
+
+        assert_eq!(spec.eval_with("ctx.entities.invoice_id == 'INV-7'", &entities).unwrap(), true);
+        assert_eq!(spec.eval_with("ctx.entities.amount > 100 && ctx.plan == 'pro'", &entities).unwrap(), true);
+        assert_eq!(spec.eval("str(ctx)").unwrap(), "{\"plan\":\"pro\"}");
+    }
+
+    #[test]
+    fn rejects_bad_definitions() {
+        let spec = Spec::default()
+            .with_entity("invoice id", EntityDef::Email)
+            .with_entity("order", EntityDef::Regex { pattern: "(unclosed".into() })
+            .with_entity("card", EntityDef::Dictionary { values: BTreeMap::new() })
+            .with_entity("person", EntityDef::Ner { label: " ".into() });
+
+        let problems = spec.validate().unwrap_err();
+        assert!(problems.contains("entity 'invoice id' needs a name of letters, digits and underscores"), "{problems}");
+        assert!(problems.contains("pattern of entity 'order' does not compile"), "{problems}");
+        assert!(problems.contains("dictionary of entity 'card' has no values"), "{problems}");
+        assert!(problems.contains("entity 'person' needs a NER label"), "{problems}");
+    }
+}
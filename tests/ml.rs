@@ -0,0 +1,221 @@
+#[macro_use]
+#[path = "./../src/config.rs"]
+mod config;
+
+#[path = "./../src/ml/mod.rs"]
+mod ml;
+
+#[cfg(test)]
+mod qa {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use dfs_ml::bert::prelude::{Answer, QaInput};
+    use dfs_ml::registry::{ModelKind, ModelRegistry, ModelSpec, ModelState};
+
+    use crate::config::QaSettings;
+    use crate::ml::*;
+
+    /// Answers with the first word of the context, after waiting on `gate` when there is one
+    struct FirstWord {
+        gate: Option<Arc<Mutex<mpsc::Receiver<()>>>>,
+    }
+
+    impl Answerer for FirstWord {
+        fn answer(&self, inputs: &[QaInput], top_k: usize, _max_answer_length: usize) -> Result<Vec<Vec<Answer>>, String> {
+            if let Some(gate) = &self.gate {
+                gate.lock().unwrap().recv().unwrap();
+            }
+            Ok(inputs
+                .iter()
+                .map(|input| {
+                    let word = input.context.split_whitespace().next().unwrap_or_default().to_string();
+                    vec![Answer { score: 0.9, start: 0, end: word.len(), answer: word }; top_k]
+                })
+                .collect())
+        }
+    }
+
+    fn settings() -> QaSettings {
+        QaSettings {
+            enabled: true,
+            workers: 1,
+            queue_size: 1,
+            max_batch: 2,
+            top_k: 2,
+            timeout_ms: 2_000,
+            ..Default::default()
+        }
+    }
+
+    fn request(questions: usize, top_k: Option<usize>) -> QaRequest {
+        QaRequest {
+            inputs: (0..questions)
+                .map(|idx| QaQuestion { question: "Who?".into(), context: format!("Isla{idx} answers questions.") })
+                .collect(),
+            top_k,
+        }
+    }
+
+    #[actix_web::test]
+    async fn answers_batches_on_workers() {
+        let service = QaService::start(settings(), || Ok(FirstWord { gate: None }));
+
+        let answers = service.answer(request(2, None)).await.unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1].len(), 2);
+        assert_eq!(answers[1][0], QaAnswer { answer: "Isla1".into(), score: 0.9, start: 0, end: 5 });
+
+        let answers = service.answer(request(1, Some(10))).await.unwrap();
+        assert_eq!(answers[0].len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn validates_batches() {
+        let service = QaService::start(settings(), || Ok(FirstWord { gate: None }));
+
+        assert!(matches!(service.answer(request(0, None)).await, Err(QaError::Invalid(_))));
+        assert!(matches!(service.answer(request(3, None)).await, Err(QaError::Invalid(_))));
+
+        let mut blank = request(1, None);
+        blank.inputs[0].context = " ".into();
+        assert_eq!(service.answer(blank).await.unwrap_err().code(), "invalid_request");
+    }
+
+    #[actix_web::test]
+    async fn unavailable_without_model() {
+        assert_eq!(QaService::disabled().answer(request(1, None)).await, Err(QaError::Unavailable));
+        let registry = Arc::new(ModelRegistry::new(vec![ModelSpec {
+            name: "distilbert-qa".into(),
+            kind: ModelKind::QuestionAnswering,
+            dir: "/does/not/exist".into(),
+            model_type: "distilbert".into(),
+            lower_case: false,
+            warm_up: false,
+            generation: Default::default(),
+        }]));
+        assert_eq!(
+            QaService::from_config(&QaSettings::default(), registry.clone()).answer(request(1, None)).await,
+            Err(QaError::Unavailable)
+        );
+
+        let service = QaService::from_config(&settings(), registry.clone());
+        // the worker stops once it fails to load, after which nothing takes the jobs
+        let mut result = service.answer(request(1, None)).await;
+        for _ in 0..50 {
+            if result == Err(QaError::Unavailable) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            result = service.answer(request(1, None)).await;
+        }
+        assert_eq!(result, Err(QaError::Unavailable));
+        assert!(matches!(registry.health()[0].state, ModelState::Failed { .. }));
+    }
+
+    #[actix_web::test]
+    async fn turns_away_batches_when_busy() {
+        let (open, gate) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(gate));
+        let service = Arc::new(QaService::start(
+            QaSettings { timeout_ms: 200, ..settings() },
+            move || Ok(FirstWord { gate: Some(gate.clone()) }),
+        ));
+
+        // one batch on the worker and one in the queue
+        let first = tokio::spawn({
+            let service = service.clone();
+            async move { service.answer(request(1, None)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let second = tokio::spawn({
+            let service = service.clone();
+            async move { service.answer(request(1, None)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(service.answer(request(1, None)).await, Err(QaError::Busy));
+        assert_eq!(first.await.unwrap(), Err(QaError::Timeout));
+        assert_eq!(second.await.unwrap(), Err(QaError::Timeout));
+        open.send(()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod discovery {
+    use dfs_ml::cluster::Algorithm;
+    use dfs_ml::registry::ModelRegistry;
+
+    use crate::config::DiscoverySettings;
+    use crate::ml::discovery::*;
+
+    fn utterances() -> Vec<String> {
+        [
+            "please reset my password today",
+            "forgot my password, reset it",
+            "the password reset link expired",
+            "how do I reset a lost password",
+            "cancel my subscription now",
+            "I want to cancel the annual subscription",
+            "cancel subscription before renewal",
+            "stop and cancel my subscription",
+        ]
+        .iter()
+        .map(|utterance| utterance.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn reads_csv_and_lines() {
+        let csv = "id,Utterance\n1,\"reset my password, please\"\n2,cancel my plan\n";
+        assert_eq!(read_file(csv).unwrap(), vec!["reset my password, please", "cancel my plan"]);
+        assert_eq!(read_file("reset my password\ncancel my plan\n").unwrap(), vec!["reset my password", "cancel my plan"]);
+    }
+
+    #[test]
+    fn clusters_body_file_and_unmatched() {
+        let utterances = utterances();
+        let (body, rest) = utterances.split_at(3);
+        let request = DiscoverRequest {
+            utterances: body.to_vec(),
+            file: Some(rest[..3].join("\n")),
+            unmatched: true,
+            algorithm: Some(Algorithm::KMeans { min_k: 2, max_k: 4 }),
+        };
+        let registry = ModelRegistry::new(vec![]);
+
+        let clustering = discover(request, rest[3..].to_vec(), &DiscoverySettings::default(), &registry).unwrap();
+        let mut suggested = clustering.clusters.iter().map(|cluster| cluster.suggested_intent.as_str()).collect::<Vec<&str>>();
+        suggested.sort();
+        assert_eq!(suggested, vec!["cancel", "password"]);
+        assert_eq!(clustering.clusters.iter().map(|cluster| cluster.size).sum::<usize>(), 8);
+    }
+
+    #[test]
+    fn rejects_too_few_or_too_many() {
+        let registry = ModelRegistry::new(vec![]);
+        let request = DiscoverRequest {
+            utterances: vec!["reset my password".into(), "Reset my password ".into()],
+            ..Default::default()
+        };
+        let err = discover(request, vec![], &DiscoverySettings::default(), &registry).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+
+        let settings = DiscoverySettings { max_utterances: 4, ..Default::default() };
+        let request = DiscoverRequest { utterances: utterances(), ..Default::default() };
+        assert!(matches!(discover(request, vec![], &settings, &registry), Err(DiscoveryError::Invalid(_))));
+
+        // unmatched utterances are only used when asked for
+        let request = DiscoverRequest::default();
+        assert!(discover(request, utterances(), &DiscoverySettings::default(), &registry).is_err());
+    }
+
+    #[test]
+    fn unknown_embedding_model() {
+        let settings = DiscoverySettings { embedding_model: Some("minilm".into()), ..Default::default() };
+        let request = DiscoverRequest { utterances: utterances(), ..Default::default() };
+        let err = discover(request, vec![], &settings, &ModelRegistry::new(vec![])).unwrap_err();
+        assert!(matches!(err, DiscoveryError::Model(_)));
+    }
+}
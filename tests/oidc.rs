@@ -0,0 +1,188 @@
+#[macro_use]
+#[path = "./../src/config.rs"]
+mod config;
+
+#[path = "./../src/auth/mod.rs"]
+mod auth;
+
+#[cfg(test)]
+mod oidc {
+    use std::sync::Arc;
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use oauth2::reqwest::async_http_client;
+    use oauth2::{AuthorizationCode, PkceCodeVerifier, TokenResponse};
+    use serde_json::json;
+
+    use crate::auth::oidc::*;
+    use crate::config::OidcProviderConfig;
+
+    const ACCESS_TOKEN: &str = "mock-access-token";
+
+    /// Starts a minimal OpenID Connect provider on a random local port and returns its base url
+    fn mock_provider() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let issuer = base.clone();
+
+        let server = HttpServer::new(move || {
+            let issuer = issuer.clone();
+            App::new()
+                .route("/.well-known/openid-configuration", web::get().to(move || {
+                    let issuer = issuer.clone();
+                    async move {
+                        HttpResponse::Ok().json(json!({
+                            "issuer": issuer,
+                            "authorization_endpoint": format!("{issuer}/authorize"),
+                            "token_endpoint": format!("{issuer}/token"),
+                            "userinfo_endpoint": format!("{issuer}/userinfo"),
+                            "jwks_uri": format!("{issuer}/jwks"),
+                            "scopes_supported": ["openid", "email"],
+                        }))
+                    }
+                }))
+                .route("/token", web::post().to(|| async {
+                    HttpResponse::Ok().json(json!({
+                        "access_token": ACCESS_TOKEN,
+                        "token_type": "bearer",
+                        "expires_in": 3600,
+                        "refresh_token": "mock-refresh-token",
+                    }))
+                }))
+                .route("/userinfo", web::get().to(|req: HttpRequest| async move {
+                    let authorization = req
+                        .headers()
+                        .get("Authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string());
+
+                    if authorization == Some(format!("Bearer {ACCESS_TOKEN}")) {
+                        HttpResponse::Ok().json(json!({
+                            "sub": "mock-user",
+                            "email": "isla@example.com",
+                            "email_verified": true,
+                            "given_name": "Isla",
+                            "family_name": "Bot",
+                            "preferred_username": "isla",
+                        }))
+                    } else {
+                        HttpResponse::Unauthorized().finish()
+                    }
+                }))
+        })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+
+        actix_web::rt::spawn(server);
+        base
+    }
+
+    fn provider_config(base: &str) -> OidcProviderConfig {
+        OidcProviderConfig {
+            discovery_url: Some(format!("{base}/.well-known/openid-configuration")),
+            client_id: "mock-client".into(),
+            client_secret: Some("mock-secret".into()),
+            redirect_urls: vec!["http://localhost:3000/callback".into()],
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn discovers_and_normalizes_user_info() {
+        let base = mock_provider();
+        let provider = OidcProvider::discover("mock", provider_config(&base)).await.unwrap();
+        assert_eq!(provider.endpoints.issuer, base);
+        assert_eq!(provider.endpoints.token_endpoint, format!("{base}/token"));
+
+        let identity = provider.user_info(ACCESS_TOKEN).await.unwrap();
+        assert_eq!(identity.provider, "mock");
+        assert_eq!(identity.subject, "mock-user");
+        assert_eq!(identity.name.as_deref(), Some("Isla Bot"));
+        assert_eq!(identity.username.as_deref(), Some("isla"));
+        assert_eq!(identity.email_verified, Some(true));
+
+        assert!(matches!(provider.user_info("wrong-token").await, Err(OidcError::UserInfo(_))));
+    }
+
+    #[actix_web::test]
+    async fn exchanges_codes_with_discovered_endpoints() {
+        let base = mock_provider();
+        let provider = OidcProvider::discover("mock", provider_config(&base)).await.unwrap();
+
+        assert_eq!(
+            provider.client(Some("https://evil.example.com")).err(),
+            Some(OidcError::RedirectNotAllowed("https://evil.example.com".into()))
+        );
+
+        let token = provider
+            .client(Some("http://localhost:3000/callback"))
+            .unwrap()
+            .exchange_code(AuthorizationCode::new("mock-code".into()))
+            .set_pkce_verifier(PkceCodeVerifier::new("mock-verifier-that-is-long-enough-for-pkce-checks".into()))
+            .request_async(async_http_client)
+            .await
+            .unwrap();
+        assert_eq!(token.access_token().secret(), ACCESS_TOKEN);
+    }
+
+    #[actix_web::test]
+    async fn rejects_redirects_when_none_are_configured() {
+        let base = mock_provider();
+        let config = OidcProviderConfig {
+            redirect_urls: vec![],
+            ..provider_config(&base)
+        };
+        let provider = OidcProvider::discover("mock", config).await.unwrap();
+
+        assert!(!provider.is_allowed_redirect("http://localhost:3000/callback"));
+        assert_eq!(
+            provider.client(Some("https://evil.example.com")).err(),
+            Some(OidcError::RedirectNotAllowed("https://evil.example.com".into()))
+        );
+        assert!(provider.client(None).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn registry_reuses_discovery_until_config_changes() {
+        let base = mock_provider();
+        let registry = ProviderRegistry::new();
+        let config = provider_config(&base);
+
+        let first = registry.resolve("mock", config.clone()).await.unwrap();
+        let second = registry.resolve("mock", config.clone()).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let changed = OidcProviderConfig {
+            client_id: "other-client".into(),
+            ..config
+        };
+        let third = registry.resolve("mock", changed).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(third.config.client_id, "other-client");
+    }
+
+    #[test]
+    fn identity_from_provider_claims() {
+        let github = Identity::from_claims("github", json!({
+            "id": 583231,
+            "login": "octocat",
+            "avatar_url": "https://example.com/octocat.png",
+        })).unwrap();
+        assert_eq!(github.subject, "583231");
+        assert_eq!(github.username.as_deref(), Some("octocat"));
+        assert_eq!(github.picture.as_deref(), Some("https://example.com/octocat.png"));
+        assert_eq!(github.email, None);
+
+        let cognito = Identity::from_claims("cognito", json!({
+            "sub": "abc",
+            "cognito:username": "isla",
+            "email_verified": "false",
+        })).unwrap();
+        assert_eq!(cognito.username.as_deref(), Some("isla"));
+        assert_eq!(cognito.email_verified, Some(false));
+
+        assert!(Identity::from_claims("none", json!({ "email": "x@example.com" })).is_none());
+    }
+}
@@ -0,0 +1,202 @@
+#[macro_use]
+#[path = "./../src/config.rs"]
+mod config;
+
+#[path = "./../src/auth/mod.rs"]
+mod auth;
+
+#[path = "./../src/token.rs"]
+mod token;
+
+#[cfg(test)]
+mod token_endpoint {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use actix_web::{test, web, App, HttpResponse, HttpServer};
+    use oauth2::{CsrfToken, TokenResponse};
+    use serde_json::{json, Value};
+
+    use crate::auth::oidc::*;
+    use crate::config::OidcProviderConfig;
+    use crate::token::*;
+
+    const ACCESS_TOKEN: &str = "mock-access-token";
+    const CODE: &str = "mock-code";
+    const VERIFIER: &str = "mock-verifier-that-is-long-enough-for-pkce-checks";
+    const REDIRECT_URI: &str = "http://localhost:3000/callback";
+
+    /// Starts an identity provider that checks the PKCE verifier and redeems each code once
+    fn mock_provider() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let issuer = base.clone();
+        let redeemed = web::Data::new(Mutex::new(HashSet::<String>::new()));
+
+        let server = HttpServer::new(move || {
+            let issuer = issuer.clone();
+            App::new()
+                .app_data(redeemed.clone())
+                .route("/.well-known/openid-configuration", web::get().to(move || {
+                    let issuer = issuer.clone();
+                    async move {
+                        HttpResponse::Ok().json(json!({
+                            "issuer": issuer,
+                            "authorization_endpoint": format!("{issuer}/authorize"),
+                            "token_endpoint": format!("{issuer}/token"),
+                        }))
+                    }
+                }))
+                .route("/token", web::post().to(
+                    |form: web::Form<HashMap<String, String>>, redeemed: web::Data<Mutex<HashSet<String>>>| async move {
+                        let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+                        let invalid_grant = |description: &str| HttpResponse::BadRequest().json(json!({
+                            "error": "invalid_grant",
+                            "error_description": description,
+                        }));
+
+                        if field("code_verifier") != VERIFIER {
+                            return invalid_grant("PKCE verification failed");
+                        }
+                        if field("code") != CODE || !redeemed.lock().unwrap().insert(field("code").to_string()) {
+                            return invalid_grant("Code is invalid or was already used");
+                        }
+
+                        HttpResponse::Ok().json(json!({
+                            "access_token": ACCESS_TOKEN,
+                            "token_type": "bearer",
+                            "expires_in": 3600,
+                        }))
+                    }
+                ))
+        })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+
+        actix_web::rt::spawn(server);
+        base
+    }
+
+    async fn discover(base: &str) -> OidcProvider {
+        let config = OidcProviderConfig {
+            discovery_url: Some(format!("{base}/.well-known/openid-configuration")),
+            client_id: "mock-client".into(),
+            client_secret: Some("mock-secret".into()),
+            redirect_urls: vec![REDIRECT_URI.into()],
+            ..Default::default()
+        };
+        OidcProvider::discover("mock", config).await.unwrap()
+    }
+
+    fn pending(created_at: Instant) -> PendingAuthorization {
+        PendingAuthorization {
+            provider: "mock".into(),
+            pkce_verifier: VERIFIER.into(),
+            redirect_uri: REDIRECT_URI.into(),
+            created_at,
+        }
+    }
+
+    async fn post_token(form: &[(&str, &str)]) -> (u16, Option<String>, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ProviderRegistry::new()))
+                .service(token)
+        ).await;
+        let req = test::TestRequest::post().uri("/token").set_form(form).to_request();
+        let resp = test::call_service(&app, req).await;
+
+        let status = resp.status().as_u16();
+        let cache_control = resp
+            .headers()
+            .get("Cache-Control")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        (status, cache_control, test::read_body_json(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_mismatched_pkce_verifier() {
+        let provider = discover(&mock_provider()).await;
+
+        let err = redeem_code(
+            &provider,
+            Some(pending(Instant::now())),
+            Some("another-verifier-that-is-long-enough-for-pkce-checks".into()),
+            None,
+            CODE.into(),
+        ).await.unwrap_err();
+        assert_eq!(err.error, "invalid_grant");
+        assert_eq!(err.error_description.as_deref(), Some("PKCE verification failed"));
+        assert_eq!(actix_web::ResponseError::status_code(&err).as_u16(), 400);
+    }
+
+    #[actix_web::test]
+    async fn codes_and_states_are_redeemed_once() {
+        let provider = discover(&mock_provider()).await;
+        store_pending(&CsrfToken::new("state-redeemed-once".into()), pending(Instant::now()));
+
+        let pending = take_pending("state-redeemed-once");
+        assert!(pending.is_some());
+        let token = redeem_code(&provider, pending, None, None, CODE.into()).await.unwrap();
+        assert_eq!(token.access_token().secret(), ACCESS_TOKEN);
+
+        assert!(take_pending("state-redeemed-once").is_none());
+        let (status, _, body) = post_token(&[
+            ("grant_type", "authorization_code"),
+            ("code", CODE),
+            ("state", "state-redeemed-once"),
+        ]).await;
+        assert_eq!(status, 400);
+        assert_eq!(body, json!({"error": "invalid_grant", "error_description": "Unknown or expired state"}));
+
+        let err = redeem_code(&provider, None, Some(VERIFIER.into()), Some(REDIRECT_URI.into()), CODE.into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.error, "invalid_grant");
+        assert_eq!(err.error_description.as_deref(), Some("Code is invalid or was already used"));
+    }
+
+    #[actix_web::test]
+    async fn pending_authorizations_expire() {
+        let expired = Instant::now() - PENDING_AUTHORIZATION_TTL - Duration::from_secs(1);
+        store_pending(&CsrfToken::new("state-expired".into()), pending(expired));
+        assert!(take_pending("state-expired").is_none());
+
+        store_pending(&CsrfToken::new("state-purged".into()), pending(expired));
+        store_pending(&CsrfToken::new("state-fresh".into()), pending(Instant::now()));
+        assert!(take_pending("state-purged").is_none());
+        assert!(take_pending("state-fresh").is_some());
+    }
+
+    #[test]
+    fn pending_authorizations_are_capped() {
+        let mut pending_authorizations = PendingAuthorizations::new(2);
+        pending_authorizations.insert("oldest".into(), pending(Instant::now()));
+        pending_authorizations.insert("older".into(), pending(Instant::now()));
+        pending_authorizations.insert("newest".into(), pending(Instant::now()));
+
+        assert!(pending_authorizations.take("oldest").is_none());
+        assert!(pending_authorizations.take("older").is_some());
+        assert!(pending_authorizations.take("newest").is_some());
+    }
+
+    #[actix_web::test]
+    async fn errors_follow_rfc_6749() {
+        let (status, cache_control, body) = post_token(&[("grant_type", "password")]).await;
+        assert_eq!(status, 400);
+        assert_eq!(cache_control.as_deref(), Some("no-store"));
+        assert_eq!(body, json!({
+            "error": "unsupported_grant_type",
+            "error_description": "Unsupported grant_type 'password'",
+        }));
+
+        let (status, cache_control, body) = post_token(&[("grant_type", "authorization_code")]).await;
+        assert_eq!(status, 400);
+        assert_eq!(cache_control.as_deref(), Some("no-store"));
+        assert_eq!(body, json!({"error": "invalid_request", "error_description": "Missing code"}));
+    }
+}